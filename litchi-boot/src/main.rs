@@ -8,26 +8,47 @@ extern crate alloc;
 use alloc::vec::Vec;
 use core::arch::asm;
 
-use litchi_common::elf_loader::{ElfLoader, LoaderConfig};
+use litchi_common::elf_loader::{ElfLoader, LoaderConfig, SymbolScope};
 use litchi_common::BootInfo;
 use log::info;
 use uefi::prelude::*;
 use uefi::proto::console::text::Color;
-use x86_64::registers::control::{Cr3, Cr3Flags};
-use x86_64::VirtAddr;
+use uefi::table::boot::MemoryType;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr3Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::{PhysAddr, VirtAddr};
 
 use crate::frame_allocator::BootFrameAllocator;
-use crate::page_table::create_kernel_page_table;
+use crate::page_table::{create_kernel_page_table, PHYS_OFFSET};
 
 mod file_system;
 mod frame_allocator;
 mod page_table;
+mod symbols;
 
 const KERNEL_PATH: &str = "litchi-kernel";
 
-const KERNEL_STACK_TOP: u64 = 0x6667_0000_0000;
+const KERNEL_STACK_TOP: u64 = 0xffff_ff00_0000_0000;
 const KERNEL_STACK_PAGES: u64 = 20;
 
+/// Find the end of the physical memory reported by the UEFI memory map, excluding the
+/// memory-mapped IO regions.
+fn physical_memory_end(boot_services: &BootServices) -> PhysAddr {
+    let mmap_size = boot_services.memory_map_size();
+    let mut mmap_buf = alloc::vec![0u8; mmap_size.map_size * 2];
+    let (_key, mem_desc_iter) = boot_services
+        .memory_map(&mut mmap_buf)
+        .expect("failed to get memory map");
+
+    let end = mem_desc_iter
+        .filter(|desc| desc.ty != MemoryType::MMIO && desc.ty != MemoryType::MMIO_PORT_SPACE)
+        .map(|desc| desc.phys_start + desc.page_count * 4096)
+        .max()
+        .expect("empty memory map");
+
+    PhysAddr::new(end)
+}
+
 #[entry]
 fn efi_main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     uefi_services::init(&mut system_table).expect("failed to init services");
@@ -49,33 +70,49 @@ fn efi_main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
         KERNEL_PATH, kernel_elf_bytes
     );
 
+    let phys_end = physical_memory_end(system_table.boot_services());
+    info!("physical memory ends at {:?}", phys_end);
+
     let mut allocator = BootFrameAllocator::new(system_table.boot_services());
-    let (page_table_frame, mut page_table) = create_kernel_page_table(&mut allocator);
+    let (page_table_frame, mut page_table) = create_kernel_page_table(&mut allocator, phys_end);
     info!("created kernel page table");
 
     let loader_config = LoaderConfig {
         stack_top: VirtAddr::new(KERNEL_STACK_TOP),
         stack_pages: KERNEL_STACK_PAGES,
         userspace: false,
+        load_base: VirtAddr::zero(), // The kernel is linked at a fixed address.
+        phys_offset: VirtAddr::zero(), // UEFI identity-maps the physical memory.
     };
     let kernel_loader = ElfLoader::new(
         &loader_config,
         kernel_elf_bytes,
         &mut allocator,
         &mut page_table,
-    );
+    )
+    .expect("failed to parse kernel elf");
 
-    let kernel_entry = kernel_loader.load();
+    let kernel_entry = kernel_loader
+        .load(&SymbolScope::new())
+        .expect("failed to load kernel elf");
     info!("loaded kernel elf, entry {:p}", kernel_entry);
 
+    // The kernel page table relies on `NO_EXECUTE`, and read-only pages should be protected from
+    // the kernel as well.
     unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
         Cr3::write(page_table_frame, Cr3Flags::empty());
     }
     info!("loaded kernel page table");
 
-    let mmap_size = system_table.boot_services().memory_map_size().map_size;
-    let mmap_buf = alloc::vec![0u8; mmap_size * 2].leak();
-    let memory_descriptors = Vec::with_capacity(128);
+    let kernel_symbols = symbols::load_kernel_symbols(kernel_elf_bytes);
+    info!("loaded {} kernel symbols", kernel_symbols.len());
+
+    let mmap_size = system_table.boot_services().memory_map_size();
+    let mmap_buf = alloc::vec![0u8; mmap_size.map_size * 2].leak();
+    // The memory map may grow a bit before exiting the boot services, so reserve twice the entries.
+    let memory_descriptors = Vec::with_capacity(mmap_size.map_size / mmap_size.entry_size * 2);
 
     info!("exit boot services & call the kernel entry");
 
@@ -101,17 +138,22 @@ fn efi_main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
         name: "litchi",
         kernel_entry: VirtAddr::from_ptr(kernel_entry),
         kernel_stack_top: VirtAddr::new(KERNEL_STACK_TOP),
+        kernel_stack_pages: KERNEL_STACK_PAGES,
         kernel_page_table: page_table_frame,
         system_table,
-        phys_offset: VirtAddr::zero(),
+        phys_offset: VirtAddr::new(PHYS_OFFSET),
         memory_descriptors,
+        kernel_symbols,
     };
+    // Pass the boot info through the physical memory mapping, so that it's still accessible after
+    // the kernel switches to the user page tables.
+    let boot_info_ptr = (&boot_info as *const BootInfo as u64) + PHYS_OFFSET;
 
     unsafe {
         asm!("mov rsp, {}; call {}",
             in(reg) KERNEL_STACK_TOP,
             in(reg) kernel_entry,
-            in("rdi") &boot_info as *const _,
+            in("rdi") boot_info_ptr,
             options(noreturn)
         );
     }
@@ -5,10 +5,16 @@ use x86_64::structures::paging::{
 };
 use x86_64::{PhysAddr, VirtAddr};
 
+/// The offset where the physical memory is mapped in the kernel page table, which is the start of
+/// the higher half.
+pub const PHYS_OFFSET: u64 = 0xffff_8000_0000_0000;
+
+/// Create the kernel page table with all of the physical memory below `phys_end` mapped.
 pub fn create_kernel_page_table(
     allocator: &mut impl FrameAllocator<Size4KiB>,
+    phys_end: PhysAddr,
 ) -> (PhysFrame, OffsetPageTable<'static>) {
-    let frame = allocate_zeroed_frame(allocator);
+    let frame = allocate_zeroed_frame(allocator, VirtAddr::zero());
 
     // UEFI maps vmem with a zero offset.
     let mut page_table = unsafe {
@@ -16,23 +22,31 @@ pub fn create_kernel_page_table(
         OffsetPageTable::new(p4_table, VirtAddr::zero())
     };
 
-    // Map 0-4 GiB
-    for page in Page::<Size1GiB>::range_inclusive(
-        Page::containing_address(VirtAddr::zero()),
-        Page::containing_address(VirtAddr::new(0xffffffff)),
-    ) {
-        let frame =
-            PhysFrame::from_start_address(PhysAddr::new(page.start_address().as_u64())).unwrap();
+    // Always cover 0-4 GiB for the memory-mapped IO like the APICs.
+    let phys_end = phys_end.max(PhysAddr::new(0x1_0000_0000));
+
+    // Map the physical memory both at the physical offset for the kernel, and at the identity for
+    // the bootloader itself and the UEFI runtime. The identity mapping is never shared with users.
+    for offset in [PHYS_OFFSET, 0] {
+        for page in Page::<Size1GiB>::range_inclusive(
+            Page::containing_address(VirtAddr::new(offset)),
+            Page::containing_address(VirtAddr::new(offset + phys_end.as_u64() - 1)),
+        ) {
+            let frame = PhysFrame::from_start_address(PhysAddr::new(
+                page.start_address().as_u64() - offset,
+            ))
+            .unwrap();
 
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
 
-        unsafe {
-            page_table
-                .map_to(page, frame, flags, allocator)
-                .expect("failed to map page")
-                .flush();
+            unsafe {
+                page_table
+                    .map_to(page, frame, flags, allocator)
+                    .expect("failed to map page")
+                    .flush();
 
-            debug!("mapped {:?} to {:?}", page, frame);
+                debug!("mapped {:?} to {:?}", page, frame);
+            }
         }
     }
 
@@ -0,0 +1,27 @@
+use core::{slice, str};
+
+use litchi_common::symbols::{self, Symbol};
+
+use crate::page_table::PHYS_OFFSET;
+
+/// Move the slice from the identity mapping to the physical memory mapping of the kernel page
+/// table.
+///
+/// # Safety
+/// The kernel page table must be loaded.
+unsafe fn rebase<T>(slice: &[T]) -> &'static [T] {
+    let addr = slice.as_ptr() as u64 + PHYS_OFFSET;
+    slice::from_raw_parts(addr as *const T, slice.len())
+}
+
+/// Collect the function symbols of the kernel, sorted by the address. The identity mapping is not
+/// shared with users, so the table and the names are rebased to the physical memory mapping, which
+/// must be loaded already.
+pub fn load_kernel_symbols(kernel_elf_bytes: &'static [u8]) -> &'static [Symbol] {
+    let mut symbols = symbols::function_symbols(kernel_elf_bytes);
+    for symbol in symbols.iter_mut() {
+        symbol.name = unsafe { str::from_utf8_unchecked(rebase(symbol.name.as_bytes())) };
+    }
+
+    unsafe { rebase(symbols.leak()) }
+}
@@ -8,6 +8,8 @@ use uefi::table::{Runtime, SystemTable};
 use x86_64::structures::paging::PhysFrame;
 use x86_64::{PhysAddr, VirtAddr};
 
+use crate::symbols::Symbol;
+
 pub struct BootInfo {
     pub name: &'static str,
 
@@ -15,6 +17,10 @@ pub struct BootInfo {
 
     pub kernel_stack_top: VirtAddr,
 
+    /// The number of pages of the kernel stack. The page below the stack is left unmapped as the
+    /// guard.
+    pub kernel_stack_pages: u64,
+
     pub kernel_page_table: PhysFrame,
 
     pub system_table: SystemTable<Runtime>,
@@ -22,6 +28,10 @@ pub struct BootInfo {
     pub phys_offset: VirtAddr,
 
     pub memory_descriptors: Vec<&'static MemoryDescriptor>,
+
+    /// The function symbols of the kernel sorted by the address, which are accessed through the
+    /// physical memory mapping like the boot info itself. Empty if the kernel is stripped.
+    pub kernel_symbols: &'static [Symbol],
 }
 
 // TODO: `SystemTable` should not be shared across threads
@@ -76,10 +86,12 @@ impl Debug for BootInfo {
             .field("name", &self.name)
             .field("kernel_entry", &self.kernel_entry)
             .field("kernel_stack_top", &self.kernel_stack_top)
+            .field("kernel_stack_pages", &self.kernel_stack_pages)
             .field("kernel_page_table", &self.kernel_page_table)
             .field("phys_offset", &self.phys_offset)
             .field("usable_memory", &UsableMemory(self.usable_memory()))
             .field("acpi_rsdp_addr", &self.acpi_rsdp_addr())
+            .field("kernel_symbols", &self.kernel_symbols.len())
             .finish_non_exhaustive()
     }
 }
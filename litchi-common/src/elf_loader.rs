@@ -1,16 +1,67 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
 use core::intrinsics::copy_nonoverlapping;
+use core::mem::align_of;
 
-use itertools::{EitherOrBoth, Itertools};
 use log::debug;
+use x86_64::structures::paging::mapper::MapToError;
 use x86_64::structures::paging::{
     FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
     Translate,
 };
-use x86_64::VirtAddr;
+use x86_64::{PhysAddr, VirtAddr};
+use xmas_elf::program::{ProgramHeader, ProgramHeader64};
 use xmas_elf::{header, program, ElfFile};
 
 pub type EntryPoint = *const extern "C" fn() -> !;
 
+#[derive(Debug)]
+pub enum LoadError {
+    /// The input is not a valid ELF file.
+    Parse(&'static str),
+    /// The ELF file is valid but not supported, like 32-bit ones or shared objects.
+    Unsupported(&'static str),
+    /// A segment refers to bytes out of the input, or its sizes are inconsistent.
+    SegmentOutOfBounds,
+    /// A segment or the entry point is placed at an invalid virtual address.
+    InvalidAddress,
+    /// A segment is both writable and executable.
+    WritableAndExecutable,
+    /// A page is mapped twice, for example, by overlapping segments.
+    AlreadyMapped,
+    /// No enough frames for the segments or the page tables.
+    OutOfMemory,
+    /// A shared library required by `DT_NEEDED` is not found.
+    MissingLibrary(String),
+    /// A symbol referred by the relocations is not defined by any loaded object.
+    UndefinedSymbol(String),
+}
+
+impl core::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self, f)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for LoadError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => Self::OutOfMemory,
+            MapToError::ParentEntryHugePage | MapToError::PageAlreadyMapped(_) => {
+                Self::AlreadyMapped
+            }
+        }
+    }
+}
+
+pub type LoadResult<T> = Result<T, LoadError>;
+
+/// The addresses of the global symbols defined by the loaded objects, which are used to resolve
+/// the symbol relocations. The first definition of a name takes precedence.
+pub type SymbolScope = BTreeMap<String, u64>;
+
 #[derive(Debug, Clone)]
 pub struct LoaderConfig {
     pub stack_top: VirtAddr,
@@ -18,6 +69,102 @@ pub struct LoaderConfig {
     pub stack_pages: u64,
 
     pub userspace: bool,
+
+    /// The base address to load position-independent executables at. Ignored for executables
+    /// linked at fixed addresses.
+    pub load_base: VirtAddr,
+
+    /// The offset where the physical memory is mapped in the current address space, through which
+    /// the loader fills the frames.
+    pub phys_offset: VirtAddr,
+}
+
+/// The relocation types of x86_64, the dynamic tags and the symbol constants used by the loader.
+mod reloc {
+    pub const DT_NULL: u64 = 0;
+    pub const DT_NEEDED: u64 = 1;
+    pub const DT_PLTRELSZ: u64 = 2;
+    pub const DT_HASH: u64 = 4;
+    pub const DT_STRTAB: u64 = 5;
+    pub const DT_SYMTAB: u64 = 6;
+    pub const DT_RELA: u64 = 7;
+    pub const DT_RELASZ: u64 = 8;
+    pub const DT_RELAENT: u64 = 9;
+    pub const DT_STRSZ: u64 = 10;
+    pub const DT_SYMENT: u64 = 11;
+    pub const DT_REL: u64 = 17;
+    pub const DT_TEXTREL: u64 = 22;
+    pub const DT_JMPREL: u64 = 23;
+    pub const DT_GNU_HASH: u64 = 0x6fff_fef5;
+
+    pub const R_X86_64_NONE: u32 = 0;
+    pub const R_X86_64_64: u32 = 1;
+    pub const R_X86_64_GLOB_DAT: u32 = 6;
+    pub const R_X86_64_JUMP_SLOT: u32 = 7;
+    pub const R_X86_64_RELATIVE: u32 = 8;
+
+    pub const STB_LOCAL: u8 = 0;
+    pub const STB_GLOBAL: u8 = 1;
+    pub const STB_WEAK: u8 = 2;
+    pub const STV_HIDDEN: u8 = 2;
+    pub const STV_INTERNAL: u8 = 1;
+    pub const SHN_UNDEF: u16 = 0;
+    pub const SHN_ABS: u16 = 0xfff1;
+
+    pub const DYN_SIZE: u64 = 16;
+    pub const RELA_SIZE: u64 = 24;
+    pub const SYM_SIZE: u64 = 24;
+}
+
+/// The entries of the dynamic segment used by the loader, in virtual addresses of the ELF.
+#[derive(Debug, Default)]
+struct DynamicInfo {
+    needed: Vec<u64>,
+    strtab: Option<u64>,
+    strtab_size: u64,
+    symtab: Option<u64>,
+    hash: Option<u64>,
+    gnu_hash: Option<u64>,
+    /// The general and the PLT relocation tables with their sizes, both in the `Elf64_Rela`
+    /// format.
+    rela_tables: [(Option<u64>, u64); 2],
+}
+
+/// An entry of the dynamic symbol table.
+#[derive(Debug)]
+struct DynamicSymbol {
+    name: u32,
+    info: u8,
+    other: u8,
+    section: u16,
+    value: u64,
+}
+
+impl DynamicSymbol {
+    fn binding(&self) -> u8 {
+        self.info >> 4
+    }
+
+    fn is_defined(&self) -> bool {
+        self.section != reloc::SHN_UNDEF
+    }
+}
+
+/// What a relocation is relative to.
+#[derive(Debug)]
+enum RelocationBase {
+    /// The load bias or the address of a local symbol, known at parsing time.
+    Fixed(u64),
+    /// A global symbol to be resolved in the scope. Weak ones are resolved to zero if not found.
+    Symbol { name: String, weak: bool },
+}
+
+/// A relocation writing `base + addend` to `offset` in the ELF.
+#[derive(Debug)]
+struct Relocation {
+    offset: u64,
+    base: RelocationBase,
+    addend: u64,
 }
 
 pub struct ElfLoader<'a, A> {
@@ -25,6 +172,21 @@ pub struct ElfLoader<'a, A> {
 
     elf: ElfFile<'static>,
 
+    /// The aligned copy of the input referred by `elf`, if the input is not aligned.
+    copied_input: Option<Vec<u64>>,
+
+    /// The offset added to all virtual addresses in the ELF, which is non-zero only for
+    /// position-independent executables.
+    bias: u64,
+
+    /// The names of the shared libraries required by `DT_NEEDED`.
+    needed: Vec<String>,
+
+    /// The global symbols defined by this ELF with the load bias applied.
+    exports: Vec<(String, u64)>,
+
+    relocations: Vec<Relocation>,
+
     page_table: &'a mut OffsetPageTable<'static>,
 
     allocator: &'a mut A,
@@ -34,161 +196,1263 @@ impl<'a, A> ElfLoader<'a, A>
 where
     A: FrameAllocator<Size4KiB>,
 {
+    /// Parse and validate the ELF file. All of the loadable segments and relocations are checked
+    /// here, so loading can only fail due to the memory or undefined symbols.
     pub fn new(
         config: &'a LoaderConfig,
         input: &'static [u8],
         allocator: &'a mut A,
         page_table: &'a mut OffsetPageTable<'static>,
-    ) -> Self {
-        let elf = ElfFile::new(input).expect("failed to parse elf");
-        Self::check_dynamic(&elf);
+    ) -> LoadResult<Self> {
+        // The ELF parser requires the headers to be aligned, so copy the input if it's not.
+        let (input, copied_input) = if input.as_ptr().align_offset(align_of::<u64>()) == 0 {
+            (input, None)
+        } else {
+            let mut copied = vec![0u64; (input.len() + 7) / 8];
+            unsafe {
+                copy_nonoverlapping(input.as_ptr(), copied.as_mut_ptr() as *mut u8, input.len());
+            }
+            // The heap buffer never moves and lives as long as the loader.
+            let input =
+                unsafe { core::slice::from_raw_parts(copied.as_ptr() as *const u8, input.len()) };
+            (input, Some(copied))
+        };
+
+        let elf = ElfFile::new(input).map_err(LoadError::Parse)?;
+        Self::check_header(&elf)?;
 
-        Self {
+        let bias = if elf.header.pt2.type_().as_type() == header::Type::SharedObject {
+            if !config.load_base.is_aligned(Size4KiB::SIZE) {
+                return Err(LoadError::InvalidAddress);
+            }
+            config.load_base.as_u64()
+        } else {
+            0
+        };
+        let entry_point = elf.header.pt2.entry_point().checked_add(bias);
+        if !entry_point.map_or(false, is_canonical) {
+            return Err(LoadError::InvalidAddress);
+        }
+
+        let mut loader = Self {
             config,
             elf,
+            copied_input,
+            bias,
+            needed: Vec::new(),
+            exports: Vec::new(),
+            relocations: Vec::new(),
             page_table,
             allocator,
+        };
+        for segment in loader.load_segments() {
+            loader.check_segment(&segment)?;
         }
+        loader.check_overlapping()?;
+        loader.parse_dynamic()?;
+
+        Ok(loader)
     }
 
-    fn check_dynamic(elf: &ElfFile) {
-        if elf.header.pt2.type_().as_type() == header::Type::SharedObject {
-            unimplemented!("loading a shared object / pie executable is not supported");
+    fn check_header(elf: &ElfFile) -> LoadResult<()> {
+        if elf.header.pt1.class() != header::Class::SixtyFour {
+            return Err(LoadError::Unsupported("not a 64-bit elf"));
+        }
+
+        // The program headers are read lazily without bounds checks, so check the table here.
+        let pt2 = &elf.header.pt2;
+        let ph_size = pt2.ph_entry_size() as u64;
+        let ph_end = pt2.ph_offset().checked_add(ph_size * pt2.ph_count() as u64);
+        if ph_size < core::mem::size_of::<ProgramHeader64>() as u64
+            || ph_end.map_or(true, |end| end > elf.input.len() as u64)
+            || (pt2.ph_offset() | ph_size) % align_of::<ProgramHeader64>() as u64 != 0
+        {
+            return Err(LoadError::Parse("program headers out of bounds"));
+        }
+        if pt2
+            .sh_offset()
+            .checked_add(pt2.sh_entry_size() as u64 * pt2.sh_count() as u64)
+            .is_none()
+        {
+            return Err(LoadError::Parse("section headers out of bounds"));
+        }
+        header::sanity_check(elf).map_err(LoadError::Parse)?;
+
+        // The interpreter is ignored since the shared libraries are loaded by the loader itself.
+        for index in 0..elf.header.pt2.ph_count() {
+            let segment = elf.program_header(index).map_err(LoadError::Parse)?;
+            segment.get_type().map_err(LoadError::Parse)?;
         }
+
+        Ok(())
     }
 
-    pub fn load(self) -> EntryPoint {
-        // TODO: This requires the target page table can access the elf input.
-        let file_base = self
-            .page_table
-            .translate_addr(VirtAddr::from_ptr(self.elf.input.as_ptr()))
-            .expect("failed to translate file base");
-        assert!(
-            file_base.is_aligned(Size4KiB::SIZE),
-            "this elf is not 4K aligned"
-        );
+    fn check_segment(&self, segment: &ProgramHeader) -> LoadResult<()> {
+        let file_end = segment
+            .offset()
+            .checked_add(segment.file_size())
+            .ok_or(LoadError::SegmentOutOfBounds)?;
+        if file_end > self.elf.input.len() as u64 || segment.file_size() > segment.mem_size() {
+            return Err(LoadError::SegmentOutOfBounds);
+        }
 
-        // TODO: use correct flags
-        let flags = {
-            let mut base = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-            if self.config.userspace {
-                base |= PageTableFlags::USER_ACCESSIBLE;
-            }
-            base
+        // Both ends must be in the same canonical half, so that the segment never crosses the hole.
+        // The end of the last page is checked, so that the page ranges never overflow either.
+        let mem_start = segment.virtual_addr().checked_add(self.bias);
+        let page_end = mem_start
+            .and_then(|start| start.checked_add(segment.mem_size() - 1))
+            .and_then(|last| (last | (Size4KiB::SIZE - 1)).checked_add(1));
+        match (mem_start, page_end) {
+            (Some(start), Some(end))
+                if is_canonical(start) && is_canonical(end) && (start ^ end) >> 63 == 0 => {}
+            _ => return Err(LoadError::InvalidAddress),
+        }
+
+        Self::segment_flags(self.config, segment).map(|_| ())
+    }
+
+    /// Check that no pages are shared by the loadable segments. Each page is mapped once with the
+    /// flags of its segment, so the segments can't even share a page on the boundary, which the
+    /// linkers never produce by default.
+    fn check_overlapping(&self) -> LoadResult<()> {
+        let mut pages = self
+            .load_segments()
+            .map(|segment| {
+                // The addresses never overflow, which is checked with the segment.
+                let start = segment.virtual_addr() + self.bias;
+                let last = start + (segment.mem_size() - 1);
+                (start / Size4KiB::SIZE, last / Size4KiB::SIZE)
+            })
+            .collect::<Vec<_>>();
+        pages.sort_unstable();
+
+        if pages.windows(2).any(|pair| pair[0].1 >= pair[1].0) {
+            return Err(LoadError::AlreadyMapped);
+        }
+        Ok(())
+    }
+
+    /// Iterate over the loadable segments. The program headers are checked in `new`.
+    fn load_segments(&self) -> impl Iterator<Item = ProgramHeader<'static>> + '_ {
+        (0..self.elf.header.pt2.ph_count())
+            .map(|index| self.elf.program_header(index).unwrap())
+            .filter(|p| p.get_type().unwrap() == program::Type::Load && p.mem_size() > 0)
+    }
+
+    /// Find the file offset of the bytes at `vaddr`, which must be inside the file part of a
+    /// loadable segment.
+    fn file_offset(&self, vaddr: u64) -> LoadResult<u64> {
+        self.load_segments()
+            .find(|s| vaddr >= s.virtual_addr() && vaddr - s.virtual_addr() < s.file_size())
+            .map(|s| s.offset() + (vaddr - s.virtual_addr()))
+            .ok_or(LoadError::SegmentOutOfBounds)
+    }
+
+    /// Read `N` bytes from the input at `offset`.
+    fn read<const N: usize>(&self, offset: u64) -> LoadResult<[u8; N]> {
+        self.elf
+            .input
+            .get(offset as usize..)
+            .and_then(|bytes| bytes.get(..N))
+            .map(|bytes| bytes.try_into().unwrap())
+            .ok_or(LoadError::SegmentOutOfBounds)
+    }
+
+    /// Read a little-endian `u64` from the input at `offset`.
+    fn read_u64(&self, offset: u64) -> LoadResult<u64> {
+        self.read(offset).map(u64::from_le_bytes)
+    }
+
+    /// Read a little-endian `u32` from the input at `offset`.
+    fn read_u32(&self, offset: u64) -> LoadResult<u32> {
+        self.read(offset).map(u32::from_le_bytes)
+    }
+
+    /// Parse the dynamic segment for the needed libraries, the exported symbols and the
+    /// relocations.
+    fn parse_dynamic(&mut self) -> LoadResult<()> {
+        let dynamic = (0..self.elf.header.pt2.ph_count())
+            .map(|index| self.elf.program_header(index).unwrap())
+            .find(|p| p.get_type().unwrap() == program::Type::Dynamic);
+        let Some(dynamic) = dynamic else {
+            return Ok(());
         };
+        let dynamic_end = dynamic.offset().checked_add(dynamic.file_size());
+        if dynamic_end.map_or(true, |end| end > self.elf.input.len() as u64) {
+            return Err(LoadError::SegmentOutOfBounds);
+        }
 
-        for segment in self
-            .elf
-            .program_iter()
-            .filter(|p| p.get_type().expect("bad type") == program::Type::Load && p.mem_size() > 0)
-        {
-            debug!("begin to map segment {:x?}", segment);
+        let mut info = DynamicInfo::default();
+        for i in 0..dynamic.file_size() / reloc::DYN_SIZE {
+            // Both of the words are inside the segment, which is checked above.
+            let entry = dynamic.offset() + i * reloc::DYN_SIZE;
+            let (tag, value) = (self.read_u64(entry)?, self.read_u64(entry + 8)?);
+            match tag {
+                reloc::DT_NULL => break,
+                reloc::DT_NEEDED => info.needed.push(value),
+                reloc::DT_STRTAB => info.strtab = Some(value),
+                reloc::DT_STRSZ => info.strtab_size = value,
+                reloc::DT_SYMTAB => info.symtab = Some(value),
+                reloc::DT_HASH => info.hash = Some(value),
+                reloc::DT_GNU_HASH => info.gnu_hash = Some(value),
+                reloc::DT_RELA => info.rela_tables[0].0 = Some(value),
+                reloc::DT_RELASZ => info.rela_tables[0].1 = value,
+                reloc::DT_JMPREL => info.rela_tables[1].0 = Some(value),
+                reloc::DT_PLTRELSZ => info.rela_tables[1].1 = value,
+                reloc::DT_RELAENT if value != reloc::RELA_SIZE => {
+                    return Err(LoadError::Parse("invalid relocation entry size"))
+                }
+                reloc::DT_SYMENT if value != reloc::SYM_SIZE => {
+                    return Err(LoadError::Parse("invalid symbol entry size"))
+                }
+                reloc::DT_REL => return Err(LoadError::Unsupported("implicit addend relocations")),
+                reloc::DT_TEXTREL => return Err(LoadError::Unsupported("text relocations")),
+                _ => {}
+            }
+        }
 
-            let file_start = file_base + segment.offset();
-            let file_end = file_start + segment.file_size();
-
-            let mem_start = VirtAddr::new(segment.virtual_addr());
-            let mem_end = mem_start + segment.mem_size();
-
-            let start_page = Page::<Size4KiB>::containing_address(mem_start);
-            let end_page = Page::containing_address(mem_end - 1u64);
-
-            let start_frame = PhysFrame::containing_address(file_start);
-            let end_frame = PhysFrame::containing_address(file_end - 1u64);
-
-            if segment.mem_size() > segment.file_size() {
-                for pair in Page::range_inclusive(start_page, end_page)
-                    .zip_longest(PhysFrame::range_inclusive(start_frame, end_frame))
-                {
-                    let (page, new_frame) = match pair {
-                        EitherOrBoth::Both(page, frame) => unsafe {
-                            let new_frame = allocate_zeroed_frame(self.allocator);
-                            let size_in_frame =
-                                core::cmp::min(frame.size(), file_end - frame.start_address())
-                                    as usize;
-                            copy_nonoverlapping(
-                                frame.start_address().as_u64() as *const u8,
-                                new_frame.start_address().as_u64() as *mut u8,
-                                size_in_frame,
-                            );
-                            (page, new_frame)
-                        },
-                        EitherOrBoth::Left(page) => {
-                            let new_frame = allocate_zeroed_frame(self.allocator);
-                            (page, new_frame)
-                        }
-                        EitherOrBoth::Right(_frame) => unreachable!(),
-                    };
+        self.needed = info
+            .needed
+            .iter()
+            .map(|&name| self.string(&info, name).map(ToString::to_string))
+            .collect::<LoadResult<_>>()?;
 
-                    unsafe {
-                        self.page_table
-                            .map_to(page, new_frame, flags, self.allocator)
-                            .expect("failed to map page")
-                            .flush();
+        for index in 1..self.symbol_count(&info)? {
+            let symbol = self.symbol(&info, index)?;
+            let visible = !matches!(symbol.other & 0x3, reloc::STV_HIDDEN | reloc::STV_INTERNAL);
+            let global = matches!(symbol.binding(), reloc::STB_GLOBAL | reloc::STB_WEAK);
+            if symbol.is_defined() && global && visible && symbol.name != 0 {
+                let name = self.string(&info, symbol.name.into())?.to_string();
+                self.exports.push((name, self.symbol_address(&symbol)));
+            }
+        }
 
-                        debug!("mapped bss {:?} to {:?}", page, new_frame);
-                    }
+        for (table, size) in info.rela_tables {
+            let Some(table) = table else {
+                continue;
+            };
+            let table_offset = self.file_offset(table)?;
+            for i in 0..size / reloc::RELA_SIZE {
+                let entry = checked_offset(table_offset, i, reloc::RELA_SIZE)?;
+                if let Some(relocation) = self.parse_relocation(&info, entry)? {
+                    self.relocations.push(relocation);
                 }
-            } else {
-                for pair in Page::range_inclusive(start_page, end_page)
-                    .zip_longest(PhysFrame::range_inclusive(start_frame, end_frame))
-                {
-                    let (page, frame) = match pair {
-                        EitherOrBoth::Both(page, frame) => (page, frame),
-                        EitherOrBoth::Left(_page) => panic!("frame not enough"),
-                        EitherOrBoth::Right(_frame) => break,
-                    };
-
-                    unsafe {
-                        self.page_table
-                            .map_to(page, frame, flags, self.allocator)
-                            .expect("failed to map page")
-                            .flush();
-
-                        debug!("mapped {:?} to {:?}", page, frame);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read the NUL-terminated string at `offset` of the dynamic string table.
+    fn string(&self, info: &DynamicInfo, offset: u64) -> LoadResult<&str> {
+        let strtab = info
+            .strtab
+            .ok_or(LoadError::Parse("no dynamic string table"))?;
+        if offset >= info.strtab_size {
+            return Err(LoadError::Parse("string out of bounds"));
+        }
+
+        let start = self.file_offset(checked_offset(strtab, offset, 1)?)? as usize;
+        let bytes = &self.elf.input[start..];
+        let len = bytes
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(LoadError::Parse("unterminated string"))?;
+        core::str::from_utf8(&bytes[..len]).map_err(|_| LoadError::Parse("invalid string"))
+    }
+
+    /// Get the number of entries in the dynamic symbol table from the hash tables.
+    fn symbol_count(&self, info: &DynamicInfo) -> LoadResult<u64> {
+        if let Some(hash) = info.hash {
+            // The number of chains equals the number of symbols.
+            return self.read_u32(self.file_offset(hash)? + 4).map(u64::from);
+        }
+        let Some(gnu_hash) = info.gnu_hash else {
+            return Ok(0);
+        };
+
+        // The GNU hash table has no count, so find the last symbol in the longest chain.
+        let base = self.file_offset(gnu_hash)?;
+        let bucket_count = self.read_u32(base)? as u64;
+        let symbol_offset = self.read_u32(base + 4)? as u64;
+        let bloom_size = self.read_u32(base + 8)? as u64;
+        let buckets = checked_offset(base + 16, bloom_size, 8)?;
+        let chains = checked_offset(buckets, bucket_count, 4)?;
+
+        let mut last = 0;
+        for i in 0..bucket_count {
+            last = last.max(self.read_u32(checked_offset(buckets, i, 4)?)? as u64);
+        }
+        if last < symbol_offset {
+            return Ok(symbol_offset);
+        }
+        while self.read_u32(checked_offset(chains, last - symbol_offset, 4)?)? & 1 == 0 {
+            last += 1;
+        }
+        Ok(last + 1)
+    }
+
+    fn symbol(&self, info: &DynamicInfo, index: u64) -> LoadResult<DynamicSymbol> {
+        let symtab = info
+            .symtab
+            .ok_or(LoadError::Parse("no dynamic symbol table"))?;
+        let entry = self.file_offset(checked_offset(symtab, index, reloc::SYM_SIZE)?)?;
+        let [info, other] = self.read(entry + 4)?;
+
+        Ok(DynamicSymbol {
+            name: self.read_u32(entry)?,
+            info,
+            other,
+            section: u16::from_le_bytes(self.read(entry + 6)?),
+            value: self.read_u64(entry + 8)?,
+        })
+    }
+
+    fn symbol_address(&self, symbol: &DynamicSymbol) -> u64 {
+        if symbol.section == reloc::SHN_ABS {
+            symbol.value
+        } else {
+            symbol.value.wrapping_add(self.bias)
+        }
+    }
+
+    /// Parse and check the relocation entry at the file offset `entry`. Returns `None` for the
+    /// entries that need nothing to do.
+    fn parse_relocation(&self, info: &DynamicInfo, entry: u64) -> LoadResult<Option<Relocation>> {
+        let offset = self.read_u64(entry)?;
+        let r_info = self.read_u64(entry + 8)?;
+        let addend = self.read_u64(entry + 16)?;
+
+        let (symbol_index, ty) = (r_info >> 32, r_info as u32);
+        let (base, addend) = match ty {
+            reloc::R_X86_64_NONE => return Ok(None),
+            reloc::R_X86_64_RELATIVE => (RelocationBase::Fixed(self.bias), addend),
+            reloc::R_X86_64_64 | reloc::R_X86_64_GLOB_DAT | reloc::R_X86_64_JUMP_SLOT => {
+                let base = if symbol_index == 0 {
+                    RelocationBase::Fixed(0)
+                } else {
+                    let symbol = self.symbol(info, symbol_index)?;
+                    if symbol.binding() == reloc::STB_LOCAL {
+                        RelocationBase::Fixed(self.symbol_address(&symbol))
+                    } else {
+                        RelocationBase::Symbol {
+                            name: self.string(info, symbol.name.into())?.to_string(),
+                            weak: symbol.binding() == reloc::STB_WEAK,
+                        }
                     }
+                };
+                // The GOT entries are the addresses of the symbols without addends.
+                let addend = if ty == reloc::R_X86_64_64 { addend } else { 0 };
+                (base, addend)
+            }
+            _ => return Err(LoadError::Unsupported("relocation type")),
+        };
+
+        let writable = self.load_segments().any(|s| {
+            s.flags().is_write()
+                && offset >= s.virtual_addr()
+                && offset
+                    .checked_add(8)
+                    .map_or(false, |end| end - s.virtual_addr() <= s.mem_size())
+        });
+        if !writable {
+            return Err(LoadError::Unsupported(
+                "relocation outside writable segments",
+            ));
+        }
+
+        Ok(Some(Relocation {
+            offset,
+            base,
+            addend,
+        }))
+    }
+
+    /// The offset added to the addresses in the ELF, which is the load base for shared objects and
+    /// zero for executables linked at fixed addresses.
+    pub fn bias(&self) -> u64 {
+        self.bias
+    }
+
+    /// The names of the shared libraries this ELF depends on.
+    pub fn needed(&self) -> &[String] {
+        &self.needed
+    }
+
+    /// Add the global symbols defined by this ELF to the scope, unless they're already defined by
+    /// the objects added before.
+    pub fn export_symbols(&self, scope: &mut SymbolScope) {
+        for (name, addr) in &self.exports {
+            scope.entry(name.clone()).or_insert(*addr);
+        }
+    }
+
+    /// Resolve the values of the relocations in the scope.
+    fn resolve_relocations(&self, scope: &SymbolScope) -> LoadResult<Vec<u64>> {
+        self.relocations
+            .iter()
+            .map(|relocation| {
+                let base = match &relocation.base {
+                    RelocationBase::Fixed(base) => *base,
+                    RelocationBase::Symbol { name, weak } => match scope.get(name) {
+                        Some(&addr) => addr,
+                        None if *weak => 0,
+                        None => return Err(LoadError::UndefinedSymbol(name.clone())),
+                    },
+                };
+                Ok(base.wrapping_add(relocation.addend))
+            })
+            .collect()
+    }
+
+    /// Patch the relocations through the target page table, after the segments are mapped.
+    fn apply_relocations(&mut self, values: &[u64]) {
+        for (relocation, value) in self.relocations.iter().zip(values) {
+            let target = VirtAddr::new(self.bias + relocation.offset);
+
+            // The target may cross the page boundary, so translate each byte.
+            for (i, byte) in value.to_le_bytes().into_iter().enumerate() {
+                let addr = self.page_table.translate_addr(target + i).unwrap();
+                unsafe { *(self.config.phys_offset + addr.as_u64()).as_mut_ptr::<u8>() = byte };
+            }
+        }
+        debug!("applied {} relocations", values.len());
+    }
+
+    /// Get the page table flags for mapping the segment. Segments that are both writable and
+    /// executable are refused.
+    fn segment_flags(config: &LoaderConfig, segment: &ProgramHeader) -> LoadResult<PageTableFlags> {
+        let segment_flags = segment.flags();
+        if segment_flags.is_write() && segment_flags.is_execute() {
+            return Err(LoadError::WritableAndExecutable);
+        }
+
+        let mut flags = PageTableFlags::PRESENT;
+        if config.userspace {
+            flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+        if segment_flags.is_write() {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !segment_flags.is_execute() {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        Ok(flags)
+    }
+
+    /// The virtual memory ranges `[start, end)` of the loadable segments, with the page table flags
+    /// they will be mapped with.
+    pub fn segment_ranges(
+        &self,
+    ) -> impl Iterator<Item = (VirtAddr, VirtAddr, PageTableFlags)> + '_ {
+        self.load_segments().map(|segment| {
+            let start = VirtAddr::new(segment.virtual_addr() + self.bias);
+            (
+                start,
+                start + segment.mem_size(),
+                Self::segment_flags(self.config, &segment).unwrap(),
+            )
+        })
+    }
+
+    fn map_page(&mut self, page: Page, frame: PhysFrame, flags: PageTableFlags) -> LoadResult<()> {
+        // The page is not mapped before, and the TLB never caches non-present entries, so there's
+        // nothing to flush.
+        unsafe {
+            self.page_table
+                .map_to(page, frame, flags, self.allocator)?
+                .ignore();
+        }
+        debug!("mapped {:?} to {:?}", page, frame);
+        Ok(())
+    }
+
+    /// Map the segment to new frames with the content copied.
+    fn load_private(&mut self, segment: &ProgramHeader, flags: PageTableFlags) -> LoadResult<()> {
+        let offset = segment.offset() as usize;
+        let data = &self.elf.input[offset..offset + segment.file_size() as usize];
+
+        let mem_start = segment.virtual_addr() + self.bias;
+        let data_end = mem_start + segment.file_size();
+        let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(mem_start));
+        let end_page = Page::containing_address(VirtAddr::new(mem_start + segment.mem_size() - 1));
+
+        for page in Page::range_inclusive(start_page, end_page) {
+            let frame = try_allocate_zeroed_frame(self.allocator, self.config.phys_offset)
+                .ok_or(LoadError::OutOfMemory)?;
+
+            // Copy the part of the data inside this page, and leave the rest zeroed.
+            let page_start = page.start_address().as_u64();
+            let copy_start = page_start.max(mem_start);
+            let copy_end = (page_start + Size4KiB::SIZE).min(data_end);
+            if copy_start < copy_end {
+                let src = &data[(copy_start - mem_start) as usize..(copy_end - mem_start) as usize];
+                unsafe {
+                    copy_nonoverlapping(
+                        src.as_ptr(),
+                        (self.config.phys_offset + frame.start_address().as_u64())
+                            .as_mut_ptr::<u8>()
+                            .add((copy_start - page_start) as usize),
+                        src.len(),
+                    );
                 }
             }
 
-            debug!("mapped this segment")
+            self.map_page(page, frame, flags)?;
         }
 
-        let entry_point = self.elf.header.pt2.entry_point();
-        debug!("entry point at 0x{:x}", self.elf.header.pt2.entry_point());
+        Ok(())
+    }
 
-        let stack_page = Page::containing_address(self.config.stack_top);
-        for i in 0..=self.config.stack_pages {
-            let page = stack_page - i;
-            let frame = allocate_zeroed_frame(self.allocator);
+    /// Map the segment to the frames of the input directly.
+    fn load_shared(
+        &mut self,
+        segment: &ProgramHeader,
+        flags: PageTableFlags,
+        file_base: PhysAddr,
+    ) -> LoadResult<()> {
+        let mem_start = VirtAddr::new(segment.virtual_addr() + self.bias);
+        let start_page = Page::<Size4KiB>::containing_address(mem_start);
+        let end_page = Page::containing_address(mem_start + (segment.mem_size() - 1));
+        let start_frame = PhysFrame::containing_address(file_base + segment.offset());
 
-            let stack_flags = if i == self.config.stack_pages {
-                // Make the bottom page unwritable.
-                flags - PageTableFlags::WRITABLE
-            } else {
-                flags
-            };
+        for (i, page) in Page::range_inclusive(start_page, end_page).enumerate() {
+            self.map_page(page, start_frame + i as u64, flags)?;
+        }
 
-            unsafe {
-                self.page_table
-                    .map_to(page, frame, stack_flags, self.allocator)
-                    .expect("failed to map page")
-                    .flush();
+        Ok(())
+    }
+
+    /// Map the segments and the stack, then apply the relocations with the symbols in `scope`.
+    pub fn load(mut self, scope: &SymbolScope) -> LoadResult<EntryPoint> {
+        let relocation_values = self.resolve_relocations(scope)?;
+
+        // The input can be mapped directly only if it's not a temporary copy, the target page table
+        // can access it and it's 4K aligned. Otherwise, all of the segments will be copied.
+        let file_base = self
+            .page_table
+            .translate_addr(VirtAddr::from_ptr(self.elf.input.as_ptr()))
+            .filter(|base| self.copied_input.is_none() && base.is_aligned(Size4KiB::SIZE));
+        if file_base.is_none() {
+            debug!("elf input is not mapped or not 4K aligned, will copy all segments");
+        }
+
+        for index in 0..self.elf.header.pt2.ph_count() {
+            let segment = self.elf.program_header(index).unwrap();
+            if segment.get_type().unwrap() != program::Type::Load || segment.mem_size() == 0 {
+                continue;
+            }
+
+            debug!("begin to map segment {:x?}", segment);
+            let flags = Self::segment_flags(self.config, &segment)?;
+
+            // Writable segments and the ones with bss are copied to private frames, so that
+            // multiple instances of the same ELF never share the writable memory. The read-only
+            // ones are mapped to the frames of the ELF directly.
+            let private = flags.contains(PageTableFlags::WRITABLE)
+                || segment.mem_size() > segment.file_size()
+                || segment.offset() % Size4KiB::SIZE != segment.virtual_addr() % Size4KiB::SIZE;
+
+            match file_base {
+                Some(file_base) if !private => self.load_shared(&segment, flags, file_base)?,
+                _ => self.load_private(&segment, flags)?,
+            }
+
+            debug!("mapped this segment")
+        }
+        self.apply_relocations(&relocation_values);
+
+        let entry_point = self.elf.header.pt2.entry_point() + self.bias;
+        debug!("entry point at 0x{:x}", entry_point);
+
+        // A zero-sized stack means the caller will set up the stack by itself.
+        if self.config.stack_pages > 0 {
+            let mut flags =
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            if self.config.userspace {
+                flags |= PageTableFlags::USER_ACCESSIBLE;
+            }
 
-                debug!("mapped {:?} to {:?}", page, frame);
+            // Map the pages right below the top, and leave the page below the bottom unmapped as
+            // the guard, so that a stack overflow faults instead of corrupting the memory.
+            let top_page = Page::containing_address(self.config.stack_top.align_up(Size4KiB::SIZE));
+            for i in 1..=self.config.stack_pages {
+                let page = top_page - i;
+                let frame = try_allocate_zeroed_frame(self.allocator, self.config.phys_offset)
+                    .ok_or(LoadError::OutOfMemory)?;
+
+                self.map_page(page, frame, flags)?;
             }
         }
 
-        entry_point as EntryPoint
+        Ok(entry_point as EntryPoint)
     }
 }
 
-pub fn allocate_zeroed_frame(allocator: &mut impl FrameAllocator<Size4KiB>) -> PhysFrame<Size4KiB> {
-    let frame = allocator
-        .allocate_frame()
-        .expect("failed to allocate frame");
-    let ptr = frame.start_address().as_u64() as *mut u8;
+/// Whether the address is canonical. Unlike `VirtAddr::try_new`, the addresses in the hole are not
+/// sign-extended silently.
+fn is_canonical(addr: u64) -> bool {
+    VirtAddr::try_new(addr).map_or(false, |canonical| canonical.as_u64() == addr)
+}
+
+/// Compute `base + index * size` in the tables of the ELF. All of them come from the input, so the
+/// overflows are reported as errors.
+fn checked_offset(base: u64, index: u64, size: u64) -> LoadResult<u64> {
+    index
+        .checked_mul(size)
+        .and_then(|offset| base.checked_add(offset))
+        .ok_or(LoadError::Parse("offset overflow"))
+}
+
+/// Allocate a frame and fill it with zeros through the physical memory mapped at `phys_offset`.
+pub fn try_allocate_zeroed_frame(
+    allocator: &mut impl FrameAllocator<Size4KiB>,
+    phys_offset: VirtAddr,
+) -> Option<PhysFrame<Size4KiB>> {
+    let frame = allocator.allocate_frame()?;
+    let ptr = (phys_offset + frame.start_address().as_u64()).as_mut_ptr::<u8>();
     unsafe {
         core::ptr::write_bytes(ptr, 0, Size4KiB::SIZE as usize);
     }
-    frame
+    Some(frame)
+}
+
+pub fn allocate_zeroed_frame(
+    allocator: &mut impl FrameAllocator<Size4KiB>,
+    phys_offset: VirtAddr,
+) -> PhysFrame<Size4KiB> {
+    try_allocate_zeroed_frame(allocator, phys_offset).expect("failed to allocate frame")
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use x86_64::structures::paging::PageTable;
+
+    use super::*;
+
+    const HEADER_SIZE: usize = 64;
+    const PH_SIZE: usize = 56;
+    const DATA_SIZE: usize = 64;
+    const BASE: u64 = 0x40_0000;
+
+    const PF_X: u32 = 1;
+    const PF_W: u32 = 2;
+    const PF_R: u32 = 4;
+
+    const ET_EXEC: u16 = 2;
+    const ET_DYN: u16 = 3;
+    const PT_LOAD: u32 = 1;
+    const PT_DYNAMIC: u32 = 2;
+    const STT_FUNC: u8 = 2;
+
+    /// The address of the GOT in the shared objects, before the load bias.
+    const GOT: u64 = 0x1000;
+    /// The offset of the dynamic segment in the shared objects, right after the program headers.
+    const DYNAMIC_OFFSET: usize = HEADER_SIZE + PH_SIZE * 3;
+    const RELATIVE_ADDEND: u64 = 0x10;
+
+    macro_rules! assert_error {
+        ($result:expr, $pattern:pat) => {
+            let result = $result;
+            assert!(
+                matches!(result, Err($pattern)),
+                "unexpected result: {:?}",
+                result
+            );
+        };
+    }
+
+    #[derive(Clone, Copy)]
+    struct Segment {
+        flags: u32,
+        offset: u64,
+        vaddr: u64,
+        file_size: u64,
+        mem_size: u64,
+    }
+
+    /// The only segment of the valid ELF, which covers the whole file with some bss.
+    fn text_segment() -> Segment {
+        let size = (HEADER_SIZE + PH_SIZE + DATA_SIZE) as u64;
+        Segment {
+            flags: PF_R | PF_X,
+            offset: 0,
+            vaddr: BASE,
+            file_size: size,
+            mem_size: size + 0x100,
+        }
+    }
+
+    fn put(elf: &mut [u8], offset: usize, bytes: &[u8]) {
+        elf[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Build a 64-bit ELF of the type with the program headers, padded to `size` bytes.
+    fn build_with(ty: u16, entry: u64, segments: &[(u32, Segment)], size: usize) -> Vec<u8> {
+        let mut elf = vec![0u8; size];
+        put(&mut elf, 0, b"\x7fELF");
+        put(&mut elf, 4, &[2, 1, 1]); // 64-bit, little endian, version 1
+        put(&mut elf, 16, &ty.to_le_bytes());
+        put(&mut elf, 18, &0x3eu16.to_le_bytes()); // x86_64
+        put(&mut elf, 20, &1u32.to_le_bytes());
+        put(&mut elf, 24, &entry.to_le_bytes());
+        put(&mut elf, 32, &(HEADER_SIZE as u64).to_le_bytes());
+        put(&mut elf, 52, &(HEADER_SIZE as u16).to_le_bytes());
+        put(&mut elf, 54, &(PH_SIZE as u16).to_le_bytes());
+        put(&mut elf, 56, &(segments.len() as u16).to_le_bytes());
+        put(&mut elf, 58, &64u16.to_le_bytes());
+
+        for (i, (segment_type, segment)) in segments.iter().enumerate() {
+            let ph = HEADER_SIZE + PH_SIZE * i;
+            put(&mut elf, ph, &segment_type.to_le_bytes());
+            put(&mut elf, ph + 4, &segment.flags.to_le_bytes());
+            put(&mut elf, ph + 8, &segment.offset.to_le_bytes());
+            put(&mut elf, ph + 16, &segment.vaddr.to_le_bytes());
+            put(&mut elf, ph + 24, &segment.vaddr.to_le_bytes());
+            put(&mut elf, ph + 32, &segment.file_size.to_le_bytes());
+            put(&mut elf, ph + 40, &segment.mem_size.to_le_bytes());
+            put(&mut elf, ph + 48, &Size4KiB::SIZE.to_le_bytes());
+        }
+        elf
+    }
+
+    /// Build a 64-bit executable with the loadable segments, followed by `DATA_SIZE` bytes.
+    fn build(entry: u64, segments: &[Segment]) -> Vec<u8> {
+        let segments = segments.iter().map(|&s| (PT_LOAD, s)).collect::<Vec<_>>();
+        let size = HEADER_SIZE + PH_SIZE * segments.len() + DATA_SIZE;
+        build_with(ET_EXEC, entry, &segments, size)
+    }
+
+    /// Build a shared object, which needs the libraries and defines the symbols at the offsets.
+    /// The writable page at `GOT` holds an entry for each imported symbol, followed by a pointer
+    /// to `RELATIVE_ADDEND` in this object.
+    fn build_dynamic(needed: &[&str], exports: &[(&str, u64)], imports: &[&str]) -> Vec<u8> {
+        let mut strtab = vec![0u8];
+        let mut add_string = |s: &str| {
+            strtab.extend_from_slice(s.as_bytes());
+            strtab.push(0);
+            (strtab.len() - s.len() - 1) as u64
+        };
+
+        let mut dynamic = Vec::new();
+        for name in needed {
+            dynamic.push((reloc::DT_NEEDED, add_string(name)));
+        }
+
+        let mut symtab = vec![0u8; reloc::SYM_SIZE as usize];
+        let defined = exports.iter().map(|&(name, value)| (name, value, 1u16));
+        let undefined = imports.iter().map(|&name| (name, 0, reloc::SHN_UNDEF));
+        for (name, value, section) in defined.chain(undefined) {
+            symtab.extend_from_slice(&(add_string(name) as u32).to_le_bytes());
+            symtab.push(reloc::STB_GLOBAL << 4 | STT_FUNC);
+            symtab.push(0);
+            symtab.extend_from_slice(&section.to_le_bytes());
+            symtab.extend_from_slice(&value.to_le_bytes());
+            symtab.extend_from_slice(&0u64.to_le_bytes());
+        }
+        let symbol_count = symtab.len() as u64 / reloc::SYM_SIZE;
+
+        // A single bucket, the loader only reads the number of chains.
+        let mut hash = Vec::new();
+        for word in [1, symbol_count as u32, 0]
+            .into_iter()
+            .chain((0..symbol_count).map(|_| 0))
+        {
+            hash.extend_from_slice(&word.to_le_bytes());
+        }
+
+        let mut rela = Vec::new();
+        let first_import = symbol_count - imports.len() as u64;
+        let relocations = (0..imports.len() as u64)
+            .map(|i| {
+                (
+                    GOT + i * 8,
+                    (first_import + i) << 32 | reloc::R_X86_64_GLOB_DAT as u64,
+                    0,
+                )
+            })
+            .chain([(
+                GOT + imports.len() as u64 * 8,
+                reloc::R_X86_64_RELATIVE as u64,
+                RELATIVE_ADDEND,
+            )]);
+        for (offset, info, addend) in relocations {
+            for value in [offset, info, addend] {
+                rela.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+
+        // Place the tables after the headers, in the first read-only page.
+        let dynamic_offset = DYNAMIC_OFFSET as u64;
+        let dynamic_size = (dynamic.len() as u64 + 9) * reloc::DYN_SIZE;
+        let strtab_offset = dynamic_offset + dynamic_size;
+        let symtab_offset = (strtab_offset + strtab.len() as u64 + 7) & !7;
+        let hash_offset = symtab_offset + symtab.len() as u64;
+        let rela_offset = (hash_offset + hash.len() as u64 + 7) & !7;
+        let tables_end = rela_offset + rela.len() as u64;
+        assert!(tables_end <= GOT);
+
+        dynamic.extend([
+            (reloc::DT_STRTAB, strtab_offset),
+            (reloc::DT_STRSZ, strtab.len() as u64),
+            (reloc::DT_SYMTAB, symtab_offset),
+            (reloc::DT_SYMENT, reloc::SYM_SIZE),
+            (reloc::DT_HASH, hash_offset),
+            (reloc::DT_RELA, rela_offset),
+            (reloc::DT_RELASZ, rela.len() as u64),
+            (reloc::DT_RELAENT, reloc::RELA_SIZE),
+            (reloc::DT_NULL, 0),
+        ]);
+
+        let got_size = (imports.len() as u64 + 1) * 8;
+        let segments = [
+            (
+                PT_LOAD,
+                Segment {
+                    flags: PF_R,
+                    offset: 0,
+                    vaddr: 0,
+                    file_size: tables_end,
+                    mem_size: tables_end,
+                },
+            ),
+            (
+                PT_LOAD,
+                Segment {
+                    flags: PF_R | PF_W,
+                    offset: GOT,
+                    vaddr: GOT,
+                    file_size: got_size,
+                    mem_size: got_size,
+                },
+            ),
+            (
+                PT_DYNAMIC,
+                Segment {
+                    flags: PF_R,
+                    offset: dynamic_offset,
+                    vaddr: dynamic_offset,
+                    file_size: dynamic_size,
+                    mem_size: dynamic_size,
+                },
+            ),
+        ];
+        let mut elf = build_with(ET_DYN, 0, &segments, (GOT + got_size) as usize);
+
+        for (i, (tag, value)) in dynamic.into_iter().enumerate() {
+            let entry = dynamic_offset as usize + i * reloc::DYN_SIZE as usize;
+            put(&mut elf, entry, &tag.to_le_bytes());
+            put(&mut elf, entry + 8, &value.to_le_bytes());
+        }
+        put(&mut elf, strtab_offset as usize, &strtab);
+        put(&mut elf, symtab_offset as usize, &symtab);
+        put(&mut elf, hash_offset as usize, &hash);
+        put(&mut elf, rela_offset as usize, &rela);
+        elf
+    }
+
+    /// Find the entry of the tag in the dynamic segment of a shared object, and return its offset.
+    fn dynamic_entry(elf: &[u8], tag: u64) -> usize {
+        (DYNAMIC_OFFSET..)
+            .step_by(reloc::DYN_SIZE as usize)
+            .find(|&entry| elf[entry..entry + 8] == tag.to_le_bytes())
+            .unwrap()
+    }
+
+    fn set_dynamic(elf: &mut [u8], tag: u64, value: u64) {
+        let entry = dynamic_entry(elf, tag);
+        put(elf, entry + 8, &value.to_le_bytes());
+    }
+
+    fn valid() -> Vec<u8> {
+        build(BASE + 0x78, &[text_segment()])
+    }
+
+    /// Copy the ELF to a leaked buffer at `misalign` bytes after an 8-byte boundary.
+    fn leak(elf: &[u8], misalign: usize) -> &'static [u8] {
+        let buffer = vec![0u64; (misalign + elf.len() + 7) / 8].leak();
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, buffer.len() * 8)
+        };
+        let bytes = &mut bytes[misalign..misalign + elf.len()];
+        bytes.copy_from_slice(elf);
+        bytes
+    }
+
+    struct NoFrames;
+
+    unsafe impl FrameAllocator<Size4KiB> for NoFrames {
+        fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+            None
+        }
+    }
+
+    #[repr(C, align(4096))]
+    struct HostFrame([u8; Size4KiB::SIZE as usize]);
+
+    /// Leak `count` frames filled with garbage on the host.
+    fn leak_frames(count: usize) -> &'static mut [HostFrame] {
+        (0..count)
+            .map(|_| HostFrame([0xcc; Size4KiB::SIZE as usize]))
+            .collect::<Vec<_>>()
+            .leak()
+    }
+
+    fn host_frame(addr: *const u8) -> PhysFrame {
+        PhysFrame::from_start_address(PhysAddr::new(addr as u64)).unwrap()
+    }
+
+    /// Hand out the frames leaked on the host. With the physical memory "mapped" at zero, the
+    /// loader accesses the frames and the page tables through their host addresses.
+    struct VecFrames(Vec<PhysFrame>);
+
+    impl VecFrames {
+        fn new(count: usize) -> Self {
+            let frames = leak_frames(count);
+            Self(
+                frames
+                    .iter()
+                    .map(|frame| host_frame(frame.0.as_ptr()))
+                    .collect(),
+            )
+        }
+    }
+
+    unsafe impl FrameAllocator<Size4KiB> for VecFrames {
+        fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+            self.0.pop()
+        }
+    }
+
+    /// Copy the ELF to leaked frames, so that it can be mapped directly.
+    fn leak_page_aligned(elf: &[u8]) -> &'static [u8] {
+        let frames = leak_frames((elf.len() + 4095) / 4096);
+        let bytes =
+            unsafe { core::slice::from_raw_parts_mut(frames.as_mut_ptr() as *mut u8, elf.len()) };
+        bytes.copy_from_slice(elf);
+        bytes
+    }
+
+    /// Read the bytes at `addr` of the loaded ELF through the page table.
+    fn read_loaded(page_table: &OffsetPageTable, addr: u64, len: u64) -> Vec<u8> {
+        (addr..addr + len)
+            .map(|addr| {
+                let addr = page_table.translate_addr(VirtAddr::new(addr)).unwrap();
+                unsafe { *(addr.as_u64() as *const u8) }
+            })
+            .collect()
+    }
+
+    fn config(load_base: u64) -> LoaderConfig {
+        LoaderConfig {
+            stack_top: VirtAddr::zero(),
+            stack_pages: 0,
+            userspace: true,
+            load_base: VirtAddr::new(load_base),
+            phys_offset: VirtAddr::zero(),
+        }
+    }
+
+    fn page_table() -> OffsetPageTable<'static> {
+        let page_table = Box::leak(Box::new(PageTable::new()));
+        unsafe { OffsetPageTable::new(page_table, VirtAddr::zero()) }
+    }
+
+    /// Parse and check the ELF without loading it.
+    fn check(elf: &[u8], misalign: usize) -> LoadResult<()> {
+        let config = config(0x1000_0000);
+        let mut page_table = page_table();
+
+        ElfLoader::new(&config, leak(elf, misalign), &mut NoFrames, &mut page_table).map(|_| ())
+    }
+
+    #[test]
+    fn valid_elf() {
+        check(&valid(), 0).unwrap();
+    }
+
+    #[test]
+    fn truncated_header() {
+        assert_error!(check(&valid()[..HEADER_SIZE / 2], 0), LoadError::Parse(_));
+    }
+
+    #[test]
+    fn program_headers_out_of_bounds() {
+        let elf = valid();
+        assert_error!(
+            check(&elf[..HEADER_SIZE + PH_SIZE / 2], 0),
+            LoadError::Parse("program headers out of bounds")
+        );
+
+        let mut elf = valid();
+        put(&mut elf, 56, &u16::MAX.to_le_bytes());
+        assert_error!(
+            check(&elf, 0),
+            LoadError::Parse("program headers out of bounds")
+        );
+    }
+
+    #[test]
+    fn segment_out_of_input() {
+        let mut segment = text_segment();
+        segment.file_size += 1;
+        assert_error!(
+            check(&build(BASE, &[segment]), 0),
+            LoadError::SegmentOutOfBounds
+        );
+
+        let mut segment = text_segment();
+        segment.offset = u64::MAX;
+        assert_error!(
+            check(&build(BASE, &[segment]), 0),
+            LoadError::SegmentOutOfBounds
+        );
+    }
+
+    #[test]
+    fn file_size_over_mem_size() {
+        let mut segment = text_segment();
+        segment.mem_size = segment.file_size - 1;
+        assert_error!(
+            check(&build(BASE, &[segment]), 0),
+            LoadError::SegmentOutOfBounds
+        );
+    }
+
+    #[test]
+    fn writable_and_executable() {
+        let mut segment = text_segment();
+        segment.flags = PF_R | PF_W | PF_X;
+        assert_error!(
+            check(&build(BASE, &[segment]), 0),
+            LoadError::WritableAndExecutable
+        );
+    }
+
+    #[test]
+    fn segments_sharing_page() {
+        let text = text_segment();
+        let mut data = Segment {
+            flags: PF_R | PF_W,
+            offset: 0,
+            vaddr: text.vaddr + text.mem_size,
+            file_size: 0,
+            mem_size: 0x100,
+        };
+        assert_error!(
+            check(&build(BASE, &[text, data]), 0),
+            LoadError::AlreadyMapped
+        );
+        assert_error!(
+            check(&build(BASE, &[data, text]), 0),
+            LoadError::AlreadyMapped
+        );
+
+        data.vaddr = BASE + Size4KiB::SIZE;
+        check(&build(BASE, &[text, data]), 0).unwrap();
+    }
+
+    #[test]
+    fn load_segments() {
+        let text = text_segment();
+        let text = Segment {
+            mem_size: text.file_size,
+            ..text
+        };
+        // The data is copied from the program headers, with the bss over the next page.
+        let data = Segment {
+            flags: PF_R | PF_W,
+            offset: HEADER_SIZE as u64,
+            vaddr: BASE + 0x2000 + HEADER_SIZE as u64,
+            file_size: PH_SIZE as u64,
+            mem_size: 0x1800,
+        };
+        let elf = build(BASE, &[text, data]);
+
+        for mapped in [false, true] {
+            let config = config(0);
+            let (mut frames, mut page_table) = (VecFrames::new(32), page_table());
+            let input = leak_page_aligned(&elf);
+            if mapped {
+                // Map the input at its host address, so that the read-only segment is shared.
+                let page = Page::containing_address(VirtAddr::from_ptr(input.as_ptr()));
+                unsafe {
+                    page_table
+                        .map_to(
+                            page,
+                            host_frame(input.as_ptr()),
+                            PageTableFlags::PRESENT,
+                            &mut frames,
+                        )
+                        .unwrap()
+                        .ignore();
+                }
+            }
+
+            let loader = ElfLoader::new(&config, input, &mut frames, &mut page_table).unwrap();
+            loader.load(&SymbolScope::new()).unwrap();
+
+            let text_frame = page_table.translate_addr(VirtAddr::new(BASE)).unwrap();
+            assert_eq!(text_frame.as_u64() == input.as_ptr() as u64, mapped);
+            let text_size = text.file_size as usize;
+            assert_eq!(
+                read_loaded(&page_table, BASE, text.file_size),
+                elf[..text_size]
+            );
+
+            // The data page is always private, with the bytes out of the segment zeroed.
+            let data_frame = page_table
+                .translate_addr(VirtAddr::new(data.vaddr))
+                .unwrap();
+            assert_ne!(data_frame.as_u64(), input.as_ptr() as u64 + data.offset);
+            let mut expected = vec![0; 0x2000];
+            let start = HEADER_SIZE;
+            expected[start..start + PH_SIZE].copy_from_slice(&elf[start..start + PH_SIZE]);
+            assert_eq!(read_loaded(&page_table, BASE + 0x2000, 0x2000), expected);
+        }
+    }
+
+    #[test]
+    fn non_canonical_address() {
+        let mut segment = text_segment();
+        segment.vaddr = 0x8000_0000_0000;
+        assert_error!(
+            check(&build(BASE, &[segment]), 0),
+            LoadError::InvalidAddress
+        );
+
+        // The segment starts in the lower half but ends in the hole.
+        let mut segment = text_segment();
+        segment.vaddr = 0x7fff_ffff_f000;
+        segment.mem_size = 0x2000;
+        assert_error!(
+            check(&build(BASE, &[segment]), 0),
+            LoadError::InvalidAddress
+        );
+
+        assert_error!(
+            check(&build(0x8000_0000_0000, &[text_segment()]), 0),
+            LoadError::InvalidAddress
+        );
+
+        // The end of the last page is not canonical, or overflows.
+        for vaddr in [0x7fff_ffff_f000, 0xffff_ffff_ffff_f000] {
+            let mut segment = text_segment();
+            segment.vaddr = vaddr;
+            assert_error!(
+                check(&build(BASE, &[segment]), 0),
+                LoadError::InvalidAddress
+            );
+        }
+    }
+
+    #[test]
+    fn unaligned_input() {
+        check(&valid(), 1).unwrap();
+        assert_error!(check(&valid()[..HEADER_SIZE / 2], 3), LoadError::Parse(_));
+
+        let mut segment = text_segment();
+        segment.file_size += 1;
+        assert_error!(
+            check(&build(BASE, &[segment]), 5),
+            LoadError::SegmentOutOfBounds
+        );
+    }
+
+    #[test]
+    fn dynamic_out_of_input() {
+        // The program header of the dynamic segment.
+        let ph = HEADER_SIZE + PH_SIZE * 2;
+
+        let mut elf = build_dynamic(&[], &[], &[]);
+        put(&mut elf, ph + 8, &u64::MAX.to_le_bytes());
+        assert_error!(check(&elf, 0), LoadError::SegmentOutOfBounds);
+
+        let mut elf = build_dynamic(&[], &[], &[]);
+        let size = elf.len() as u64;
+        put(&mut elf, ph + 32, &size.to_le_bytes());
+        assert_error!(check(&elf, 0), LoadError::SegmentOutOfBounds);
+    }
+
+    #[test]
+    fn dynamic_offsets_overflow() {
+        // The string offsets are checked against the huge size only.
+        let mut elf = build_dynamic(&["libfoo.so"], &[], &[]);
+        set_dynamic(&mut elf, reloc::DT_STRTAB, u64::MAX);
+        set_dynamic(&mut elf, reloc::DT_STRSZ, u64::MAX);
+        assert_error!(check(&elf, 0), LoadError::Parse("offset overflow"));
+
+        let mut elf = build_dynamic(&[], &[("foo", 0x30)], &[]);
+        set_dynamic(&mut elf, reloc::DT_SYMTAB, u64::MAX);
+        assert_error!(check(&elf, 0), LoadError::Parse("offset overflow"));
+    }
+
+    #[test]
+    fn huge_gnu_hash_bloom() {
+        // Replace the hash table with a GNU one, whose bloom filter is out of the input.
+        let mut elf = build_dynamic(&[], &[("foo", 0x30)], &[]);
+        let entry = dynamic_entry(&elf, reloc::DT_HASH);
+        let hash = u64::from_le_bytes(elf[entry + 8..entry + 16].try_into().unwrap()) as usize;
+        put(&mut elf, entry, &reloc::DT_GNU_HASH.to_le_bytes());
+        for (i, word) in [1, 1, u32::MAX, 0].into_iter().enumerate() {
+            put(&mut elf, hash + i * 4, &word.to_le_bytes());
+        }
+        assert_error!(check(&elf, 0), LoadError::SegmentOutOfBounds);
+    }
+
+    #[test]
+    fn dynamic_linking() {
+        const EXE_BASE: u64 = 0x1000_0000;
+        const LIB_BASE: u64 = 0x2000_0000;
+
+        let exe = leak(
+            &build_dynamic(&["libfoo.so"], &[("bar", 0x20)], &["foo", "bar"]),
+            0,
+        );
+        let lib = leak(&build_dynamic(&[], &[("foo", 0x30), ("bar", 0x40)], &[]), 0);
+        let (exe_config, lib_config) = (config(EXE_BASE), config(LIB_BASE));
+        let (mut frames, mut page_table) = (NoFrames, page_table());
+
+        // Export the symbols in the order of loading, as the kernel does, so the executable takes
+        // precedence over its libraries.
+        let mut scope = SymbolScope::new();
+        let loader = ElfLoader::new(&exe_config, exe, &mut frames, &mut page_table).unwrap();
+        assert_eq!(loader.needed(), ["libfoo.so"]);
+        loader.export_symbols(&mut scope);
+        let loader = ElfLoader::new(&lib_config, lib, &mut frames, &mut page_table).unwrap();
+        assert!(loader.needed().is_empty());
+        loader.export_symbols(&mut scope);
+        assert_eq!(scope["foo"], LIB_BASE + 0x30);
+        assert_eq!(scope["bar"], EXE_BASE + 0x20);
+
+        let loader = ElfLoader::new(&lib_config, lib, &mut frames, &mut page_table).unwrap();
+        assert_eq!(
+            loader.resolve_relocations(&scope).unwrap(),
+            [LIB_BASE + RELATIVE_ADDEND]
+        );
+        let loader = ElfLoader::new(&exe_config, exe, &mut frames, &mut page_table).unwrap();
+        assert_eq!(
+            loader.resolve_relocations(&scope).unwrap(),
+            [LIB_BASE + 0x30, EXE_BASE + 0x20, EXE_BASE + RELATIVE_ADDEND]
+        );
+    }
+
+    #[test]
+    fn undefined_symbol() {
+        let exe = leak(&build_dynamic(&["libfoo.so"], &[], &["foo"]), 0);
+        let config = config(0x1000_0000);
+        let (mut frames, mut page_table) = (NoFrames, page_table());
+
+        let loader = ElfLoader::new(&config, exe, &mut frames, &mut page_table).unwrap();
+        let result = loader.load(&SymbolScope::new());
+        assert!(
+            matches!(&result, Err(LoadError::UndefinedSymbol(name)) if name == "foo"),
+            "unexpected result: {:?}",
+            result
+        );
+    }
 }
@@ -1,8 +1,10 @@
 #![no_std]
+#![feature(let_else)]
 
 extern crate alloc;
 
 pub mod boot_info;
 pub mod elf_loader;
+pub mod symbols;
 
 pub use boot_info::BootInfo;
@@ -0,0 +1,59 @@
+use alloc::vec::Vec;
+
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::{Entry, Type};
+use xmas_elf::ElfFile;
+
+/// A function symbol of an ELF, to symbolize the backtraces.
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    /// The address without the load bias.
+    pub addr: u64,
+
+    pub size: u64,
+
+    /// The mangled name.
+    pub name: &'static str,
+}
+
+/// Collect the function symbols from the `.symtab` of the ELF, sorted by the address. Returns an
+/// empty table if the ELF is stripped or invalid.
+pub fn function_symbols(elf_bytes: &'static [u8]) -> Vec<Symbol> {
+    let elf = match ElfFile::new(elf_bytes) {
+        Ok(elf) => elf,
+        Err(_) => return Vec::new(),
+    };
+    let entries = match elf
+        .find_section_by_name(".symtab")
+        .map(|section| section.get_data(&elf))
+    {
+        Some(Ok(SectionData::SymbolTable64(entries))) => entries,
+        _ => return Vec::new(),
+    };
+
+    let mut symbols = entries
+        .iter()
+        .filter(|entry| entry.get_type() == Ok(Type::Func) && entry.value() != 0)
+        .filter_map(|entry| {
+            Some(Symbol {
+                addr: entry.value(),
+                size: entry.size(),
+                name: entry.get_name(&elf).ok()?,
+            })
+        })
+        .collect::<Vec<_>>();
+    symbols.sort_unstable_by_key(|symbol| symbol.addr);
+    symbols
+}
+
+/// Find the symbol containing the address in the sorted table, and the offset in it.
+pub fn find(symbols: &[Symbol], addr: u64) -> Option<(&Symbol, u64)> {
+    let index = symbols
+        .partition_point(|symbol| symbol.addr <= addr)
+        .checked_sub(1)?;
+    let symbol = &symbols[index];
+
+    let offset = addr - symbol.addr;
+    // Some symbols have no size, assume that they extend to the next one.
+    (symbol.size == 0 || offset < symbol.size).then(|| (symbol, offset))
+}
@@ -9,7 +9,9 @@ use acpi::platform::{Processor, ProcessorInfo};
 use acpi::{AcpiHandler, AcpiTables};
 use lazy_static::lazy_static;
 use log::info;
+use x86_64::PhysAddr;
 
+use crate::memory::phys_to_virt;
 use crate::BOOT_INFO;
 
 #[derive(Clone)]
@@ -23,7 +25,9 @@ impl AcpiHandler for OffsetAcpiHandler {
     ) -> acpi::PhysicalMapping<Self, T> {
         acpi::PhysicalMapping::new(
             physical_address,
-            NonNull::new_unchecked(physical_address as *mut _),
+            NonNull::new_unchecked(
+                phys_to_virt(PhysAddr::new(physical_address as u64)).as_mut_ptr(),
+            ),
             size,
             size,
             self.clone(),
@@ -0,0 +1,101 @@
+use core::arch::asm;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use litchi_common::symbols::{self, Symbol};
+use log::error;
+use x86_64::VirtAddr;
+
+pub use self::demangle::Demangle;
+use crate::BOOT_INFO;
+
+mod demangle;
+
+/// Stop walking after this many frames, in case the chain of frame pointers is corrupted.
+const MAX_FRAMES: usize = 32;
+
+/// The start of the higher half. Frame pointers below it are not on any kernel stack.
+const KERNEL_SPACE_START: u64 = 0xffff_8000_0000_0000;
+
+/// Set while a backtrace is being printed, so that a fault on a bad frame pointer doesn't walk the
+/// stack again.
+static PRINTING: AtomicBool = AtomicBool::new(false);
+
+/// The address faulted on while walking the frames, where the later walks stop.
+static FAULT_ADDR: AtomicU64 = AtomicU64::new(0);
+
+fn kernel_symbols() -> &'static [Symbol] {
+    BOOT_INFO
+        .get()
+        .map_or(&[], |boot_info| boot_info.kernel_symbols)
+}
+
+/// The return address of a frame, printed as `function+offset`.
+struct Location(u64);
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The return address may be right after the function if it ends with a call, so look up the
+        // call instruction instead.
+        match symbols::find(kernel_symbols(), self.0 - 1) {
+            Some((symbol, offset)) => write!(f, "{}+{:#x}", Demangle(symbol.name), offset + 1),
+            None => write!(f, "??"),
+        }
+    }
+}
+
+/// Print the call stack of the kernel to the log by walking the frame pointers. Frames of the
+/// interrupted code are included if called from an interrupt handler.
+#[inline(never)]
+pub fn print() {
+    if PRINTING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let mut frame_pointer: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) frame_pointer) };
+
+    error!("backtrace:");
+    let fault_addr = FAULT_ADDR.load(Ordering::SeqCst);
+    for depth in 0..MAX_FRAMES {
+        if frame_pointer < KERNEL_SPACE_START || frame_pointer % 8 != 0 {
+            break;
+        }
+        let Some(frame_end) = frame_pointer.checked_add(16) else {
+            break;
+        };
+        if (frame_pointer..frame_end).contains(&fault_addr) {
+            error!("{:>4}: bad frame pointer {:#x}", depth, frame_pointer);
+            break;
+        }
+
+        // The saved frame pointer of the caller, followed by the return address.
+        let (next, return_addr) = unsafe {
+            let frame = frame_pointer as *const u64;
+            (*frame, *frame.add(1))
+        };
+        if return_addr == 0 {
+            break;
+        }
+
+        error!("{:>4}: {:#x} {}", depth, return_addr, Location(return_addr));
+
+        // The stack grows down, so the callers' frames must be above. Otherwise the chain is
+        // corrupted, and may even be cyclic.
+        if next <= frame_pointer {
+            break;
+        }
+        frame_pointer = next;
+    }
+
+    PRINTING.store(false, Ordering::SeqCst);
+}
+
+/// Called on the kernel faults at `addr`. If it's from walking a bad frame pointer, the backtrace
+/// is cut off, so allow printing again and stop the later walks before the address.
+pub fn handle_fault(addr: VirtAddr) {
+    if PRINTING.load(Ordering::SeqCst) {
+        FAULT_ADDR.store(addr.as_u64(), Ordering::SeqCst);
+        PRINTING.store(false, Ordering::SeqCst);
+    }
+}
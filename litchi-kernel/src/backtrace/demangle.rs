@@ -0,0 +1,101 @@
+use core::fmt::{self, Write};
+
+/// A symbol name of the legacy Rust mangling, like `_ZN4core9panicking5panic17h0123456789abcdefE`,
+/// displayed as `core::panicking::panic`. Other names are displayed as is.
+pub struct Demangle<'a>(pub &'a str);
+
+/// Split the next length-prefixed identifier from the path.
+fn next_ident(path: &str) -> Option<(&str, &str)> {
+    let digits = path.find(|c: char| !c.is_ascii_digit())?;
+    let len: usize = path[..digits].parse().ok()?;
+    let rest = &path[digits..];
+    rest.get(..len).map(|ident| (ident, &rest[len..]))
+}
+
+/// Whether the identifier is the hash appended to the path, like `h0123456789abcdef`.
+fn is_hash(ident: &str) -> bool {
+    ident.len() == 17 && ident.starts_with('h') && ident[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Write the identifier with the escapes like `$LT$` and `..` unescaped.
+fn write_ident(f: &mut fmt::Formatter<'_>, ident: &str) -> fmt::Result {
+    // Identifiers starting with `$` are prefixed with `_`.
+    let mut rest = if ident.starts_with("_$") {
+        &ident[1..]
+    } else {
+        ident
+    };
+
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = after;
+        } else if let Some((escape, after)) = rest
+            .strip_prefix('$')
+            .and_then(|escaped| escaped.split_once('$'))
+        {
+            let unescaped = match escape {
+                "SP" => Some('@'),
+                "BP" => Some('*'),
+                "RF" => Some('&'),
+                "LT" => Some('<'),
+                "GT" => Some('>'),
+                "LP" => Some('('),
+                "RP" => Some(')'),
+                "C" => Some(','),
+                _ => escape
+                    .strip_prefix('u')
+                    .and_then(|code| u32::from_str_radix(code, 16).ok())
+                    .and_then(char::from_u32),
+            };
+            match unescaped {
+                Some(c) => f.write_char(c)?,
+                None => write!(f, "${}$", escape)?,
+            }
+            rest = after;
+        } else {
+            let c = rest.chars().next().unwrap();
+            f.write_char(c)?;
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(path) = self
+            .0
+            .strip_prefix("_ZN")
+            .and_then(|path| path.strip_suffix('E'))
+        else {
+            return f.write_str(self.0);
+        };
+
+        // Check the whole path before writing anything.
+        let mut idents = 0;
+        let mut rest = path;
+        while !rest.is_empty() {
+            let Some((_, after)) = next_ident(rest) else {
+                return f.write_str(self.0);
+            };
+            idents += 1;
+            rest = after;
+        }
+
+        let mut rest = path;
+        for i in 0..idents {
+            let (ident, after) = next_ident(rest).unwrap();
+            rest = after;
+
+            if i == idents - 1 && i > 0 && is_hash(ident) {
+                break;
+            }
+            if i > 0 {
+                f.write_str("::")?;
+            }
+            write_ident(f, ident)?;
+        }
+        Ok(())
+    }
+}
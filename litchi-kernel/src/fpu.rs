@@ -0,0 +1,68 @@
+use core::arch::asm;
+
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+/// The default x87 control word, with all exceptions masked and the extended precision.
+const DEFAULT_FCW: u16 = 0x037f;
+
+/// The default MXCSR, with all exceptions masked and rounding to nearest.
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+/// Enable the x87 FPU and SSE for user tasks on the current processor. The kernel is built with
+/// soft float and never touches these registers, so they're only switched between user tasks.
+pub fn init() {
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            // Report x87 errors with exceptions instead of the legacy interrupt.
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+        asm!("fninit", options(nomem, nostack));
+    }
+}
+
+/// The x87 and SSE registers of a user task, in the layout of `fxsave`. It's saved every time
+/// returning from the task, and restored before running it again, so that tasks can be moved
+/// between processors freely.
+#[repr(C, align(16))]
+pub struct FpuState([u8; 512]);
+
+impl FpuState {
+    fn control_word(&self) -> u16 {
+        u16::from_le_bytes([self.0[0], self.0[1]])
+    }
+
+    fn mxcsr(&self) -> u32 {
+        u32::from_le_bytes(self.0[24..28].try_into().unwrap())
+    }
+
+    /// Save the registers of the current processor.
+    pub fn save(&mut self) {
+        unsafe { asm!("fxsave64 [{}]", in(reg) self.0.as_mut_ptr(), options(nostack)) };
+    }
+
+    /// Load the registers to the current processor.
+    pub fn restore(&self) {
+        unsafe { asm!("fxrstor64 [{}]", in(reg) self.0.as_ptr(), options(nostack)) };
+    }
+}
+
+impl Default for FpuState {
+    /// The state after `fninit`, with empty x87 registers and zeroed SSE registers.
+    fn default() -> Self {
+        let mut state = Self([0; 512]);
+        state.0[0..2].copy_from_slice(&DEFAULT_FCW.to_le_bytes());
+        state.0[24..28].copy_from_slice(&DEFAULT_MXCSR.to_le_bytes());
+        state
+    }
+}
+
+impl core::fmt::Debug for FpuState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("FpuState")
+            .field("control_word", &format_args!("{:#x}", self.control_word()))
+            .field("mxcsr", &format_args!("{:#x}", self.mxcsr()))
+            .finish_non_exhaustive()
+    }
+}
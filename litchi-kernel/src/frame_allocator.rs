@@ -1,17 +1,25 @@
+mod buddy;
 mod global;
 mod raii;
+mod zero_pool;
 
+pub use global::{FrameStats, LOW_MEMORY_END};
 use log::info;
 pub use raii::RaiiFrameAllocator;
-use spin::Mutex;
-use x86_64::structures::paging::FrameAllocator;
+use x86_64::structures::paging::frame::PhysFrameRange;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
 
+use self::buddy::{order_of, MAX_ORDER};
 use self::global::{GlobalFrameAllocator, FRAME_ALLOCATOR};
+use self::zero_pool::{ZeroPool, ZERO_POOL};
+use crate::memory::phys_to_virt;
+use crate::sync::IrqSafeMutex;
 use crate::BOOT_INFO;
 
 pub fn init() {
     let boot_info = BOOT_INFO.get().unwrap();
-    FRAME_ALLOCATOR.call_once(|| Mutex::new(GlobalFrameAllocator::new(boot_info)));
+    FRAME_ALLOCATOR
+        .call_once(|| IrqSafeMutex::new("frame allocator", GlobalFrameAllocator::new(boot_info)));
 
     {
         let mut allocator = FRAME_ALLOCATOR.get().unwrap().lock();
@@ -20,5 +28,139 @@ pub fn init() {
             .expect("failed to allocate test frame");
     }
 
-    info!("initialized frame allocator from boot info");
+    info!(
+        "initialized buddy frame allocator from boot info: {:?}",
+        stats()
+    );
+}
+
+fn with_global<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut GlobalFrameAllocator) -> R,
+{
+    let mut allocator = FRAME_ALLOCATOR
+        .get()
+        .expect("frame allocator not initialized")
+        .lock();
+    f(&mut *allocator)
+}
+
+/// The number of frames reserved for the kernel. Allocations for users fail when the free frames
+/// drop below this, so that the OOM killer kicks in before the kernel itself runs out of memory.
+const KERNEL_RESERVED_FRAMES: u64 = 256;
+
+fn with_pool<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut ZeroPool) -> R,
+{
+    f(&mut *ZERO_POOL.lock())
+}
+
+fn zero_frame(frame: PhysFrame) {
+    unsafe {
+        core::ptr::write_bytes(
+            phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+            0,
+            frame.size() as usize,
+        );
+    }
+}
+
+/// Allocate a frame from the global allocator without touching the frames reserved for the kernel.
+fn allocate_unreserved() -> Option<PhysFrame> {
+    with_global(|allocator| {
+        if allocator.stats().free_frames > KERNEL_RESERVED_FRAMES {
+            allocator.allocate_frame()
+        } else {
+            None
+        }
+    })
+}
+
+/// Allocate a frame for the kernel. The reserved frames and the ones in the zero pool are all
+/// available, since the kernel cannot recover from out of memory as the users do.
+fn allocate_kernel() -> Option<PhysFrame> {
+    with_global(|allocator| allocator.allocate_frame())
+        .or_else(|| with_pool(|pool| pool.pop_zeroed().or_else(|| pool.pop_dirty())))
+}
+
+/// Allocate a frame filled with zeros for the user. Take one from the pool zeroed in the background
+/// if possible, and zero it here otherwise.
+fn allocate_zeroed() -> Option<PhysFrame> {
+    if let Some(frame) = with_pool(|pool| pool.pop_zeroed()) {
+        return Some(frame);
+    }
+
+    let frame = allocate_unreserved().or_else(|| with_pool(|pool| pool.pop_dirty()))?;
+    zero_frame(frame);
+    Some(frame)
+}
+
+/// Release frames that may contain data of users. They will be zeroed before being reused.
+///
+/// # Safety
+/// The frames must be unused.
+unsafe fn deallocate_dirty(frames: impl IntoIterator<Item = PhysFrame>) {
+    with_pool(|pool| {
+        for frame in frames {
+            pool.push_dirty(frame);
+        }
+    })
+}
+
+/// Zero at most `batch` frames released by users, or newly allocated ones if the pool of zeroed
+/// frames is not full. Returns the number of frames zeroed.
+pub fn zero_frames(batch: usize) -> usize {
+    for zeroed in 0..batch {
+        let frame = match with_pool(|pool| pool.pop_dirty()) {
+            Some(frame) => frame,
+            None if with_pool(|pool| pool.wants_zeroed()) => match allocate_unreserved() {
+                Some(frame) => frame,
+                None => return zeroed,
+            },
+            None => return zeroed,
+        };
+
+        zero_frame(frame);
+        if let Err(frame) = with_pool(|pool| unsafe { pool.push_zeroed(frame) }) {
+            with_global(|allocator| unsafe { allocator.deallocate_frame(frame) });
+        }
+    }
+
+    batch
+}
+
+/// Allocate at least `frames` physically contiguous frames, for DMA buffers for example. The
+/// number of frames is rounded up to a power of two, and the range is aligned to its size. Returns
+/// `None` if the rounded number is larger than the largest block.
+#[allow(dead_code)]
+fn allocate_contiguous(frames: u64) -> Option<PhysFrameRange> {
+    if frames > 1 << (MAX_ORDER - 1) {
+        return None;
+    }
+    let order = order_of(frames);
+    let start = with_global(|allocator| allocator.allocate_contiguous(order))?;
+    Some(PhysFrameRange {
+        start,
+        end: start + (1 << order),
+    })
+}
+
+/// Deallocate the frames returned by [`allocate_contiguous`].
+///
+/// # Safety
+/// The range must be exactly the one returned by [`allocate_contiguous`], and must not be used
+/// anymore.
+#[allow(dead_code)]
+unsafe fn deallocate_contiguous(range: PhysFrameRange) {
+    let order = order_of(range.end - range.start);
+    with_global(|allocator| allocator.deallocate_contiguous(range.start, order))
+}
+
+/// Get the statistics of physical frames. The frames in the zero pool are counted as free.
+pub fn stats() -> FrameStats {
+    let pooled_frames = with_pool(|pool| pool.frames());
+    let mut stats = with_global(|allocator| allocator.stats());
+    stats.free_frames += pooled_frames;
+    stats
 }
@@ -0,0 +1,267 @@
+// https://en.wikipedia.org/wiki/Buddy_memory_allocation
+
+use core::mem::size_of;
+
+use x86_64::structures::paging::frame::PhysFrameRange;
+use x86_64::structures::paging::{PageSize, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// The number of orders. A block of order `n` consists of `2^n` contiguous frames, so the largest
+/// block is 4 MiB.
+pub const MAX_ORDER: usize = 11;
+
+/// The node of the doubly linked free list, stored in the first bytes of a free block itself.
+struct FreeBlock {
+    prev: Option<PhysFrame>,
+
+    next: Option<PhysFrame>,
+}
+
+/// A binary buddy allocator for physical frames.
+///
+/// Free blocks of each order are linked into intrusive free lists, and a bitmap per order records
+/// whether a block is free, so that we can find and merge the buddy in constant time on
+/// deallocation. The bitmaps are carved from the managed memory itself, so the allocator does not
+/// depend on the kernel heap.
+pub struct BuddyAllocator {
+    phys_offset: VirtAddr,
+
+    /// The first frame number covered by the bitmaps, aligned to the largest block.
+    base: u64,
+
+    /// The number of frames covered by the bitmaps.
+    frames: u64,
+
+    free_lists: [Option<PhysFrame>; MAX_ORDER],
+
+    bitmap: &'static mut [u64],
+
+    /// The offset in words of each order in `bitmap`.
+    bitmap_offsets: [usize; MAX_ORDER],
+
+    total_frames: u64,
+
+    free_frames: u64,
+}
+
+impl BuddyAllocator {
+    /// Create a buddy allocator managing the frames yielded by `ranges`.
+    ///
+    /// # Safety
+    /// The given frames must be unused and accessible at `phys_offset`. `ranges` must yield the
+    /// same ranges every time it's called.
+    pub unsafe fn new<I>(phys_offset: VirtAddr, ranges: impl Fn() -> I) -> Self
+    where
+        I: Iterator<Item = PhysFrameRange>,
+    {
+        const MAX_BLOCK_FRAMES: u64 = 1 << (MAX_ORDER - 1);
+
+        let start = ranges()
+            .map(|r| frame_number(r.start))
+            .min()
+            .expect("no usable memory");
+        let end = ranges()
+            .map(|r| frame_number(r.end))
+            .max()
+            .expect("no usable memory");
+
+        let base = start / MAX_BLOCK_FRAMES * MAX_BLOCK_FRAMES;
+        let frames = (end - base).div_ceil(MAX_BLOCK_FRAMES) * MAX_BLOCK_FRAMES;
+
+        let mut bitmap_offsets = [0; MAX_ORDER];
+        let mut bitmap_words = 0;
+        for (order, offset) in bitmap_offsets.iter_mut().enumerate() {
+            *offset = bitmap_words;
+            bitmap_words += (frames >> order).div_ceil(64) as usize;
+        }
+
+        // Carve the bitmaps from the first range that is large enough.
+        let bitmap_frames = (bitmap_words * size_of::<u64>()).div_ceil(Size4KiB::SIZE as usize);
+        let bitmap_range = ranges()
+            .find(|&r| range_len(r) >= bitmap_frames as u64)
+            .expect("no enough memory for frame bitmaps");
+        let bitmap_range = PhysFrame::range(
+            bitmap_range.start,
+            bitmap_range.start + bitmap_frames as u64,
+        );
+
+        let bitmap = core::slice::from_raw_parts_mut(
+            (phys_offset + bitmap_range.start.start_address().as_u64()).as_mut_ptr(),
+            bitmap_words,
+        );
+        bitmap.fill(0);
+
+        let mut allocator = Self {
+            phys_offset,
+            base,
+            frames,
+            free_lists: [None; MAX_ORDER],
+            bitmap,
+            bitmap_offsets,
+            total_frames: 0,
+            free_frames: 0,
+        };
+
+        for range in ranges() {
+            if range.start == bitmap_range.start {
+                allocator.add_range(PhysFrame::range(bitmap_range.end, range.end));
+            } else {
+                allocator.add_range(range);
+            }
+        }
+
+        allocator
+    }
+
+    /// Add the frames in `range` to the free lists, splitting it into blocks as large as possible.
+    unsafe fn add_range(&mut self, range: PhysFrameRange) {
+        let mut number = frame_number(range.start);
+        let end = frame_number(range.end);
+
+        while number < end {
+            let order = [
+                number.trailing_zeros() as usize,
+                (u64::BITS - 1 - (end - number).leading_zeros()) as usize,
+                MAX_ORDER - 1,
+            ]
+            .into_iter()
+            .min()
+            .unwrap();
+
+            self.total_frames += 1 << order;
+            self.deallocate(frame_from_number(number), order);
+
+            number += 1 << order;
+        }
+    }
+
+    /// Allocate a block of `2^order` contiguous frames, aligned to its size.
+    pub fn allocate(&mut self, order: usize) -> Option<PhysFrame> {
+        assert!(order < MAX_ORDER, "order {order} too large");
+
+        let found_order = (order..MAX_ORDER).find(|&o| self.free_lists[o].is_some())?;
+        let block = unsafe { self.pop(found_order) }.unwrap();
+
+        // Split the block and give back the upper halves.
+        for o in (order..found_order).rev() {
+            unsafe { self.push(block + (1 << o), o) };
+        }
+
+        self.free_frames -= 1 << order;
+        Some(block)
+    }
+
+    /// Deallocate a block of `2^order` frames, merging it with its buddies as much as possible.
+    ///
+    /// # Safety
+    /// The block must be allocated from this allocator with the same order.
+    pub unsafe fn deallocate(&mut self, block: PhysFrame, order: usize) {
+        assert!(order < MAX_ORDER, "order {order} too large");
+        self.free_frames += 1 << order;
+
+        let mut number = frame_number(block);
+        let mut order = order;
+
+        while order < MAX_ORDER - 1 {
+            let buddy_number = number ^ (1 << order);
+            if !self.contains(buddy_number) || !self.is_free(buddy_number, order) {
+                break;
+            }
+
+            self.remove(frame_from_number(buddy_number), order);
+            number &= !(1 << order);
+            order += 1;
+        }
+
+        self.push(frame_from_number(number), order);
+    }
+
+    pub fn total_frames(&self) -> u64 {
+        self.total_frames
+    }
+
+    pub fn free_frames(&self) -> u64 {
+        self.free_frames
+    }
+}
+
+impl BuddyAllocator {
+    fn contains(&self, number: u64) -> bool {
+        number >= self.base && number < self.base + self.frames
+    }
+
+    fn bit_position(&self, number: u64, order: usize) -> (usize, u64) {
+        let index = ((number - self.base) >> order) as usize;
+        let word = self.bitmap_offsets[order] + index / 64;
+        (word, 1 << (index % 64))
+    }
+
+    fn is_free(&self, number: u64, order: usize) -> bool {
+        let (word, mask) = self.bit_position(number, order);
+        self.bitmap[word] & mask != 0
+    }
+
+    fn set_free(&mut self, number: u64, order: usize, free: bool) {
+        let (word, mask) = self.bit_position(number, order);
+        if free {
+            self.bitmap[word] |= mask;
+        } else {
+            self.bitmap[word] &= !mask;
+        }
+    }
+
+    fn node(&self, block: PhysFrame) -> *mut FreeBlock {
+        (self.phys_offset + block.start_address().as_u64()).as_mut_ptr()
+    }
+
+    unsafe fn push(&mut self, block: PhysFrame, order: usize) {
+        let head = self.free_lists[order];
+        if let Some(head) = head {
+            (*self.node(head)).prev = Some(block);
+        }
+        self.node(block).write(FreeBlock {
+            prev: None,
+            next: head,
+        });
+
+        self.free_lists[order] = Some(block);
+        self.set_free(frame_number(block), order, true);
+    }
+
+    unsafe fn remove(&mut self, block: PhysFrame, order: usize) {
+        let FreeBlock { prev, next } = self.node(block).read();
+
+        match prev {
+            Some(prev) => (*self.node(prev)).next = next,
+            None => self.free_lists[order] = next,
+        }
+        if let Some(next) = next {
+            (*self.node(next)).prev = prev;
+        }
+
+        self.set_free(frame_number(block), order, false);
+    }
+
+    unsafe fn pop(&mut self, order: usize) -> Option<PhysFrame> {
+        let block = self.free_lists[order]?;
+        self.remove(block, order);
+        Some(block)
+    }
+}
+
+fn frame_number(frame: PhysFrame) -> u64 {
+    frame.start_address().as_u64() / Size4KiB::SIZE
+}
+
+fn range_len(range: PhysFrameRange) -> u64 {
+    frame_number(range.end) - frame_number(range.start)
+}
+
+fn frame_from_number(number: u64) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(number * Size4KiB::SIZE))
+}
+
+/// The smallest order of block that holds `frames` frames.
+pub fn order_of(frames: u64) -> usize {
+    frames.next_power_of_two().trailing_zeros() as usize
+}
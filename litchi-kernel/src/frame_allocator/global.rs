@@ -1,59 +1,113 @@
-use alloc::collections::VecDeque;
-
 use litchi_common::BootInfo;
-use spin::{Mutex, Once};
-use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use size_format::SizeFormatterBinary;
+use spin::Once;
+use x86_64::structures::paging::frame::PhysFrameRange;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB,
+};
 use x86_64::PhysAddr;
 
-use crate::heap;
+use super::buddy::BuddyAllocator;
+use crate::sync::IrqSafeMutex;
 
-type UsableFrameIterator = impl Iterator<Item = PhysFrame>;
+/// The end of the low memory below which frames are never handed out. The application processors
+/// start in real mode, so their trampoline must live here.
+pub const LOW_MEMORY_END: u64 = 0x10000;
 
-fn extract_iter_from_boot_info(boot_info: &'static BootInfo) -> UsableFrameIterator {
-    boot_info.usable_memory_ranges().flat_map(|desc| {
+fn usable_frame_ranges(boot_info: &'static BootInfo) -> impl Iterator<Item = PhysFrameRange> {
+    boot_info.usable_memory_ranges().map(|desc| {
         let start = PhysFrame::from_start_address(PhysAddr::new(desc.phys_start))
             .expect("phys frame not aligned");
         let end = start + desc.page_count;
 
-        PhysFrame::<Size4KiB>::range(start, end)
+        // Never hand out the low memory, which is reserved for the AP trampoline.
+        let start = start.max(PhysFrame::containing_address(PhysAddr::new(LOW_MEMORY_END)));
+        PhysFrame::range(start, end.max(start))
     })
 }
 
-pub(super) struct GlobalFrameAllocator {
-    iter: UsableFrameIterator,
+#[derive(Clone, Copy)]
+pub struct FrameStats {
+    pub total_frames: u64,
 
-    deallocated: Option<VecDeque<PhysFrame>>,
+    pub free_frames: u64,
+}
+
+impl FrameStats {
+    pub fn used_frames(&self) -> u64 {
+        self.total_frames - self.free_frames
+    }
+}
+
+impl core::fmt::Debug for FrameStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let bytes = |frames: u64| SizeFormatterBinary::new(frames * Size4KiB::SIZE);
+
+        f.debug_struct("FrameStats")
+            .field("total", &format_args!("{:.10}B", bytes(self.total_frames)))
+            .field("free", &format_args!("{:.10}B", bytes(self.free_frames)))
+            .field("used", &format_args!("{:.10}B", bytes(self.used_frames())))
+            .finish()
+    }
+}
+
+pub(super) struct GlobalFrameAllocator {
+    buddy: BuddyAllocator,
 }
 
 impl GlobalFrameAllocator {
     pub(super) fn new(boot_info: &'static BootInfo) -> Self {
-        Self {
-            iter: extract_iter_from_boot_info(boot_info),
-            deallocated: None,
+        let buddy = unsafe {
+            BuddyAllocator::new(boot_info.phys_offset, || usable_frame_ranges(boot_info))
+        };
+
+        Self { buddy }
+    }
+
+    /// Allocate `2^order` physically contiguous frames, aligned to its size.
+    pub(super) fn allocate_contiguous(&mut self, order: usize) -> Option<PhysFrame> {
+        self.buddy.allocate(order)
+    }
+
+    /// Deallocate frames allocated by [`Self::allocate_contiguous`].
+    pub(super) unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, order: usize) {
+        self.buddy.deallocate(start, order)
+    }
+
+    pub(super) fn stats(&self) -> FrameStats {
+        FrameStats {
+            total_frames: self.buddy.total_frames(),
+            free_frames: self.buddy.free_frames(),
         }
     }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        self.iter
-            .next()
-            .or_else(|| self.deallocated.as_mut().and_then(|de| de.pop_front()))
+        self.buddy.allocate(0)
     }
 }
 
 impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
     unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
-        let de = self.deallocated.get_or_insert_with(|| {
-            assert!(
-                heap::initialized(),
-                "cannot deallocate frame when kernel heap allocator not initialized"
-            );
-            VecDeque::new()
-        });
+        self.buddy.deallocate(frame, 0)
+    }
+}
+
+const HUGE_PAGE_ORDER: usize = (Size2MiB::SIZE / Size4KiB::SIZE).trailing_zeros() as usize;
+
+unsafe impl FrameAllocator<Size2MiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let start = self.buddy.allocate(HUGE_PAGE_ORDER)?;
+        Some(PhysFrame::from_start_address(start.start_address()).unwrap())
+    }
+}
 
-        de.push_back(frame);
+impl FrameDeallocator<Size2MiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let start = PhysFrame::containing_address(frame.start_address());
+        self.buddy.deallocate(start, HUGE_PAGE_ORDER)
     }
 }
 
-pub(super) static FRAME_ALLOCATOR: Once<Mutex<GlobalFrameAllocator>> = Once::new();
+pub(super) static FRAME_ALLOCATOR: Once<IrqSafeMutex<GlobalFrameAllocator>> = Once::new();
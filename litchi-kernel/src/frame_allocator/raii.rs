@@ -1,64 +1,92 @@
-use alloc::vec::Vec;
+use alloc::collections::BTreeSet;
 
 use log::info;
-use x86_64::instructions;
 use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
 
-use super::FRAME_ALLOCATOR;
+use super::{allocate_kernel, allocate_zeroed, deallocate_dirty, zero_frame};
 
+/// Frames allocated for the user are always zeroed, and go through the zero pool when they're
+/// deallocated, so that no data leaks from one task to another.
 pub struct RaiiFrameAllocator {
-    allocated: Option<Vec<PhysFrame>>,
+    allocated: Option<BTreeSet<PhysFrame>>,
 }
 
 impl RaiiFrameAllocator {
     /// For the user program.
     pub fn new_traced() -> Self {
         Self {
-            allocated: Some(Vec::new()),
+            allocated: Some(BTreeSet::new()),
         }
     }
 
     /// For the kernel. When creating this instance, the heap and `alloc` of kernel may not be
-    /// initialized so we must not call `Vec::new()` here.
+    /// initialized so we must not call `BTreeSet::new()` here.
     pub fn new_untraced() -> Self {
         Self { allocated: None }
     }
+
+    /// The number of frames allocated by this instance and not deallocated yet. Always zero for the
+    /// kernel, which is not traced.
+    pub fn allocated_frames(&self) -> u64 {
+        self.allocated
+            .as_ref()
+            .map_or(0, |allocated| allocated.len() as u64)
+    }
+
+    /// Allocate a frame filled with zeros, no matter whether it's for the user or the kernel.
+    pub fn allocate_zeroed_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let frame = self.allocate_frame()?;
+        if self.allocated.is_none() {
+            zero_frame(frame);
+        }
+        Some(frame)
+    }
+
+    /// Deallocate a frame allocated by this instance but never used, for example, when there's no
+    /// enough memory to map it. Unlike `deallocate_frame`, the frames of the kernel are returned
+    /// too.
+    ///
+    /// # Safety
+    /// The frame must be allocated by this instance and not be mapped.
+    pub unsafe fn deallocate_unused(&mut self, frame: PhysFrame<Size4KiB>) {
+        match self.allocated.as_mut() {
+            Some(_) => self.deallocate_frame(frame),
+            None => deallocate_dirty([frame]),
+        }
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for RaiiFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = instructions::interrupts::without_interrupts(|| {
-            FRAME_ALLOCATOR
-                .get()
-                .expect("frame allocator not initialized")
-                .lock()
-                .allocate_frame()
-        });
+        match self.allocated.as_mut() {
+            Some(allocated) => {
+                let frame = allocate_zeroed()?;
+                allocated.insert(frame);
+                Some(frame)
+            }
+            None => allocate_kernel(),
+        }
+    }
+}
 
+impl FrameDeallocator<Size4KiB> for RaiiFrameAllocator {
+    /// Return the frame to the global allocator if it's allocated by this instance. Otherwise, the
+    /// frame is not owned by us, like the ones of the kernel image, so it's simply ignored.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
         if let Some(allocated) = self.allocated.as_mut() {
-            if let Some(frame) = frame {
-                allocated.push(frame);
+            if allocated.remove(&frame) {
+                deallocate_dirty([frame]);
             }
         }
-        frame
     }
 }
 
 impl Drop for RaiiFrameAllocator {
     fn drop(&mut self) {
-        instructions::interrupts::without_interrupts(|| {
-            let mut inner = FRAME_ALLOCATOR
-                .get()
-                .expect("frame allocator not initialized")
-                .lock();
+        if let Some(allocated) = self.allocated.take() {
+            info!("will deallocate {} frames", allocated.len());
 
-            if let Some(allocated) = self.allocated.take() {
-                info!("will deallocate {} frames", allocated.len());
-
-                for frame in allocated {
-                    unsafe { inner.deallocate_frame(frame) };
-                }
-            }
-        });
+            unsafe { deallocate_dirty(allocated) };
+        }
     }
 }
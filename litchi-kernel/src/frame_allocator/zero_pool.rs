@@ -0,0 +1,105 @@
+use x86_64::structures::paging::PhysFrame;
+use x86_64::PhysAddr;
+
+use crate::memory::phys_to_virt;
+use crate::sync::IrqSafeMutex;
+
+/// The number of zeroed frames to keep in the pool. Zeroed frames beyond this are returned to the
+/// global allocator.
+const POOL_TARGET_FRAMES: u64 = 512;
+
+/// An intrusive stack of frames, each storing the address of the next one in its first word. The
+/// frame at physical address zero is never allocated, so zero marks the end.
+struct FrameStack {
+    head: Option<PhysFrame>,
+
+    len: u64,
+}
+
+impl FrameStack {
+    const fn new() -> Self {
+        Self { head: None, len: 0 }
+    }
+
+    fn link(frame: PhysFrame) -> *mut u64 {
+        phys_to_virt(frame.start_address()).as_mut_ptr()
+    }
+
+    unsafe fn push(&mut self, frame: PhysFrame) {
+        let next = self.head.map_or(0, |head| head.start_address().as_u64());
+        *Self::link(frame) = next;
+
+        self.head = Some(frame);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<PhysFrame> {
+        let frame = self.head?;
+        let next = unsafe {
+            let link = Self::link(frame);
+            // Clear the link so that a zeroed frame stays zeroed.
+            core::mem::replace(&mut *link, 0)
+        };
+
+        self.head = (next != 0).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+        self.len -= 1;
+        Some(frame)
+    }
+}
+
+/// Frames released by user tasks, which are zeroed by a kernel task in the background before
+/// they're handed out again.
+pub(super) struct ZeroPool {
+    /// Frames that are known to be filled with zeros.
+    zeroed: FrameStack,
+
+    /// Frames that may contain data of a dead task.
+    dirty: FrameStack,
+}
+
+impl ZeroPool {
+    const fn new() -> Self {
+        Self {
+            zeroed: FrameStack::new(),
+            dirty: FrameStack::new(),
+        }
+    }
+
+    pub(super) fn pop_zeroed(&mut self) -> Option<PhysFrame> {
+        self.zeroed.pop()
+    }
+
+    pub(super) fn pop_dirty(&mut self) -> Option<PhysFrame> {
+        self.dirty.pop()
+    }
+
+    /// # Safety
+    /// The frame must be unused and not in the pool.
+    pub(super) unsafe fn push_dirty(&mut self, frame: PhysFrame) {
+        self.dirty.push(frame)
+    }
+
+    /// Put a zeroed frame into the pool, or give it back if the pool is full.
+    ///
+    /// # Safety
+    /// The frame must be unused, filled with zeros and not in the pool.
+    pub(super) unsafe fn push_zeroed(&mut self, frame: PhysFrame) -> Result<(), PhysFrame> {
+        if self.wants_zeroed() {
+            self.zeroed.push(frame);
+            Ok(())
+        } else {
+            Err(frame)
+        }
+    }
+
+    pub(super) fn wants_zeroed(&self) -> bool {
+        self.zeroed.len < POOL_TARGET_FRAMES
+    }
+
+    pub(super) fn frames(&self) -> u64 {
+        self.zeroed.len + self.dirty.len
+    }
+}
+
+pub(super) static ZERO_POOL: IrqSafeMutex<ZeroPool> =
+    IrqSafeMutex::new("zero pool", ZeroPool::new());
@@ -1,9 +1,14 @@
+use alloc::boxed::Box;
+use alloc::format;
+
 use lazy_static::lazy_static;
-use log::{debug, info};
+use log::info;
 use x86_64::registers::segmentation::{Segment, SegmentSelector};
 use x86_64::structures::gdt::GlobalDescriptorTable;
 use x86_64::structures::tss::TaskStateSegment;
-use x86_64::{instructions, registers, VirtAddr};
+use x86_64::{instructions, registers};
+
+use crate::stack;
 
 #[repr(u16)]
 pub enum IstIndex {
@@ -11,44 +16,25 @@ pub enum IstIndex {
     UserInterrupt,
 }
 
-const INTERRUPT_STACK_SIZE: usize = 4096 * 5;
-
-// Note: avoid declaring as &[u8] here.
-#[repr(C, align(4096))]
-struct TrapStack([u8; INTERRUPT_STACK_SIZE]);
-
-impl TrapStack {
-    const fn new() -> Self {
-        Self([0; INTERRUPT_STACK_SIZE])
-    }
-}
+const INTERRUPT_STACK_PAGES: u64 = 5;
 
 lazy_static! {
-    static ref KERNEL_TSS: TaskStateSegment = new_kernel_tss();
+    static ref KERNEL_TSS: TaskStateSegment = new_kernel_tss(0);
 }
 
-fn new_kernel_tss() -> TaskStateSegment {
+/// Create the TSS for the given processor, with its own interrupt stacks.
+fn new_kernel_tss(cpu_id: usize) -> TaskStateSegment {
+    let stack_name = |name: &str| -> &'static str {
+        Box::leak(format!("{} on cpu {}", name, cpu_id).into_boxed_str())
+    };
+
     let mut tss = TaskStateSegment::new();
 
-    tss.interrupt_stack_table[IstIndex::DoubleFault as usize] = {
-        static DOUBLE_FAULT_STACK: TrapStack = TrapStack::new();
-        let stack_top = DOUBLE_FAULT_STACK.0.as_ptr_range().end;
-        debug!(
-            "stack for double fault: {:?}",
-            DOUBLE_FAULT_STACK.0.as_ptr_range()
-        );
-        VirtAddr::from_ptr(stack_top)
-    };
+    tss.interrupt_stack_table[IstIndex::DoubleFault as usize] =
+        stack::allocate(stack_name("double fault"), INTERRUPT_STACK_PAGES);
 
-    tss.interrupt_stack_table[IstIndex::UserInterrupt as usize] = {
-        static USER_INTERRUPT_STACK: TrapStack = TrapStack::new();
-        let stack_top = USER_INTERRUPT_STACK.0.as_ptr_range().end;
-        debug!(
-            "stack for user interrupt: {:?}",
-            USER_INTERRUPT_STACK.0.as_ptr_range()
-        );
-        VirtAddr::from_ptr(stack_top)
-    };
+    tss.interrupt_stack_table[IstIndex::UserInterrupt as usize] =
+        stack::allocate(stack_name("user interrupt"), INTERRUPT_STACK_PAGES);
 
     // TODO: privilege stack table
     tss
@@ -65,10 +51,12 @@ pub struct GlobalDescriptorTableWrapper {
 }
 
 lazy_static! {
-    pub static ref GDT: GlobalDescriptorTableWrapper = new_gdt();
+    /// The GDT of the bootstrap processor. The others have their own copies with the same
+    /// selectors, so the selectors here can be used everywhere.
+    pub static ref GDT: GlobalDescriptorTableWrapper = new_gdt(&KERNEL_TSS);
 }
 
-fn new_gdt() -> GlobalDescriptorTableWrapper {
+fn new_gdt(tss: &'static TaskStateSegment) -> GlobalDescriptorTableWrapper {
     use x86_64::structures::gdt::Descriptor;
 
     let mut gdt = GlobalDescriptorTable::new();
@@ -76,7 +64,7 @@ fn new_gdt() -> GlobalDescriptorTableWrapper {
     let kernel_code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
     let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
     let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
-    let kernel_tss_selector = gdt.add_entry(Descriptor::tss_segment(&KERNEL_TSS));
+    let kernel_tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
 
     GlobalDescriptorTableWrapper {
         gdt,
@@ -87,14 +75,33 @@ fn new_gdt() -> GlobalDescriptorTableWrapper {
     }
 }
 
-pub fn init() {
-    GDT.gdt.load();
+fn load(gdt: &'static GlobalDescriptorTableWrapper) {
+    gdt.gdt.load();
 
     unsafe {
-        registers::segmentation::CS::set_reg(GDT.kernel_code_selector);
+        registers::segmentation::CS::set_reg(gdt.kernel_code_selector);
         registers::segmentation::SS::set_reg(SegmentSelector(0)); // important
-        instructions::tables::load_tss(GDT.kernel_tss_selector);
+        instructions::tables::load_tss(gdt.kernel_tss_selector);
     }
+}
+
+pub fn init() {
+    load(&GDT);
 
     info!("loaded gdt at {:p} and kernel tss", &GDT.gdt)
 }
+
+/// Load a new GDT and TSS for the application processor.
+pub fn init_ap(cpu_id: usize) {
+    let tss = Box::leak(Box::new(new_kernel_tss(cpu_id)));
+    let gdt = Box::leak(Box::new(new_gdt(tss)));
+    assert_eq!(gdt.user_code_selector, GDT.user_code_selector);
+    assert_eq!(gdt.user_data_selector, GDT.user_data_selector);
+
+    load(gdt);
+
+    info!(
+        "loaded gdt at {:p} and kernel tss for cpu {}",
+        &gdt.gdt, cpu_id
+    )
+}
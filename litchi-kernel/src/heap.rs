@@ -1,60 +1,157 @@
+mod growable;
+mod slab;
+
 use alloc::vec::Vec;
+use core::alloc::{GlobalAlloc, Layout};
 use core::any::type_name_of_val;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::ptr::{null_mut, NonNull};
 
-use linked_list_allocator::LockedHeap;
 use log::info;
 use size_format::SizeFormatterBinary;
-use x86_64::structures::paging::{Page, PageSize, PageTableFlags, Size4KiB};
 use x86_64::VirtAddr;
 
-use crate::memory::KERNEL_PAGE_TABLE;
+use self::growable::GrowableHeap;
+use self::slab::{SlabCache, SlabStats, SIZE_CLASSES};
+use crate::sync::IrqSafeMutex;
 
-#[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+const HEAP_BASE: VirtAddr = VirtAddr::new_truncate(0xffff_c000_0000_0000);
+const HEAP_INITIAL_SIZE: u64 = 4 * 1024 * 1024; // 4 MiB
+const HEAP_LIMIT: VirtAddr = VirtAddr::new_truncate(0xffff_c100_0000_0000); // 1 TiB
 
-static INITIALIZED: AtomicBool = AtomicBool::new(false);
+struct Inner {
+    slabs: [SlabCache; SIZE_CLASSES.len()],
 
-pub fn init() {
-    const HEAP_BASE: VirtAddr = VirtAddr::new_truncate(0x4444_0000_0000);
-    const HEAP_PAGES: usize = 8192; // 32 MiB
-    const HEAP_SIZE: usize = HEAP_PAGES * (Size4KiB::SIZE as usize);
+    heap: GrowableHeap,
+}
 
-    let heap_base_page = Page::from_start_address(HEAP_BASE).unwrap();
-    for i in 0..HEAP_PAGES {
-        let page = heap_base_page + i as u64;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+/// The kernel allocator. Small objects are served by the slab caches of size classes, while large
+/// ones go to the growable heap directly.
+pub struct KernelAllocator {
+    inner: IrqSafeMutex<Inner>,
+}
 
-        unsafe {
-            KERNEL_PAGE_TABLE.allocate_and_map_to(page, flags).unwrap();
+impl KernelAllocator {
+    const fn new() -> Self {
+        Self {
+            inner: IrqSafeMutex::new(
+                "kernel heap",
+                Inner {
+                    slabs: [
+                        SlabCache::new(SIZE_CLASSES[0]),
+                        SlabCache::new(SIZE_CLASSES[1]),
+                        SlabCache::new(SIZE_CLASSES[2]),
+                        SlabCache::new(SIZE_CLASSES[3]),
+                        SlabCache::new(SIZE_CLASSES[4]),
+                        SlabCache::new(SIZE_CLASSES[5]),
+                        SlabCache::new(SIZE_CLASSES[6]),
+                        SlabCache::new(SIZE_CLASSES[7]),
+                        SlabCache::new(SIZE_CLASSES[8]),
+                    ],
+                    heap: GrowableHeap::empty(),
+                },
+            ),
         }
     }
 
+    fn with_inner<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Inner) -> R,
+    {
+        f(&mut *self.inner.lock())
+    }
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.with_inner(|inner| {
+            let ptr = match SlabCache::class_of(layout) {
+                Some(class) => inner.slabs[class].allocate(&mut inner.heap),
+                None => inner.heap.allocate(layout),
+            };
+            ptr.map_or(null_mut(), NonNull::as_ptr)
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let ptr = NonNull::new_unchecked(ptr);
+
+        self.with_inner(|inner| match SlabCache::class_of(layout) {
+            Some(class) => inner.slabs[class].deallocate(ptr),
+            None => inner.heap.deallocate(ptr, layout),
+        })
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator::new();
+
+pub struct HeapStats {
+    /// The size of memory mapped for the heap.
+    pub mapped: usize,
+
+    /// The size of memory allocated from the heap, including the slabs.
+    pub used: usize,
+
+    pub slabs: [SlabStats; SIZE_CLASSES.len()],
+}
+
+impl core::fmt::Debug for HeapStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("HeapStats")
+            .field(
+                "mapped",
+                &format_args!("{:.10}B", SizeFormatterBinary::new(self.mapped as u64)),
+            )
+            .field(
+                "used",
+                &format_args!("{:.10}B", SizeFormatterBinary::new(self.used as u64)),
+            )
+            .field("slabs", &self.slabs)
+            .finish()
+    }
+}
+
+/// Get the statistics of the kernel heap.
+pub fn stats() -> HeapStats {
+    ALLOCATOR.with_inner(|inner| {
+        let mut slabs = [SlabStats::default(); SIZE_CLASSES.len()];
+        for (stats, cache) in slabs.iter_mut().zip(inner.slabs.iter()) {
+            *stats = cache.stats();
+        }
+
+        HeapStats {
+            mapped: inner.heap.size(),
+            used: inner.heap.used(),
+            slabs,
+        }
+    })
+}
+
+pub fn init() {
+    ALLOCATOR
+        .with_inner(|inner| inner.heap.init(HEAP_BASE, HEAP_INITIAL_SIZE, HEAP_LIMIT));
+
     info!(
-        "allocated heap at {:?} of {}B",
+        "allocated heap at {:?} of {}B, growable up to {:?}",
         HEAP_BASE.as_ptr::<()>(),
-        SizeFormatterBinary::new(HEAP_SIZE as u64)
+        SizeFormatterBinary::new(HEAP_INITIAL_SIZE),
+        HEAP_LIMIT.as_ptr::<()>(),
     );
 
-    unsafe {
-        ALLOCATOR
-            .lock()
-            .init(HEAP_BASE.as_u64() as usize, HEAP_SIZE);
-    }
-    INITIALIZED.store(true, Ordering::SeqCst);
-
     let test_vec = (0u16..).take(4096).collect::<Vec<_>>();
     assert!(test_vec.as_ptr() >= HEAP_BASE.as_ptr());
     for (i, num) in test_vec.into_iter().enumerate() {
         assert_eq!(i as u16, num);
     }
 
+    // Allocate more than the initial size to test growing.
+    let large_vec = alloc::vec![0u8; HEAP_INITIAL_SIZE as usize * 2];
+    assert!(large_vec.iter().all(|&b| b == 0));
+    drop(large_vec);
+
     info!(
-        "allocator of `{}` initialized",
-        type_name_of_val(&ALLOCATOR)
+        "allocator of `{}` initialized: {:?}",
+        type_name_of_val(&ALLOCATOR),
+        stats()
     );
 }
-
-pub fn initialized() -> bool {
-    INITIALIZED.load(Ordering::SeqCst)
-}
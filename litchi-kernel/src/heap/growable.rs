@@ -0,0 +1,111 @@
+use core::alloc::Layout;
+use core::ptr::NonNull;
+
+use linked_list_allocator::Heap;
+use log::warn;
+use x86_64::structures::paging::{Page, PageSize, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::memory::KERNEL_PAGE_TABLE;
+
+/// A linked-list heap that maps more pages from the frame allocator on demand.
+pub struct GrowableHeap {
+    inner: Heap,
+
+    base: VirtAddr,
+
+    /// The upper bound of the virtual memory reserved for this heap.
+    limit: VirtAddr,
+}
+
+impl GrowableHeap {
+    /// The minimum size to grow each time, to amortize the cost of mapping pages.
+    const GROW_CHUNK: u64 = 1024 * 1024; // 1 MiB
+
+    pub const fn empty() -> Self {
+        Self {
+            inner: Heap::empty(),
+            base: VirtAddr::zero(),
+            limit: VirtAddr::zero(),
+        }
+    }
+
+    /// Map `initial_size` bytes at `base` and initialize the heap, which may grow up to `limit`.
+    pub fn init(&mut self, base: VirtAddr, initial_size: u64, limit: VirtAddr) {
+        assert!(base.is_aligned(Size4KiB::SIZE) && base < limit);
+
+        let mapped = Self::map(base, initial_size);
+        assert_eq!(mapped, initial_size, "no enough memory for the kernel heap");
+
+        unsafe { self.inner.init(base.as_u64() as usize, mapped as usize) };
+        self.base = base;
+        self.limit = limit;
+    }
+
+    pub fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        if let Ok(ptr) = self.inner.allocate_first_fit(layout) {
+            return Some(ptr);
+        }
+
+        // The free space may be fragmented, so always reserve enough for the alignment.
+        self.grow((layout.size() + layout.align()) as u64)?;
+        self.inner.allocate_first_fit(layout).ok()
+    }
+
+    /// # Safety
+    /// The `ptr` must be allocated from this heap with the same layout.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        self.inner.deallocate(ptr, layout)
+    }
+
+    /// The size of memory mapped for this heap.
+    pub fn size(&self) -> usize {
+        self.inner.size()
+    }
+
+    pub fn used(&self) -> usize {
+        self.inner.used()
+    }
+
+    fn grow(&mut self, at_least: u64) -> Option<()> {
+        let top = VirtAddr::new(self.inner.top() as u64);
+        let size = at_least
+            .max(Self::GROW_CHUNK)
+            .min(self.limit - top)
+            .next_multiple_of(Size4KiB::SIZE);
+
+        if size < at_least {
+            warn!("kernel heap reaches its limit {:?}", self.limit);
+            return None;
+        }
+
+        let mapped = Self::map(top, size);
+        if mapped == 0 {
+            return None;
+        }
+        unsafe { self.inner.extend(mapped as usize) };
+
+        if mapped < at_least {
+            None
+        } else {
+            Some(())
+        }
+    }
+
+    /// Map pages for `[base, base + size)` until out of memory, returns the bytes mapped.
+    fn map(base: VirtAddr, size: u64) -> u64 {
+        let base_page = Page::<Size4KiB>::from_start_address(base).unwrap();
+        let pages = size / Size4KiB::SIZE;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+        let mapped_pages = (0..pages)
+            .take_while(|&i| unsafe {
+                KERNEL_PAGE_TABLE
+                    .allocate_and_map_to(base_page + i, flags)
+                    .is_some()
+            })
+            .count() as u64;
+
+        mapped_pages * Size4KiB::SIZE
+    }
+}
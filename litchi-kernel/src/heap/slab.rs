@@ -0,0 +1,99 @@
+use core::alloc::Layout;
+use core::ptr::NonNull;
+
+use super::growable::GrowableHeap;
+
+/// The object sizes of slab caches. Allocations larger than the last class go to the heap directly.
+pub const SIZE_CLASSES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+const SLAB_SIZE: usize = 4096;
+
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SlabStats {
+    pub object_size: usize,
+
+    /// The number of slabs taken from the heap.
+    pub slabs: usize,
+
+    /// The number of objects currently allocated.
+    pub allocated: usize,
+}
+
+/// A cache of equal-sized objects carved from slabs of the heap. Free objects are linked into an
+/// intrusive list, so allocating and deallocating small objects are O(1) and never fragment the
+/// heap.
+pub struct SlabCache {
+    free: Option<NonNull<FreeObject>>,
+
+    stats: SlabStats,
+}
+
+// The free objects are only accessed with the lock of the allocator held.
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    pub const fn new(object_size: usize) -> Self {
+        Self {
+            free: None,
+            stats: SlabStats {
+                object_size,
+                slabs: 0,
+                allocated: 0,
+            },
+        }
+    }
+
+    /// Get the index of the slab cache serving `layout`, or `None` if it's too large.
+    pub fn class_of(layout: Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align());
+        SIZE_CLASSES.iter().position(|&class| class >= size)
+    }
+
+    pub fn allocate(&mut self, heap: &mut GrowableHeap) -> Option<NonNull<u8>> {
+        if self.free.is_none() {
+            self.refill(heap)?;
+        }
+
+        let object = self.free.unwrap();
+        self.free = unsafe { object.as_ref().next };
+        self.stats.allocated += 1;
+
+        Some(object.cast())
+    }
+
+    /// # Safety
+    /// The `ptr` must be allocated from this cache.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>) {
+        let object = ptr.cast::<FreeObject>();
+        object.as_ptr().write(FreeObject { next: self.free });
+        self.free = Some(object);
+        self.stats.allocated -= 1;
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.stats
+    }
+
+    /// Take a new slab from the heap and split it into free objects. Since the slab is aligned to
+    /// its size and the object size is a power of two, every object is aligned to its size.
+    fn refill(&mut self, heap: &mut GrowableHeap) -> Option<()> {
+        let layout = Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap();
+        let slab = heap.allocate(layout)?;
+        let object_size = self.stats.object_size;
+
+        for offset in (0..SLAB_SIZE).step_by(object_size).rev() {
+            unsafe {
+                let object = slab.as_ptr().add(offset).cast::<FreeObject>();
+                object.write(FreeObject { next: self.free });
+                self.free = Some(NonNull::new_unchecked(object));
+            }
+        }
+
+        self.stats.slabs += 1;
+        Some(())
+    }
+}
@@ -9,33 +9,27 @@ use x86_64::{instructions, set_general_handler, PrivilegeLevel};
 use crate::gdt::IstIndex;
 
 mod io_apic;
+pub mod irq;
 mod local_apic;
 mod macros;
 mod trap_handlers;
 mod user_handlers;
 
-pub const USER_INTERRUPT_OFFSET: u8 = 32;
-pub const IO_APIC_INTERRUPT_OFFSET: u8 = 128;
+pub use self::local_apic::with_local_apic;
 
-pub type RawUserInterrupt = u8;
+pub const USER_INTERRUPT_OFFSET: u8 = 32;
+/// The vectors from here are allocated to devices dynamically, see [`irq::register`].
+pub const DEVICE_INTERRUPT_OFFSET: u8 = 128;
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum UserInterrupt {
     ApicTimer = USER_INTERRUPT_OFFSET,
+    TlbShootdown = USER_INTERRUPT_OFFSET + 1,
     ApicError = USER_INTERRUPT_OFFSET + 19,
     ApicSpurious = USER_INTERRUPT_OFFSET + 31,
 
     Syscall = SYSCALL_INTERRUPT,
-
-    #[allow(unused)]
-    SerialIn = IO_APIC_INTERRUPT_OFFSET + 4,
-}
-
-impl From<RawUserInterrupt> for UserInterrupt {
-    fn from(raw: RawUserInterrupt) -> Self {
-        unsafe { core::mem::transmute(raw) }
-    }
 }
 
 impl UserInterrupt {
@@ -57,8 +51,61 @@ fn new_idt() -> InterruptDescriptorTable {
     // default unhandled
     set_general_handler!(&mut idt, unhandled);
 
-    // Breakpoint
-    idt.breakpoint.set_handler_fn(breakpoint);
+    // Exceptions, which only kill the task if raised by the user. There's no privilege stack, so
+    // they must switch to the interrupt stack as well.
+    let user_interrupt_stack = IstIndex::UserInterrupt as u16;
+    unsafe {
+        idt.divide_error
+            .set_handler_fn(divide_error)
+            .set_stack_index(user_interrupt_stack);
+        idt.debug
+            .set_handler_fn(debug)
+            .set_stack_index(user_interrupt_stack);
+        idt.breakpoint
+            .set_handler_fn(breakpoint)
+            .set_privilege_level(PrivilegeLevel::Ring3)
+            .set_stack_index(user_interrupt_stack);
+        idt.overflow
+            .set_handler_fn(overflow)
+            .set_privilege_level(PrivilegeLevel::Ring3)
+            .set_stack_index(user_interrupt_stack);
+        idt.bound_range_exceeded
+            .set_handler_fn(bound_range_exceeded)
+            .set_stack_index(user_interrupt_stack);
+        idt.invalid_opcode
+            .set_handler_fn(invalid_opcode)
+            .set_stack_index(user_interrupt_stack);
+        idt.device_not_available
+            .set_handler_fn(device_not_available)
+            .set_stack_index(user_interrupt_stack);
+        idt.invalid_tss
+            .set_handler_fn(invalid_tss)
+            .set_stack_index(user_interrupt_stack);
+        idt.segment_not_present
+            .set_handler_fn(segment_not_present)
+            .set_stack_index(user_interrupt_stack);
+        idt.stack_segment_fault
+            .set_handler_fn(stack_segment_fault)
+            .set_stack_index(user_interrupt_stack);
+        idt.general_protection_fault
+            .set_handler_fn(general_protection_fault)
+            .set_stack_index(user_interrupt_stack);
+        idt.x87_floating_point
+            .set_handler_fn(x87_floating_point)
+            .set_stack_index(user_interrupt_stack);
+        idt.alignment_check
+            .set_handler_fn(alignment_check)
+            .set_stack_index(user_interrupt_stack);
+        idt.simd_floating_point
+            .set_handler_fn(simd_floating_point)
+            .set_stack_index(user_interrupt_stack);
+        idt.virtualization
+            .set_handler_fn(virtualization)
+            .set_stack_index(user_interrupt_stack);
+        idt.security_exception
+            .set_handler_fn(security_exception)
+            .set_stack_index(user_interrupt_stack);
+    }
 
     // Double fault
     unsafe {
@@ -82,13 +129,22 @@ fn new_idt() -> InterruptDescriptorTable {
             .set_stack_index(IstIndex::UserInterrupt as u16);
     }
 
-    // Serial in
+    // TLB shootdown
     unsafe {
-        idt[UserInterrupt::SerialIn.as_index()]
-            .set_handler_fn(serial_in)
+        idt[UserInterrupt::TlbShootdown.as_index()]
+            .set_handler_fn(tlb_shootdown)
             .set_stack_index(IstIndex::UserInterrupt as u16);
     }
 
+    // Device interrupts
+    for (i, &handler) in irq::HANDLERS.iter().enumerate() {
+        unsafe {
+            idt[DEVICE_INTERRUPT_OFFSET as usize + i]
+                .set_handler_fn(handler)
+                .set_stack_index(IstIndex::UserInterrupt as u16);
+        }
+    }
+
     // Syscall
     unsafe {
         idt[UserInterrupt::Syscall.as_index()]
@@ -107,8 +163,15 @@ pub fn init() {
     local_apic::enable();
     info!("enabled local apic with timer");
 
-    io_apic::enable_irqs();
-    info!("initialized and enabled io apic");
+    io_apic::init();
+    info!("initialized io apic");
+}
+
+/// Initialize interrupts for the application processor. The IO APIC is shared and only routes
+/// device interrupts to the bootstrap processor.
+pub fn init_ap() {
+    IDT.load();
+    local_apic::enable();
 }
 
 #[allow(dead_code)]
@@ -1,16 +1,34 @@
 use alloc::vec::Vec;
 
-use acpi::platform::interrupt::{InterruptSourceOverride, IoApic as IoApicInfo};
+use acpi::platform::interrupt::{
+    InterruptSourceOverride, IoApic as IoApicInfo, Polarity, TriggerMode,
+};
 use log::info;
-use spin::Mutex;
 use x2apic::ioapic::{IoApic, IrqFlags, IrqMode, RedirectionTableEntry};
+use x86_64::PhysAddr;
 
-use super::{RawUserInterrupt, IO_APIC_INTERRUPT_OFFSET};
+use super::irq::IrqError;
+use super::DEVICE_INTERRUPT_OFFSET;
 use crate::acpi::ACPI;
-use crate::interrupt::UserInterrupt;
+use crate::memory::phys_to_virt;
+use crate::sync::IrqSafeMutex;
 
 lazy_static::lazy_static! {
-    static ref IO_APICS: Mutex<IoApics> = Mutex::new(IoApics::new_and_init());
+    static ref IO_APICS: IrqSafeMutex<IoApics> =
+        IrqSafeMutex::new("io apics", IoApics::new_and_init());
+}
+
+/// The number of redirection entries of each IO APIC.
+const IO_APIC_ENTRIES: u32 = 24;
+
+/// An interrupt line of the IO APICs, with its trigger mode and polarity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Line {
+    pub global_system_interrupt: u32,
+
+    level_triggered: bool,
+
+    active_low: bool,
 }
 
 struct IoApicWrapper {
@@ -22,7 +40,12 @@ struct IoApicWrapper {
 impl IoApicWrapper {
     fn handle(&self, global_system_interrupt: u32) -> bool {
         global_system_interrupt >= self.info.global_system_interrupt_base
-            && global_system_interrupt < (self.info.global_system_interrupt_base + 24)
+            && global_system_interrupt < (self.info.global_system_interrupt_base + IO_APIC_ENTRIES)
+    }
+
+    /// The index of the redirection entry for the interrupt.
+    fn entry_of(&self, global_system_interrupt: u32) -> u8 {
+        (global_system_interrupt - self.info.global_system_interrupt_base) as u8
     }
 }
 
@@ -40,8 +63,9 @@ impl IoApics {
             .io_apics
             .iter()
             .map(|io_apic_info| unsafe {
-                let mut io_apic = IoApic::new(io_apic_info.address as u64);
-                io_apic.init(IO_APIC_INTERRUPT_OFFSET);
+                let base = phys_to_virt(PhysAddr::new(io_apic_info.address as u64));
+                let mut io_apic = IoApic::new(base.as_u64());
+                io_apic.init(DEVICE_INTERRUPT_OFFSET);
 
                 IoApicWrapper {
                     inner: io_apic,
@@ -58,57 +82,106 @@ impl IoApics {
         }
     }
 
-    fn irq_to_interrupt(&self, irq: u8) -> Option<(usize, u32)> {
-        let overrided = self
-            .overrides
-            .iter()
-            .find(|o| o.isa_source == irq)
-            .map(|o| o.global_system_interrupt);
-
-        let global_system_interrupt = overrided.unwrap_or(irq as u32);
-
-        let io_apic_index = self
-            .io_apics
-            .iter()
-            .enumerate()
-            .find(|(_i, io_apic)| io_apic.handle(global_system_interrupt))?
-            .0;
-
-        Some((io_apic_index, global_system_interrupt))
+    fn find(&mut self, global_system_interrupt: u32) -> Option<&mut IoApicWrapper> {
+        self.io_apics
+            .iter_mut()
+            .find(|io_apic| io_apic.handle(global_system_interrupt))
     }
+}
 
-    fn enable_irq(&mut self, irq: u8) -> Option<RawUserInterrupt> {
-        let (io_apic_index, global_system_interrupt) = self.irq_to_interrupt(irq)?;
-
-        let user_interrupt = global_system_interrupt as u8 + IO_APIC_INTERRUPT_OFFSET;
+/// Apply the trigger mode and polarity of the override on top of the defaults of the bus.
+fn apply_override(line: &mut Line, source_override: &InterruptSourceOverride) {
+    match source_override.trigger_mode {
+        TriggerMode::SameAsBus => {}
+        TriggerMode::Edge => line.level_triggered = false,
+        TriggerMode::Level => line.level_triggered = true,
+    }
+    match source_override.polarity {
+        Polarity::SameAsBus => {}
+        Polarity::ActiveHigh => line.active_low = false,
+        Polarity::ActiveLow => line.active_low = true,
+    }
+}
 
-        let mut entry = RedirectionTableEntry::default();
-        entry.set_mode(IrqMode::Fixed);
-        entry.set_flags(IrqFlags::MASKED | IrqFlags::LEVEL_TRIGGERED);
-        entry.set_vector(user_interrupt);
-        entry.set_dest(0); // CPU 0
+/// Resolve the legacy ISA IRQ with the interrupt source overrides. ISA interrupts are
+/// edge-triggered and active high unless overridden.
+pub fn resolve_isa(irq: u8) -> Line {
+    let io_apics = IO_APICS.lock();
+    let o = io_apics.overrides.iter().find(|o| o.isa_source == irq);
+
+    let mut line = Line {
+        global_system_interrupt: o.map_or(irq as u32, |o| o.global_system_interrupt),
+        level_triggered: false,
+        active_low: false,
+    };
+    if let Some(o) = o {
+        apply_override(&mut line, o);
+    }
+    line
+}
 
-        let io_apic = &mut self.io_apics[io_apic_index].inner;
+/// Resolve the global system interrupt. It's level-triggered and active low as PCI, unless an ISA
+/// IRQ is overridden to it.
+pub fn resolve_gsi(global_system_interrupt: u32) -> Line {
+    let io_apics = IO_APICS.lock();
+    let o = io_apics
+        .overrides
+        .iter()
+        .find(|o| o.global_system_interrupt == global_system_interrupt);
+
+    let mut line = Line {
+        global_system_interrupt,
+        level_triggered: true,
+        active_low: true,
+    };
+    if let Some(o) = o {
+        line.level_triggered = false;
+        line.active_low = false;
+        apply_override(&mut line, o);
+    }
+    line
+}
 
-        unsafe {
-            io_apic.set_table_entry(global_system_interrupt as u8, entry);
-            io_apic.enable_irq(global_system_interrupt as u8);
-        }
+/// Route the line to the vector on the processor with the local APIC ID `dest`, and unmask it.
+pub fn route(line: Line, vector: u8, dest: u32) -> Result<(), IrqError> {
+    let mut io_apics = IO_APICS.lock();
+    let io_apic = io_apics
+        .find(line.global_system_interrupt)
+        .ok_or(IrqError::NoIoApic)?;
+    let index = io_apic.entry_of(line.global_system_interrupt);
+
+    let mut flags = IrqFlags::MASKED;
+    if line.level_triggered {
+        flags |= IrqFlags::LEVEL_TRIGGERED;
+    }
+    if line.active_low {
+        flags |= IrqFlags::LOW_ACTIVE;
+    }
 
-        info!(
-            "enabled irq #{} to user interrupt {} in io apic {}",
-            irq, user_interrupt, io_apic_index
-        );
+    let mut entry = RedirectionTableEntry::default();
+    entry.set_mode(IrqMode::Fixed);
+    entry.set_flags(flags);
+    entry.set_vector(vector);
+    entry.set_dest(dest as u8);
 
-        Some(user_interrupt)
+    unsafe {
+        io_apic.inner.set_table_entry(index, entry);
+        io_apic.inner.enable_irq(index);
     }
+
+    info!("routed {:?} to vector {} of apic {}", line, vector, dest);
+    Ok(())
 }
 
-pub fn enable_irqs() {
+/// Mask the line, so that it's no longer delivered.
+pub fn mask(line: Line) {
     let mut io_apics = IO_APICS.lock();
+    if let Some(io_apic) = io_apics.find(line.global_system_interrupt) {
+        let index = io_apic.entry_of(line.global_system_interrupt);
+        unsafe { io_apic.inner.disable_irq(index) };
+    }
+}
 
-    let serial_in_raw = io_apics
-        .enable_irq(4)
-        .expect("failed to enable irq for serial in");
-    assert_eq!(serial_in_raw, UserInterrupt::SerialIn as _);
+pub fn init() {
+    lazy_static::initialize(&IO_APICS);
 }
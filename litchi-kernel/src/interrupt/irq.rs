@@ -0,0 +1,194 @@
+use alloc::sync::Arc;
+
+use log::{info, warn};
+use seq_macro::seq;
+use x86_64::structures::idt::HandlerFunc;
+
+use super::io_apic::{self, Line};
+use super::local_apic::end_of_interrupt;
+use super::DEVICE_INTERRUPT_OFFSET;
+use crate::percpu;
+use crate::sync::IrqSafeMutex;
+
+/// The number of vectors for device interrupts, starting from `DEVICE_INTERRUPT_OFFSET`.
+pub(super) const DEVICE_VECTORS: usize = 64;
+
+/// The base address of the MSI messages, targeting the local APICs.
+const MSI_ADDRESS_BASE: u64 = 0xfee0_0000;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqSource {
+    /// A legacy ISA IRQ, which may be overridden to another line by ACPI.
+    Isa(u8),
+
+    /// A global system interrupt of the IO APICs.
+    Gsi(u32),
+
+    /// A message signaled interrupt, which the driver programs into the device with
+    /// [`Irq::msi_message`].
+    Msi,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// All of the vectors for devices are taken.
+    NoFreeVector,
+
+    /// The interrupt line is claimed by another driver.
+    LineClaimed,
+
+    /// No IO APIC handles the interrupt line.
+    NoIoApic,
+}
+
+/// The handler of a device interrupt. It runs in the interrupt context, so it should only
+/// acknowledge the device and pass the data to kernel tasks, e.g. with a broadcast channel.
+type IrqHandler = Arc<dyn Fn() + Send + Sync>;
+
+struct Registration {
+    name: &'static str,
+
+    /// The line of the IO APICs, or `None` for MSI.
+    line: Option<Line>,
+
+    handler: IrqHandler,
+}
+
+const NO_REGISTRATION: Option<Registration> = None;
+
+/// The registered handlers, indexed by the vector minus `DEVICE_INTERRUPT_OFFSET`.
+static REGISTRY: IrqSafeMutex<[Option<Registration>; DEVICE_VECTORS]> =
+    IrqSafeMutex::new("irq registry", [NO_REGISTRATION; DEVICE_VECTORS]);
+
+/// A device interrupt claimed by a driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Irq {
+    vector: u8,
+}
+
+impl Irq {
+    #[allow(dead_code)]
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+
+    /// The address and data of the MSI message, which deliver the interrupt to the bootstrap
+    /// processor with the fixed mode and edge trigger.
+    #[allow(dead_code)]
+    pub fn msi_message(&self) -> (u64, u32) {
+        let address = MSI_ADDRESS_BASE | (bsp_apic_id() as u64) << 12;
+        (address, self.vector as u32)
+    }
+
+    fn index(&self) -> usize {
+        (self.vector - DEVICE_INTERRUPT_OFFSET) as usize
+    }
+}
+
+/// Device interrupts are all delivered to the bootstrap processor.
+fn bsp_apic_id() -> u32 {
+    percpu::get(0).expect("bsp not initialized").apic_id
+}
+
+/// Claim the interrupt source for the driver `name`, allocate a vector for it and route the line of
+/// the IO APICs if there's one. The handler is called on every interrupt, followed by an EOI.
+pub fn register<F>(name: &'static str, source: IrqSource, handler: F) -> Result<Irq, IrqError>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let line = match source {
+        IrqSource::Isa(irq) => Some(io_apic::resolve_isa(irq)),
+        IrqSource::Gsi(global_system_interrupt) => {
+            Some(io_apic::resolve_gsi(global_system_interrupt))
+        }
+        IrqSource::Msi => None,
+    };
+    let handler: IrqHandler = Arc::new(handler);
+
+    let mut registry = REGISTRY.lock();
+
+    if let Some(line) = line {
+        let claimed = registry
+            .iter()
+            .flatten()
+            .filter_map(|registration| registration.line)
+            .any(|other| other.global_system_interrupt == line.global_system_interrupt);
+        if claimed {
+            return Err(IrqError::LineClaimed);
+        }
+    }
+
+    let index = registry
+        .iter()
+        .position(Option::is_none)
+        .ok_or(IrqError::NoFreeVector)?;
+    let irq = Irq {
+        vector: DEVICE_INTERRUPT_OFFSET + index as u8,
+    };
+
+    // Install the handler before unmasking the line.
+    registry[index] = Some(Registration {
+        name,
+        line,
+        handler,
+    });
+    if let Some(line) = line {
+        if let Err(error) = io_apic::route(line, irq.vector, bsp_apic_id()) {
+            registry[index] = None;
+            return Err(error);
+        }
+    }
+
+    info!(
+        "registered {:?} of {} at vector {}",
+        source, name, irq.vector
+    );
+    Ok(irq)
+}
+
+/// Release the interrupt, masking its line of the IO APICs.
+#[allow(dead_code)]
+pub fn unregister(irq: Irq) {
+    let registration = REGISTRY.lock()[irq.index()].take();
+
+    if let Some(registration) = registration {
+        if let Some(line) = registration.line {
+            io_apic::mask(line);
+        }
+        info!(
+            "unregistered irq of {} at vector {}",
+            registration.name, irq.vector
+        );
+    }
+}
+
+fn dispatch(index: usize) {
+    // Don't hold the lock in the handler, which may register or unregister interrupts.
+    let handler = REGISTRY.lock()[index]
+        .as_ref()
+        .map(|registration| registration.handler.clone());
+
+    match handler {
+        Some(handler) => handler(),
+        None => warn!(
+            "spurious device interrupt at vector {}",
+            DEVICE_INTERRUPT_OFFSET as usize + index
+        ),
+    }
+
+    end_of_interrupt();
+}
+
+seq!(N in 0..64 {
+    crate::define_frame_saving_handler! { irq~N, irq_inner~N }
+
+    fn irq_inner~N() {
+        dispatch(N);
+    }
+});
+
+/// The entries of the device vectors in the IDT, each dispatching to the registered handler.
+pub(super) static HANDLERS: [HandlerFunc; DEVICE_VECTORS] = seq!(N in 0..64 {
+    [#(irq~N,)*]
+});
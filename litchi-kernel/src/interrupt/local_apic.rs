@@ -1,13 +1,11 @@
-use lazy_static::lazy_static;
-use spin::Mutex;
 use x2apic::lapic::{self, LocalApic};
+use x86_64::PhysAddr;
 
 use super::UserInterrupt;
 use crate::acpi::ACPI;
-
-lazy_static! {
-    static ref LOCAL_APIC: Mutex<LocalApic> = Mutex::new(new_local_apic());
-}
+use crate::memory::phys_to_virt;
+use crate::percpu;
+use crate::sync::IrqSafeMutex;
 
 const TIMER_INTERVAL: u32 = 10_000_000;
 
@@ -17,15 +15,26 @@ fn new_local_apic() -> LocalApic {
         .spurious_vector(UserInterrupt::ApicSpurious.as_index())
         .timer_vector(UserInterrupt::ApicTimer.as_index())
         .timer_initial(TIMER_INTERVAL)
-        .set_xapic_base(ACPI.apic_info.local_apic_address) // or lapic::xapic_base()
+        .set_xapic_base(phys_to_virt(PhysAddr::new(ACPI.apic_info.local_apic_address)).as_u64())
         .build()
         .expect("failed to build lapic")
 }
 
+/// Run the closure with the local APIC of the current processor.
+pub fn with_local_apic<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut LocalApic) -> R,
+{
+    let local_apic = percpu::current()
+        .local_apic
+        .call_once(|| IrqSafeMutex::new("local apic", new_local_apic()));
+    f(&mut *local_apic.lock())
+}
+
 pub fn enable() {
-    unsafe { LOCAL_APIC.lock().enable() };
+    with_local_apic(|lapic| unsafe { lapic.enable() });
 }
 
 pub fn end_of_interrupt() {
-    unsafe { LOCAL_APIC.lock().end_of_interrupt() };
+    with_local_apic(|lapic| unsafe { lapic.end_of_interrupt() });
 }
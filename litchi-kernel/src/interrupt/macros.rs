@@ -18,6 +18,12 @@ macro_rules! define_frame_saving_handler {
 
             unsafe {
                 asm!(
+                    // Switch to the per-cpu block of the kernel if we're from the user, by
+                    // checking the privilege level of the code segment in the interrupt frame.
+                    "test   qword ptr [rsp + 8], 3",
+                    "jz     2f",
+                    "swapgs",
+                    "2:",
                     // The order must be consistent with [`TaskFrame`].
                     //
                     // I've no idea about how to saving ds & es with inline assembly...
@@ -63,3 +69,77 @@ macro_rules! define_frame_saving_handler {
         }
     };
 }
+
+/// Like [`define_frame_saving_handler`], but for exceptions. The handler inner is called with the
+/// [`TaskFrame`](crate::task::TaskFrame) and the error code, which is zero if the exception has no
+/// error code, and never returns. It may resume the interrupted code by popping the frame.
+#[macro_export]
+macro_rules! define_exception_handler {
+    (error_code: $error_code_ty: ty; $handler_name: ident, $handler_inner: ident) => {
+        $crate::define_exception_handler!(
+            @define "", $handler_name, $handler_inner, (error_code: $error_code_ty)
+        );
+    };
+    ($handler_name: ident, $handler_inner: ident) => {
+        // Push a zero error code, so that the stack looks the same as exceptions with one.
+        $crate::define_exception_handler!(@define "push 0", $handler_name, $handler_inner, ());
+    };
+
+    (@define $push_error_code: literal, $handler_name: ident, $handler_inner: ident, ($($error_code: ident: $error_code_ty: ty)?)) => {
+        #[naked]
+        /// Note: With `naked`, this function is exactly a naked procedure without any abi.
+        /// The `x86-interrupt` is just for type checking of setting handler with `x86_64` crate.
+        pub extern "x86-interrupt" fn $handler_name(
+            frame: x86_64::structures::idt::InterruptStackFrame,
+            $($error_code: $error_code_ty)?
+        ) {
+            use core::arch::asm;
+            use x86_64::registers::{self, segmentation::Segment};
+            use $crate::task::TaskFrame;
+
+            unsafe {
+                asm!(
+                    $push_error_code,
+                    // Switch to the per-cpu block of the kernel if we're from the user.
+                    "test   qword ptr [rsp + 16], 3",
+                    "jz     2f",
+                    "swapgs",
+                    "2:",
+                    // Take the error code out, and save rax in its place. Then the rest of
+                    // [`TaskFrame`] is saved below it.
+                    "xchg   rax, qword ptr [rsp]",
+                    "mov    qword ptr [rsp - 128], 0", // Placeholder for es
+                    "mov    qword ptr [rsp - 120], 0", // Placeholder for ds
+                    "mov    qword ptr [rsp - 112], r15",
+                    "mov    qword ptr [rsp - 104], r14",
+                    "mov    qword ptr [rsp - 96], r13",
+                    "mov    qword ptr [rsp - 88], r12",
+                    "mov    qword ptr [rsp - 80], r11",
+                    "mov    qword ptr [rsp - 72], r10",
+                    "mov    qword ptr [rsp - 64], r9",
+                    "mov    qword ptr [rsp - 56], r8",
+                    "mov    qword ptr [rsp - 48], rsi",
+                    "mov    qword ptr [rsp - 40], rdi",
+                    "mov    qword ptr [rsp - 32], rbp",
+                    "mov    qword ptr [rsp - 24], rdx",
+                    "mov    qword ptr [rsp - 16], rcx",
+                    "mov    qword ptr [rsp - 8],  rbx",
+                    // `TaskFrame` is passed on the stack, so the error code is the first
+                    // argument in registers.
+                    "mov    rdi, rax",
+                    "sub    rsp, 128",
+                    "call   {}", // We've assembled `TaskFrame`, jump to _inner.
+                    sym _inner,
+                    options(noreturn)
+                )
+            }
+
+            extern "C" fn _inner(mut frame: TaskFrame, error_code: u64) -> ! {
+                frame.ds = registers::segmentation::DS::get_reg().0 as u64;
+                frame.es = registers::segmentation::ES::get_reg().0 as u64;
+
+                $handler_inner(frame, error_code)
+            }
+        }
+    };
+}
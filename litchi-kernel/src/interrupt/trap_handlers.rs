@@ -1,9 +1,11 @@
 use core::arch::asm;
 
-use log::{error, info};
+use log::error;
+use x86_64::registers::control::Cr2;
 use x86_64::structures::idt::InterruptStackFrame;
 
 use crate::qemu::{exit, ExitCode};
+use crate::{backtrace, stack};
 
 pub fn unhandled(stack_frame: InterruptStackFrame, index: u8, error_code: Option<u64>) {
     error!(
@@ -13,20 +15,28 @@ pub fn unhandled(stack_frame: InterruptStackFrame, index: u8, error_code: Option
     exit(ExitCode::Failed)
 }
 
-pub extern "x86-interrupt" fn breakpoint(stack_frame: InterruptStackFrame) {
-    info!("breakpoint: {:?}", stack_frame);
-}
-
 pub extern "x86-interrupt" fn double_fault(stack_frame: InterruptStackFrame, error_code: u64) -> ! {
     let stack_pointer: *const ();
     unsafe {
         asm!("mov {}, rsp", out(reg) stack_pointer);
     }
 
-    error!(
-        "double fault: {:?}, error code: {}; current stack ptr: {:p}",
-        stack_frame, error_code, stack_pointer
-    );
+    // Faults on the guard page of the stack cannot be handled on the same stack, so we're likely
+    // here for a stack overflow.
+    let addr = Cr2::read();
+    backtrace::handle_fault(addr);
+    if let Some(name) = stack::guard_owner(addr) {
+        error!(
+            "stack overflow in {}: {:?}; current stack ptr: {:p}",
+            name, stack_frame, stack_pointer
+        );
+    } else {
+        error!(
+            "double fault: {:?}, error code: {}; current stack ptr: {:p}",
+            stack_frame, error_code, stack_pointer
+        );
+    }
+    backtrace::print();
 
     exit(ExitCode::Failed)
 }
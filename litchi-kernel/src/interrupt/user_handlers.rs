@@ -1,68 +1,172 @@
 use litchi_user_common::syscall;
-use log::{debug, error, warn};
-use x86_64::registers::segmentation::SegmentSelector;
+use log::{debug, info};
+use paste::paste;
+use x86_64::registers::control::Cr2;
+use x86_64::registers::segmentation::{SegmentSelector, GS};
 use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
 use x86_64::PrivilegeLevel;
 
 use crate::interrupt::local_apic::end_of_interrupt;
-use crate::qemu::{exit, ExitCode};
-use crate::serial_log::DEBUG_SERIAL;
 use crate::syscall::handle_syscall;
-use crate::task::{schedule_and_run, with_task_manager};
-use crate::{define_frame_saving_handler, kernel_task};
+use crate::task::{schedule_and_run, with_task_manager, TaskFrame, TaskManager};
+use crate::{
+    backtrace, define_exception_handler, define_frame_saving_handler, kernel_task, memory, percpu,
+    stack,
+};
 
 define_frame_saving_handler! { syscall, syscall_inner }
 define_frame_saving_handler! { yield; apic_timer, apic_timer_inner }
-define_frame_saving_handler! { serial_in, serial_in_inner }
 
 fn syscall_inner() {
-    let info = with_task_manager(|tm| tm.current_info().cloned().unwrap());
+    let (info, (in_addr, out_addr)) = with_task_manager(|tm| {
+        (
+            tm.current_info().unwrap(),
+            tm.current_syscall_buffers().unwrap(),
+        )
+    });
     debug!("serving system call from {}", info.id);
 
-    let response = handle_syscall(unsafe { syscall::get_syscall() }, info);
+    let response = handle_syscall(unsafe { syscall::get_syscall(in_addr) }, info);
 
     // Maybe we've killed or yielded current task, so we first check whether it is still running.
     if with_task_manager(|tm| tm.has_running()) {
-        unsafe { syscall::response(response) };
+        unsafe { syscall::response(out_addr, response) };
     }
 }
 
 fn apic_timer_inner() {
-    kernel_task::time::inc_slice();
+    // Every processor has its own timer, but only the bootstrap one counts the time.
+    if percpu::current().is_bsp() {
+        kernel_task::time::inc_slice();
+    }
+    with_task_manager(TaskManager::tick);
 
     end_of_interrupt();
 }
 
-fn serial_in_inner() {
-    let byte = DEBUG_SERIAL.lock().receive();
-    kernel_task::serial::push(byte);
-
-    end_of_interrupt();
+/// Whether the interrupt is raised in the user mode, by the privilege level of the code segment.
+fn from_user(stack_frame: &InterruptStackFrame) -> bool {
+    SegmentSelector(stack_frame.code_segment as u16).rpl() == PrivilegeLevel::Ring3
 }
 
-pub extern "x86-interrupt" fn page_fault(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    // It's okay that we're not saving the task frame, since we gonna kill it.
-
-    let pl = SegmentSelector(stack_frame.code_segment as u16).rpl();
-    if pl == PrivilegeLevel::Ring0 {
-        error!(
-            "kernel page fault: frame {:?}, error code: {:?}",
-            stack_frame, error_code
+/// Kill the current task with a crash report if the exception is raised by the user, or panic in
+/// the kernel.
+fn handle_exception(name: &str, frame: TaskFrame, error_code: Option<u64>) -> ! {
+    if !frame.is_user() {
+        panic!(
+            "{} in kernel: frame {:?}, error code: {:?}",
+            name, frame, error_code
         );
-        exit(ExitCode::Failed)
     }
 
     with_task_manager(|tm| {
-        let current_task = tm.current_info().unwrap().clone();
-        warn!(
-            "task page fault, kill it: {:?}, frame {:?}, error code: {:?}",
-            current_task, stack_frame, error_code
-        );
-        tm.drop_current();
+        if tm.has_running() {
+            tm.crash_current(
+                format_args!("{}, error code: {:?}", name, error_code),
+                Some(&frame),
+            );
+        }
+    });
+
+    schedule_and_run();
+}
+
+macro_rules! define_user_exception_handler {
+    ($handler_name:ident, $name:literal) => {
+        paste! {
+            define_exception_handler! { $handler_name, [<$handler_name _inner>] }
+
+            fn [<$handler_name _inner>](frame: TaskFrame, _error_code: u64) -> ! {
+                handle_exception($name, frame, None)
+            }
+        }
+    };
+    ($handler_name:ident, $name:literal,error_code) => {
+        paste! {
+            define_exception_handler! { error_code: u64; $handler_name, [<$handler_name _inner>] }
+
+            fn [<$handler_name _inner>](frame: TaskFrame, error_code: u64) -> ! {
+                handle_exception($name, frame, Some(error_code))
+            }
+        }
+    };
+}
+
+define_user_exception_handler! { divide_error, "divide error" }
+define_user_exception_handler! { debug, "debug exception" }
+define_user_exception_handler! { overflow, "overflow" }
+define_user_exception_handler! { bound_range_exceeded, "bound range exceeded" }
+define_user_exception_handler! { invalid_opcode, "invalid opcode" }
+define_user_exception_handler! { device_not_available, "device not available" }
+define_user_exception_handler! { invalid_tss, "invalid tss", error_code }
+define_user_exception_handler! { segment_not_present, "segment not present", error_code }
+define_user_exception_handler! { stack_segment_fault, "stack segment fault", error_code }
+define_user_exception_handler! { general_protection_fault, "general protection fault", error_code }
+define_user_exception_handler! { x87_floating_point, "x87 floating point exception" }
+define_user_exception_handler! { alignment_check, "alignment check", error_code }
+define_user_exception_handler! { simd_floating_point, "simd floating point exception" }
+define_user_exception_handler! { virtualization, "virtualization exception" }
+define_user_exception_handler! { security_exception, "security exception", error_code }
+
+define_exception_handler! { breakpoint, breakpoint_inner }
+define_exception_handler! { error_code: PageFaultErrorCode; page_fault, page_fault_inner }
+
+/// Breakpoints in the kernel are only logged, e.g. for testing interrupts on boot.
+fn breakpoint_inner(frame: TaskFrame, _error_code: u64) -> ! {
+    if frame.is_user() {
+        handle_exception("breakpoint", frame, None);
+    }
+    info!("breakpoint: {:?}", frame.frame);
+    unsafe { frame.pop() }
+}
+
+fn page_fault_inner(frame: TaskFrame, error_code: u64) -> ! {
+    let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+    let addr = Cr2::read();
+
+    if !frame.is_user() {
+        backtrace::handle_fault(addr);
+        if let Some(name) = stack::guard_owner(addr) {
+            panic!("stack overflow in {}: frame {:?}", name, frame.frame);
+        } else {
+            panic!(
+                "kernel page fault at {:?}: frame {:?}, error code: {:?}",
+                addr, frame, error_code
+            );
+        }
+    }
+
+    // Back the page lazily if it's inside a valid region, then return to the task to retry.
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && with_task_manager(|tm| tm.handle_current_page_fault(addr, error_code))
+    {
+        unsafe { frame.pop() }
+    }
+
+    with_task_manager(|tm| {
+        // The task may have been killed by the OOM killer already.
+        if tm.has_running() {
+            tm.crash_current(
+                format_args!("page fault at {:?}, error code: {:?}", addr, error_code),
+                Some(&frame),
+            );
+        }
     });
 
     schedule_and_run();
 }
+
+pub extern "x86-interrupt" fn tlb_shootdown(stack_frame: InterruptStackFrame) {
+    // Nothing is rescheduled, so swap back before returning if we're from the user.
+    let user = from_user(&stack_frame);
+    if user {
+        unsafe { GS::swap() };
+    }
+
+    memory::serve_shootdown();
+    end_of_interrupt();
+
+    if user {
+        unsafe { GS::swap() };
+    }
+}
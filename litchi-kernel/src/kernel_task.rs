@@ -2,12 +2,15 @@ pub mod broadcast;
 mod executor;
 pub mod serial;
 pub mod time;
+mod zero;
 
 use self::executor::{TaskFuture, KERNEL_TASK_EXECUTOR};
 
 pub fn init() {
     lazy_static::initialize(&KERNEL_TASK_EXECUTOR);
+    serial::init();
     KERNEL_TASK_EXECUTOR.spawn(serial::echo());
+    KERNEL_TASK_EXECUTOR.spawn(zero::zero_frames());
 }
 
 /// Run all of the kernel tasks until they're all pending.
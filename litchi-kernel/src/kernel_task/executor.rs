@@ -87,11 +87,17 @@ impl KernelTaskExecutor {
 
     pub(super) fn poll(&self) {
         while let Some(id) = self.ready.pop() {
-            let (mut task, waker) = {
+            let entry = {
                 let Some(task_entry) = self.tasks.lock().get_mut(&id) else {
                     continue;
                 };
-                task_entry.take().unwrap()
+                task_entry.take()
+            };
+            let Some((mut task, waker)) = entry else {
+                // It's being polled on another processor. Leave the wake-up to the next poll, but
+                // don't spin on it here.
+                self.ready.push(id).expect("kernel task full");
+                break;
             };
 
             let mut context = Context::from_waker(&waker);
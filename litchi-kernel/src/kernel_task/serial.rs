@@ -1,8 +1,9 @@
 use futures_async_stream::for_await;
 use spin::Mutex;
 
+use crate::interrupt::irq::{self, IrqSource};
 use crate::kernel_task::broadcast;
-use crate::print;
+use crate::{print, serial_log};
 
 lazy_static::lazy_static! {
     static ref CHANNEL: (broadcast::Sender<u8>, Mutex<Option<broadcast::Receiver<u8>>>) = {
@@ -11,7 +12,25 @@ lazy_static::lazy_static! {
     };
 }
 
-pub fn push(byte: u8) {
+/// The ISA IRQ of COM1.
+const SERIAL_IRQ: u8 = 4;
+
+/// Drain all of the received bytes, since the interrupt is edge-triggered and won't be raised again
+/// until then.
+fn on_interrupt() {
+    while let Some(byte) = serial_log::try_receive() {
+        push(byte);
+    }
+}
+
+pub(super) fn init() {
+    irq::register("serial", IrqSource::Isa(SERIAL_IRQ), on_interrupt)
+        .expect("failed to register irq for serial in");
+    // Bytes received before the line is routed never raise an edge.
+    on_interrupt();
+}
+
+fn push(byte: u8) {
     CHANNEL.0.send_all(byte);
     if byte == b'\r' {
         CHANNEL.0.send_all(b'\n');
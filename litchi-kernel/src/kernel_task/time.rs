@@ -3,25 +3,35 @@ use alloc::vec::Vec;
 use core::sync::atomic::{AtomicU64, Ordering};
 
 use futures::StreamExt;
-use spin::Mutex;
 
 use super::broadcast;
+use crate::sync::IrqSafeMutex;
 
 static SLICE_COUNT: AtomicU64 = AtomicU64::new(0);
 
 type Notifier = broadcast::Sender<()>;
 
 lazy_static::lazy_static! {
-    static ref NOTIFIERS: Mutex<BTreeMap<u64, Vec<Notifier>>> = Mutex::new(BTreeMap::new());
+    static ref NOTIFIERS: IrqSafeMutex<BTreeMap<u64, Vec<Notifier>>> =
+        IrqSafeMutex::new("time notifiers", BTreeMap::new());
+}
+
+/// The number of timer slices since boot, counted by the bootstrap processor.
+pub fn slices() -> u64 {
+    SLICE_COUNT.load(Ordering::Acquire)
 }
 
 pub fn inc_slice() {
     // crate::print!(".");
-    let old_count = SLICE_COUNT.fetch_add(1, Ordering::SeqCst);
-    let count = old_count + 1;
-    if let Some(notifers) = NOTIFIERS.lock().remove(&count) {
-        notifers.into_iter().for_each(|n| n.send_one(()));
-    }
+    // Count under the lock, so that `sleep` never registers a notifier for a slice that has just
+    // been passed.
+    let mut notifiers = NOTIFIERS.lock();
+    let count = SLICE_COUNT.fetch_add(1, Ordering::SeqCst) + 1;
+    let later = notifiers.split_off(&(count + 1));
+    let due = core::mem::replace(&mut *notifiers, later);
+    drop(notifiers);
+
+    due.into_values().flatten().for_each(|n| n.send_one(()));
 }
 
 pub async fn sleep(slice: usize) {
@@ -29,12 +39,14 @@ pub async fn sleep(slice: usize) {
         return;
     }
     let (tx, mut rx) = broadcast::channel();
-    let current = SLICE_COUNT.load(Ordering::Acquire);
-    NOTIFIERS
-        .lock()
-        .entry(current + slice as u64)
-        .or_default()
-        .push(tx);
+    {
+        let mut notifiers = NOTIFIERS.lock();
+        let current = SLICE_COUNT.load(Ordering::Acquire);
+        notifiers
+            .entry(current + slice as u64)
+            .or_default()
+            .push(tx);
+    }
 
     rx.next().await.unwrap();
 }
@@ -0,0 +1,14 @@
+use super::time;
+use crate::frame_allocator;
+
+/// The maximum number of frames to zero in one time slice.
+const BATCH_FRAMES: usize = 64;
+
+/// Zero the frames released by users in the background, and keep the pool of zeroed frames filled,
+/// so that allocating frames for users rarely has to zero them on the spot.
+pub(super) async fn zero_frames() {
+    loop {
+        frame_allocator::zero_frames(BATCH_FRAMES);
+        time::sleep(1).await;
+    }
+}
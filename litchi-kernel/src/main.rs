@@ -6,24 +6,34 @@
 #![feature(type_name_of_val)]
 #![feature(naked_functions)]
 #![feature(asm_sym)]
+#![feature(asm_const)]
 #![feature(trait_alias)]
 #![feature(let_else)]
 #![feature(proc_macro_hygiene)]
 #![feature(stmt_expr_attributes)]
 #![feature(drain_filter)]
+#![feature(int_roundings)]
 
 extern crate alloc;
 
 mod acpi;
+mod backtrace;
+mod fpu;
 mod frame_allocator;
 mod gdt;
 mod heap;
 mod interrupt;
 mod kernel_task;
 mod memory;
+mod percpu;
 mod qemu;
+mod random;
 mod resource;
 mod serial_log;
+mod shm;
+mod smp;
+mod stack;
+mod sync;
 mod syscall;
 mod task;
 
@@ -34,6 +44,7 @@ use log::{error, info};
 use spin::Once;
 use x86_64::instructions;
 
+use crate::acpi::ACPI;
 use crate::qemu::{exit, ExitCode};
 
 static BOOT_INFO: Once<&'static BootInfo> = Once::new();
@@ -51,14 +62,21 @@ pub extern "C" fn _kernel_main(boot_info: *const BootInfo) -> ! {
     // Check BSS
     memory_check();
 
-    // Initialize memories
-    gdt::init();
+    // Initialize memories. The stacks for interrupts in the GDT are allocated from the kernel page
+    // table, so it must be initialized first.
     frame_allocator::init();
     memory::init();
+    stack::init();
     heap::init();
+    serial_log::init_filter();
 
-    // Initialize interrupts
+    // Initialize the per-cpu block and the GDT of the bootstrap processor
     acpi::init();
+    percpu::init(0, ACPI.processor_info.boot.local_apic_id);
+    gdt::init();
+    fpu::init();
+
+    // Initialize interrupts
     interrupt::disable();
     interrupt::init();
 
@@ -66,8 +84,10 @@ pub extern "C" fn _kernel_main(boot_info: *const BootInfo) -> ! {
     instructions::interrupts::int3();
 
     kernel_task::init();
+    random::init();
 
     task::load();
+    smp::init();
     task::run();
 }
 
@@ -85,5 +105,6 @@ fn memory_check() {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     error!("{}", info);
+    backtrace::print();
     exit(ExitCode::Failed);
 }
@@ -1,25 +1,32 @@
 use core::fmt::Debug;
-use core::intrinsics::copy_nonoverlapping;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use log::info;
-use spin::Mutex;
 use x86_64::registers::control::{Cr3, Cr3Flags};
-use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::mapper::{MapToError, MapperFlush, TranslateResult};
 use x86_64::structures::paging::{
     FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame,
     Size4KiB, Translate,
 };
-use x86_64::{instructions, VirtAddr};
+use x86_64::{PhysAddr, VirtAddr};
 
 use crate::frame_allocator::RaiiFrameAllocator;
-use crate::BOOT_INFO;
+use crate::sync::IrqSafeMutex;
+use crate::{percpu, BOOT_INFO};
+
+mod tlb;
+
+pub use self::tlb::{serve_shootdown, TlbBatch};
 
 pub struct PageTableWrapper {
     frame: PhysFrame,
 
-    inner: Mutex<OffsetPageTable<'static>>,
+    inner: IrqSafeMutex<OffsetPageTable<'static>>,
 
-    allocator: Mutex<RaiiFrameAllocator>,
+    allocator: IrqSafeMutex<RaiiFrameAllocator>,
+
+    /// The processors with this page table loaded, as a bit mask of their ids.
+    active: AtomicU64,
 }
 
 impl core::fmt::Debug for PageTableWrapper {
@@ -32,10 +39,16 @@ impl core::fmt::Debug for PageTableWrapper {
 }
 
 impl PageTableWrapper {
-    fn new(frame: PhysFrame, allocator: RaiiFrameAllocator) -> Self {
+    /// Wrap the level-4 table in the frame. The names of the locks tell the kernel page table from
+    /// the user ones.
+    fn new(
+        frame: PhysFrame,
+        allocator: RaiiFrameAllocator,
+        names: (&'static str, &'static str),
+    ) -> Self {
         let boot_info = BOOT_INFO.get().unwrap();
 
-        let l4_table = frame.start_address().as_u64() as *mut PageTable;
+        let l4_table = phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>();
         let inner = unsafe {
             let l4_table = l4_table.as_mut().unwrap();
             OffsetPageTable::new(l4_table, boot_info.phys_offset)
@@ -43,41 +56,75 @@ impl PageTableWrapper {
 
         Self {
             frame,
-            inner: Mutex::new(inner),
-            allocator: Mutex::new(allocator),
+            inner: IrqSafeMutex::new(names.0, inner),
+            allocator: IrqSafeMutex::new(names.1, allocator),
+            active: AtomicU64::new(0),
         }
     }
 
     fn kernel() -> Self {
         let frame = BOOT_INFO.get().unwrap().kernel_page_table;
 
-        Self::new(frame, RaiiFrameAllocator::new_untraced())
+        Self::new(
+            frame,
+            RaiiFrameAllocator::new_untraced(),
+            ("kernel page table", "kernel page table frames"),
+        )
     }
 
-    pub fn new_user() -> Self {
+    /// Create a page table for a user task. Returns `None` if there's no enough memory.
+    pub fn new_user() -> Option<Self> {
         let mut allocator = RaiiFrameAllocator::new_traced();
 
-        let frame = allocator
-            .allocate_frame()
-            .expect("failed to allocate frame for new page table");
+        let frame = allocator.allocate_frame()?;
 
-        // Copy mapping for kernel.
-        // TODO: This requires memory space used for kernel should not overlap with users.
+        // Share the higher half with the kernel, and leave the lower half empty for the user.
         unsafe {
-            copy_nonoverlapping(
-                KERNEL_PAGE_TABLE.frame.start_address().as_u64() as *const u8,
-                frame.start_address().as_u64() as *mut _,
-                frame.size() as usize,
-            );
+            let kernel_l4_table =
+                &*phys_to_virt(KERNEL_PAGE_TABLE.frame.start_address()).as_ptr::<PageTable>();
+            let l4_table = &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>();
+
+            for (i, entry) in l4_table.iter_mut().enumerate() {
+                if i < KERNEL_L4_START {
+                    entry.set_unused();
+                } else {
+                    *entry = kernel_l4_table[i].clone();
+                }
+            }
         }
 
-        Self::new(frame, allocator)
+        Some(Self::new(
+            frame,
+            allocator,
+            ("user page table", "user page table frames"),
+        ))
+    }
+
+    /// Copy the level-4 table to the given frame, sharing all of the lower-level tables with this
+    /// one.
+    ///
+    /// # Safety
+    /// The frame must not be used by others.
+    pub unsafe fn copy_l4_to(&self, frame: PhysFrame) {
+        let l4_table = &*phys_to_virt(self.frame.start_address()).as_ptr::<PageTable>();
+        let copy = &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>();
+        *copy = l4_table.clone();
     }
 
     pub fn load(&self) {
+        let cpu = percpu::current();
+        let bit = 1 << cpu.id;
+
+        self.active.fetch_or(bit, Ordering::SeqCst);
         unsafe {
             Cr3::write(self.frame, Cr3Flags::empty());
         }
+
+        let last = cpu.page_table.replace(self);
+        if !last.is_null() && !core::ptr::eq(last, self) {
+            // The last one is not dropped before the bit is cleared.
+            unsafe { (*last).active.fetch_and(!bit, Ordering::SeqCst) };
+        }
     }
 
     pub fn is_current(&self) -> bool {
@@ -88,15 +135,12 @@ impl PageTableWrapper {
     where
         F: FnOnce(&mut RaiiFrameAllocator, &mut OffsetPageTable<'static>) -> R,
     {
-        instructions::interrupts::without_interrupts(|| {
-            let mut allocator = self.allocator.lock();
-            let mut page_table = self.inner.lock();
+        let mut allocator = self.allocator.lock();
+        let mut page_table = self.inner.lock();
 
-            f(&mut *allocator, &mut *page_table)
-        })
+        f(&mut *allocator, &mut *page_table)
     }
 
-    #[allow(dead_code)]
     pub unsafe fn map_to<S: PageSize + Debug>(
         &self,
         page: Page<S>,
@@ -104,15 +148,31 @@ impl PageTableWrapper {
         flags: PageTableFlags,
     ) where
         OffsetPageTable<'static>: Mapper<S>,
+    {
+        self.try_map_to(page, frame, flags)
+            .expect("failed to map frame")
+    }
+
+    /// Map the page to the frame. Fails if the page is already mapped, or there's no enough memory
+    /// for the page tables.
+    pub unsafe fn try_map_to<S: PageSize + Debug>(
+        &self,
+        page: Page<S>,
+        frame: PhysFrame<S>,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<S>>
+    where
+        OffsetPageTable<'static>: Mapper<S>,
     {
         self.with_allocator(|frame_allocator, page_table| {
             page_table
                 .map_to(page, frame, flags, &mut *frame_allocator)
-                .expect("failed to map frame")
-                .flush();
+                .map(MapperFlush::flush)
         })
     }
 
+    /// Allocate a frame and map the page to it. Returns `None` if there's no enough memory for the
+    /// frame or the page tables.
     pub unsafe fn allocate_and_map_to(
         &self,
         page: Page,
@@ -120,17 +180,41 @@ impl PageTableWrapper {
     ) -> Option<PhysFrame> {
         self.with_allocator(|frame_allocator, page_table| {
             let frame = frame_allocator.allocate_frame()?;
+            map_allocated(frame_allocator, page_table, page, frame, flags)
+        })
+    }
 
-            page_table
-                .map_to(page, frame, flags, &mut *frame_allocator)
-                .expect("failed to map frame")
-                .flush();
-
-            Some(frame)
+    /// Allocate a frame, fill it with zeros and map the page to it. Returns `None` if there's no
+    /// enough memory for the frame or the page tables.
+    pub unsafe fn allocate_zeroed_and_map_to(
+        &self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Option<PhysFrame> {
+        self.with_allocator(|frame_allocator, page_table| {
+            let frame = frame_allocator.allocate_zeroed_frame()?;
+            map_allocated(frame_allocator, page_table, page, frame, flags)
         })
     }
 
-    pub fn check_user_accessible(&self, base: *const (), len: usize) -> bool {
+    /// Start a batch of unmapping or protecting pages, see [`TlbBatch`].
+    pub fn batch(&self) -> TlbBatch {
+        TlbBatch::new(self)
+    }
+
+    /// The number of frames owned by this page table, including the ones for the page table itself.
+    pub fn allocated_frames(&self) -> u64 {
+        self.with_allocator(|frame_allocator, _| frame_allocator.allocated_frames())
+    }
+
+    pub fn is_mapped(&self, page: Page) -> bool {
+        self.with_allocator(|_, page_table| page_table.translate_page(page).is_ok())
+    }
+
+    /// Check whether `[base, base + len)` is mapped for the user, and also writable if `write` is
+    /// true. Since the write protection is enabled, the kernel cannot write to read-only user pages
+    /// either.
+    pub fn check_user_accessible(&self, base: *const (), len: usize, write: bool) -> bool {
         if len == 0 {
             return true;
         }
@@ -145,7 +229,8 @@ impl PageTableWrapper {
 
                 match page_table.translate(check_addr) {
                     TranslateResult::Mapped { flags, .. }
-                        if flags.contains(PageTableFlags::USER_ACCESSIBLE) => {}
+                        if flags.contains(PageTableFlags::USER_ACCESSIBLE)
+                            && (!write || flags.contains(PageTableFlags::WRITABLE)) => {}
 
                     TranslateResult::Mapped { .. }
                     | TranslateResult::NotMapped
@@ -158,12 +243,76 @@ impl PageTableWrapper {
     }
 }
 
+/// Map the page to the frame just allocated. If there's no enough memory for the page tables, the
+/// frame is deallocated and `None` is returned.
+unsafe fn map_allocated(
+    frame_allocator: &mut RaiiFrameAllocator,
+    page_table: &mut OffsetPageTable<'static>,
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> Option<PhysFrame> {
+    match page_table.map_to(page, frame, flags, &mut *frame_allocator) {
+        Ok(flush) => {
+            flush.flush();
+            Some(frame)
+        }
+        Err(MapToError::FrameAllocationFailed) => {
+            frame_allocator.deallocate_unused(frame);
+            None
+        }
+        Err(err) => panic!("failed to map frame: {:?}", err),
+    }
+}
+
+impl Drop for PageTableWrapper {
+    fn drop(&mut self) {
+        if self.is_current() {
+            KERNEL_PAGE_TABLE.load();
+        }
+        // Others may still have it loaded before they schedule the next tasks. They may also be
+        // waiting for us to serve a shootdown of the kernel half, while the interrupts are
+        // disabled here.
+        while self.active.load(Ordering::SeqCst) != 0 {
+            serve_shootdown();
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Get the virtual address through which the kernel accesses the given physical address.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    BOOT_INFO.get().unwrap().phys_offset + addr.as_u64()
+}
+
+/// The first level-4 entry of the higher half, which belongs to the kernel.
+const KERNEL_L4_START: usize = 256;
+
 lazy_static::lazy_static! {
     pub static ref KERNEL_PAGE_TABLE: PageTableWrapper = PageTableWrapper::kernel();
 }
 
+/// Allocate all of the level-3 tables of the higher half in advance, so that the level-4 entries
+/// copied into the user page tables never change and the kernel mappings stay in sync.
+fn populate_kernel_half() {
+    KERNEL_PAGE_TABLE.with_allocator(|frame_allocator, page_table| {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let l4_table = page_table.level_4_table();
+
+        for entry in l4_table.iter_mut().skip(KERNEL_L4_START) {
+            if entry.is_unused() {
+                let frame = frame_allocator
+                    .allocate_zeroed_frame()
+                    .expect("failed to allocate frame for kernel page table");
+                entry.set_frame(frame, flags);
+            }
+        }
+    });
+}
+
 pub fn init() {
     lazy_static::initialize(&KERNEL_PAGE_TABLE);
+    populate_kernel_half();
 
     info!("prepared page table")
 }
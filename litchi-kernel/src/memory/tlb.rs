@@ -0,0 +1,203 @@
+use alloc::vec::Vec;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::{Mutex, RwLock};
+use x86_64::instructions::tlb;
+use x86_64::structures::paging::{FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame};
+
+use super::{PageTableWrapper, KERNEL_L4_START};
+use crate::interrupt::{with_local_apic, UserInterrupt};
+use crate::{percpu, smp};
+
+/// Flush the whole TLB instead, if there're more pages than this in a batch.
+const MAX_BATCH_PAGES: usize = 32;
+
+/// The pages to invalidate on each processor.
+#[derive(Debug, Default, Clone)]
+struct Invalidation {
+    pages: Vec<Page>,
+
+    /// There're too many pages to invalidate one by one.
+    all: bool,
+
+    /// Some of the pages are in the kernel half, which is shared by all of the page tables.
+    kernel: bool,
+}
+
+impl Invalidation {
+    fn push(&mut self, page: Page) {
+        if self.pages.len() < MAX_BATCH_PAGES {
+            self.pages.push(page);
+        } else {
+            self.all = true;
+        }
+        self.kernel |= usize::from(page.p4_index()) >= KERNEL_L4_START;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    fn flush_local(&self) {
+        if self.all {
+            tlb::flush_all();
+        } else {
+            for page in self.pages.iter() {
+                tlb::flush(page.start_address());
+            }
+        }
+    }
+}
+
+/// A batch of changes to the mappings of a page table. When it's dropped, the stale TLB entries
+/// are invalidated on all of the processors using the page table, then the unmapped frames are
+/// freed.
+#[must_use]
+pub struct TlbBatch<'a> {
+    page_table: &'a PageTableWrapper,
+
+    invalidation: Invalidation,
+
+    /// The unmapped frames, which may be still accessed by others before the invalidation.
+    frames: Vec<PhysFrame>,
+}
+
+impl<'a> TlbBatch<'a> {
+    pub(super) fn new(page_table: &'a PageTableWrapper) -> Self {
+        Self {
+            page_table,
+            invalidation: Invalidation::default(),
+            frames: Vec::new(),
+        }
+    }
+
+    /// Unmap the page and deallocate its frame later if it's owned by the page table. Returns
+    /// `false` if the page is not mapped.
+    pub unsafe fn unmap(&mut self, page: Page) -> bool {
+        let frame = self.page_table.with_allocator(|_, page_table| {
+            let (frame, flush) = page_table.unmap(page).ok()?;
+            flush.ignore();
+            Some(frame)
+        });
+
+        match frame {
+            Some(frame) => {
+                self.invalidation.push(page);
+                self.frames.push(frame);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Change the flags of a mapped page. Returns `false` if the page is not mapped.
+    pub unsafe fn update_flags(&mut self, page: Page, flags: PageTableFlags) -> bool {
+        let updated = self.page_table.with_allocator(|_, page_table| {
+            match page_table.update_flags(page, flags) {
+                Ok(flush) => {
+                    flush.ignore();
+                    true
+                }
+                Err(_) => false,
+            }
+        });
+
+        if updated {
+            self.invalidation.push(page);
+        }
+        updated
+    }
+}
+
+impl Drop for TlbBatch<'_> {
+    fn drop(&mut self) {
+        if !self.invalidation.is_empty() {
+            shootdown(self.page_table, &self.invalidation);
+        }
+
+        let frames = core::mem::take(&mut self.frames);
+        self.page_table.with_allocator(|frame_allocator, _| {
+            for frame in frames {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+        });
+    }
+}
+
+/// Serializes the shootdowns, only one request is served at a time.
+static SHOOTDOWN_LOCK: Mutex<()> = Mutex::new(());
+
+static REQUEST: RwLock<Option<Invalidation>> = RwLock::new(None);
+
+/// The processors that haven't served the request yet.
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// Invalidate the pages on all of the processors using the page table, and wait for them to finish.
+fn shootdown(page_table: &PageTableWrapper, invalidation: &Invalidation) {
+    let current = 1 << percpu::current().id;
+    let online = (1 << smp::online_cpus()) - 1;
+
+    let targets = if invalidation.kernel {
+        online
+    } else {
+        page_table.active.load(Ordering::SeqCst)
+    };
+    if targets & current != 0 {
+        invalidation.flush_local();
+    }
+    let targets = targets & !current;
+    if targets == 0 {
+        return;
+    }
+
+    let _guard = loop {
+        if let Some(guard) = SHOOTDOWN_LOCK.try_lock() {
+            break guard;
+        }
+        // The holder may be waiting for us, while the interrupts are disabled here.
+        serve_shootdown();
+        spin_loop();
+    };
+
+    *REQUEST.write() = Some(invalidation.clone());
+    PENDING.store(targets, Ordering::SeqCst);
+
+    for cpu_id in (0..smp::online_cpus()).filter(|cpu_id| targets & (1 << cpu_id) != 0) {
+        let apic_id = percpu::get(cpu_id).unwrap().apic_id;
+        with_local_apic(|lapic| unsafe {
+            lapic.send_ipi(UserInterrupt::TlbShootdown as u8, apic_id)
+        });
+    }
+
+    // The processors switching to other page tables have their TLBs flushed as well, so there's no
+    // need to wait for them, unless the kernel half is changed.
+    loop {
+        let pending = PENDING.load(Ordering::SeqCst);
+        let remaining = if invalidation.kernel {
+            pending
+        } else {
+            pending & page_table.active.load(Ordering::SeqCst)
+        };
+        if remaining == 0 {
+            break;
+        }
+        // Keep serving as in taking the lock, so that no processor is ever blocked on us.
+        serve_shootdown();
+        spin_loop();
+    }
+    PENDING.store(0, Ordering::SeqCst);
+}
+
+/// Serve the shootdown request for the current processor, if there's one.
+pub fn serve_shootdown() {
+    let current = 1 << percpu::current().id;
+    if PENDING.load(Ordering::SeqCst) & current == 0 {
+        return;
+    }
+
+    if let Some(invalidation) = REQUEST.read().as_ref() {
+        invalidation.flush_local();
+    }
+    PENDING.fetch_and(!current, Ordering::SeqCst);
+}
@@ -0,0 +1,100 @@
+use alloc::boxed::Box;
+use core::arch::asm;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use log::info;
+use spin::Once;
+use x2apic::lapic::LocalApic;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::VirtAddr;
+
+use crate::memory::PageTableWrapper;
+#[cfg(feature = "lock-debug")]
+use crate::sync::HeldLocks;
+use crate::sync::IrqSafeMutex;
+
+/// The maximum number of processors supported.
+pub const MAX_CPUS: usize = 16;
+
+/// The data owned by each processor. The block of the current processor is found through the GS
+/// base, which is swapped with `swapgs` on every entry from and exit to the user mode.
+#[repr(C)]
+pub struct PerCpu {
+    /// Points to the block itself, so that it can be read from `gs:[0]`.
+    this: *const PerCpu,
+
+    /// The index of this processor, where the bootstrap processor is always 0.
+    pub id: usize,
+
+    /// The local APIC ID of this processor.
+    pub apic_id: u32,
+
+    /// The local APIC of this processor, created on the first use.
+    pub local_apic: Once<IrqSafeMutex<LocalApic>>,
+
+    /// The page table loaded on this processor, or null before the first load.
+    pub page_table: Cell<*const PageTableWrapper>,
+
+    /// The locks held by this processor, for lock debugging.
+    #[cfg(feature = "lock-debug")]
+    pub held_locks: HeldLocks,
+}
+
+// The block is only accessed by its own processor, except for the read-only fields.
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    pub fn is_bsp(&self) -> bool {
+        self.id == 0
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_BLOCK: AtomicPtr<PerCpu> = AtomicPtr::new(core::ptr::null_mut());
+
+/// The data blocks of all of the processors, indexed by their ids.
+static BLOCKS: [AtomicPtr<PerCpu>; MAX_CPUS] = [NO_BLOCK; MAX_CPUS];
+
+/// Get the data block of the processor `id`, if it's initialized.
+pub fn get(id: usize) -> Option<&'static PerCpu> {
+    let block = BLOCKS.get(id)?.load(Ordering::Acquire);
+    unsafe { block.as_ref() }
+}
+
+/// Get the data block of the current processor.
+pub fn current() -> &'static PerCpu {
+    let this: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) this, options(nostack, preserves_flags, readonly));
+        &*this
+    }
+}
+
+/// Get the data block of the current processor, or `None` if it's not initialized yet.
+#[allow(dead_code)]
+pub fn try_current() -> Option<&'static PerCpu> {
+    (!GsBase::read().is_null()).then(current)
+}
+
+/// Allocate the data block for the current processor and make it reachable via the GS base.
+pub fn init(id: usize, apic_id: u32) {
+    assert!(id < MAX_CPUS, "too many processors");
+
+    let block = Box::leak(Box::new(PerCpu {
+        this: core::ptr::null(),
+        id,
+        apic_id,
+        local_apic: Once::new(),
+        page_table: Cell::new(core::ptr::null()),
+        #[cfg(feature = "lock-debug")]
+        held_locks: HeldLocks::default(),
+    }));
+    block.this = block;
+    BLOCKS[id].store(block, Ordering::Release);
+
+    GsBase::write(VirtAddr::from_ptr(block));
+    KernelGsBase::write(VirtAddr::zero()); // for users
+
+    info!("initialized per-cpu block for cpu {} at {:p}", id, block);
+}
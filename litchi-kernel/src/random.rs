@@ -0,0 +1,53 @@
+use core::arch::x86_64::_rdtsc;
+
+use lazy_static::lazy_static;
+use log::info;
+use x86_64::instructions::random::RdRand;
+
+use crate::sync::IrqSafeMutex;
+
+/// A xorshift* generator for randomizing the memory layout. It's fast but NOT cryptographically
+/// secure.
+struct Xorshift(u64);
+
+impl Xorshift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+lazy_static! {
+    static ref RNG: IrqSafeMutex<Xorshift> = IrqSafeMutex::new("random", Xorshift(seed()));
+}
+
+/// Seed with `RDRAND` if supported, or fall back to the time stamp counter.
+fn seed() -> u64 {
+    let seed = RdRand::new()
+        .and_then(RdRand::get_u64)
+        .unwrap_or_else(|| unsafe { _rdtsc() });
+
+    // The state of xorshift must not be zero.
+    seed | 1
+}
+
+/// Get a random `u64`.
+pub fn next_u64() -> u64 {
+    RNG.lock().next()
+}
+
+/// Get a random number in `[0, bound)`. The bias is negligible for small bounds.
+pub fn below(bound: u64) -> u64 {
+    next_u64() % bound
+}
+
+pub fn init() {
+    lazy_static::initialize(&RNG);
+
+    info!(
+        "random number generator initialized, rdrand supported: {}",
+        RdRand::new().is_some()
+    );
+}
@@ -1,3 +1,5 @@
+pub mod crash_log;
+mod kmsg;
 mod term;
 
 use alloc::boxed::Box;
@@ -7,6 +9,8 @@ use alloc::vec::Vec;
 use async_trait::async_trait;
 use litchi_user_common::resource::{ResourceError, ResourceResult};
 
+use self::crash_log::CrashLog;
+use self::kmsg::Kmsg;
 use self::term::Term;
 
 pub type BoxedResource = Box<dyn Resource>;
@@ -28,6 +32,8 @@ pub trait Resource: Send + Sync + core::fmt::Debug {
 pub fn open(path: String) -> ResourceResult<BoxedResource> {
     let res = match path.as_str() {
         "/device/term" => Term::new().boxed(),
+        "/device/crash-log" => CrashLog::new().boxed(),
+        "/device/kmsg" => Kmsg::new().boxed(),
         _ => return Err(ResourceError::NotSupported),
     };
 
@@ -0,0 +1,83 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use async_trait::async_trait;
+use litchi_user_common::resource::{ResourceError, ResourceResult};
+use spin::Mutex;
+
+use super::Resource;
+use crate::sync::IrqSafeMutex;
+
+/// Only the latest reports are kept within this size.
+const CRASH_LOG_CAPACITY: usize = 64 * 1024;
+
+struct Log {
+    data: Vec<u8>,
+
+    /// The offset of the first byte in `data` since boot, which grows as old reports are dropped.
+    start: usize,
+}
+
+static LOG: IrqSafeMutex<Log> = IrqSafeMutex::new(
+    "crash log",
+    Log {
+        data: Vec::new(),
+        start: 0,
+    },
+);
+
+/// Append the crash report to the log, dropping the oldest bytes if it's full.
+pub fn append(report: &str) {
+    let mut log = LOG.lock();
+    log.data.extend_from_slice(report.as_bytes());
+    if !report.ends_with('\n') {
+        log.data.push(b'\n');
+    }
+
+    let excess = log.data.len().saturating_sub(CRASH_LOG_CAPACITY);
+    log.data.drain(..excess);
+    log.start += excess;
+}
+
+/// The crash reports of the killed user tasks. Each reader starts from the oldest report kept, and
+/// reads nothing at the end.
+pub struct CrashLog {
+    /// The offset to read next since boot.
+    offset: Mutex<usize>,
+}
+
+impl CrashLog {
+    pub fn new() -> Self {
+        Self {
+            offset: Mutex::new(0),
+        }
+    }
+}
+
+impl core::fmt::Debug for CrashLog {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("CrashLog")
+            .field("offset", &*self.offset.lock())
+            .finish()
+    }
+}
+
+#[async_trait]
+impl Resource for CrashLog {
+    async fn read(&self, max_len: usize) -> ResourceResult<Vec<u8>> {
+        let mut offset = self.offset.lock();
+        let log = LOG.lock();
+
+        // Skip the bytes that have been dropped.
+        let start = offset.saturating_sub(log.start);
+        let end = log.data.len().min(start + max_len);
+        let read = log.data[start..end].to_vec();
+
+        *offset = log.start + end;
+        Ok(read)
+    }
+
+    async fn write(&self, _data: &[u8]) -> ResourceResult<usize> {
+        Err(ResourceError::NotSupported)
+    }
+}
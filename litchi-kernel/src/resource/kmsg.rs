@@ -0,0 +1,46 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use async_trait::async_trait;
+use litchi_user_common::resource::{ResourceError, ResourceResult};
+use spin::Mutex;
+
+use super::Resource;
+use crate::serial_log;
+
+/// The messages in the kernel log ring. Each reader starts from the oldest message kept, and reads
+/// nothing at the end.
+pub struct Kmsg {
+    /// The offset to read next since boot.
+    offset: Mutex<usize>,
+}
+
+impl Kmsg {
+    pub fn new() -> Self {
+        Self {
+            offset: Mutex::new(0),
+        }
+    }
+}
+
+impl core::fmt::Debug for Kmsg {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Kmsg")
+            .field("offset", &*self.offset.lock())
+            .finish()
+    }
+}
+
+#[async_trait]
+impl Resource for Kmsg {
+    async fn read(&self, max_len: usize) -> ResourceResult<Vec<u8>> {
+        let mut offset = self.offset.lock();
+        let (read, next) = serial_log::read_kmsg(*offset, max_len);
+        *offset = next;
+        Ok(read)
+    }
+
+    async fn write(&self, _data: &[u8]) -> ResourceResult<usize> {
+        Err(ResourceError::NotSupported)
+    }
+}
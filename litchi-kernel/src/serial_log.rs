@@ -1,29 +1,47 @@
+mod filter;
+mod ring;
+
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
 use lazy_static::lazy_static;
-use spin::Mutex;
+use litchi_user_common::log::LogResult;
+use log::{info, warn, LevelFilter};
 use uart_16550::SerialPort;
-use x86_64::instructions;
+use x86_64::instructions::port::Port;
+
+use self::filter::Filter;
+use self::ring::LogRing;
+use crate::kernel_task::time;
+use crate::sync::IrqSafeMutex;
 
 lazy_static! {
-    pub static ref DEBUG_SERIAL: Mutex<SerialPort> = Mutex::new(new_debug_serial());
+    pub static ref DEBUG_SERIAL: IrqSafeMutex<SerialPort> =
+        IrqSafeMutex::new("debug serial", new_debug_serial());
 }
 
+const COM1: u16 = 0x3f8;
+
 fn new_debug_serial() -> SerialPort {
-    let base = 0x3f8; // COM1
-    let mut serial_port = unsafe { SerialPort::new(base) };
+    let mut serial_port = unsafe { SerialPort::new(COM1) };
     serial_port.init();
 
     serial_port
 }
 
+/// Receive a byte from the debug serial without blocking, by checking the data ready bit of the
+/// line status first.
+pub fn try_receive() -> Option<u8> {
+    let mut serial = DEBUG_SERIAL.lock();
+    let line_status = unsafe { Port::<u8>::new(COM1 + 5).read() };
+    (line_status & 1 != 0).then(|| serial.receive())
+}
+
 pub fn _print(args: ::core::fmt::Arguments) {
-    use core::fmt::Write;
-
-    instructions::interrupts::without_interrupts(|| {
-        DEBUG_SERIAL
-            .lock()
-            .write_fmt(args)
-            .expect("printing to debug serial failed")
-    })
+    DEBUG_SERIAL
+        .lock()
+        .write_fmt(args)
+        .expect("printing to debug serial failed")
 }
 
 /// Prints to the host through the serial interface.
@@ -42,24 +60,55 @@ macro_rules! println {
     ($fmt:expr, $($arg:tt)*) => ($crate::print!(concat!($fmt, "\n"), $($arg)*));
 }
 
+/// The log filter set at build time with the `LITCHI_LOG` variable, like `info,task=debug`.
+const BOOT_FILTER: Option<&str> = option_env!("LITCHI_LOG");
+
+static FILTER: IrqSafeMutex<Filter> =
+    IrqSafeMutex::new("log filter", Filter::new(LevelFilter::Info));
+
+/// The latest kernel messages, read by `/device/kmsg`.
+static KMSG: IrqSafeMutex<LogRing> = IrqSafeMutex::new("kernel log", LogRing::new());
+
+/// A log record formatted as a line, with the timestamp in timer slices since boot.
+struct Line<'a, 'b> {
+    slice: u64,
+
+    record: &'a log::Record<'b>,
+}
+
+impl fmt::Display for Line<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{:>8}] <{:>5}>: {:>12}:{:03}: {}",
+            self.slice,
+            self.record.level(),
+            self.record.file().unwrap_or("?"),
+            self.record.line().unwrap_or(0),
+            self.record.args()
+        )
+    }
+}
+
+/// Writes the records to the debug serial and the kernel log ring.
 struct SerialLogger;
 
 static LOGGER: SerialLogger = SerialLogger;
 
 impl log::Log for SerialLogger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= FILTER.lock().level(metadata.target())
     }
 
     fn log(&self, record: &log::Record) {
         if self.enabled(record.metadata()) {
-            println!(
-                "<{:>5}>: {:>12}:{:03}: {}",
-                record.metadata().level(),
-                record.file().unwrap_or("?"),
-                record.line().unwrap_or(0),
-                record.args()
-            )
+            let line = Line {
+                slice: time::slices(),
+                record,
+            };
+            // Writing to the ring never fails.
+            let _ = writeln!(KMSG.lock(), "{}", line);
+            println!("{}", line);
         }
     }
 
@@ -68,5 +117,30 @@ impl log::Log for SerialLogger {
 
 pub fn init() {
     log::set_logger(&LOGGER).expect("failed to init serial logger");
-    log::set_max_level(log::LevelFilter::Info);
+    log::set_max_level(LevelFilter::Info);
+}
+
+/// Apply the log filter given at build time. Parsing the module directives needs the heap.
+pub fn init_filter() {
+    if let Some(spec) = BOOT_FILTER.filter(|spec| !spec.is_empty()) {
+        if let Err(err) = set_filter(spec) {
+            warn!("invalid boot log filter `{}`: {}", spec, err);
+        }
+    }
+}
+
+/// Replace the log filter at runtime, like `info,task=debug`.
+pub fn set_filter(spec: &str) -> LogResult<()> {
+    let filter = Filter::parse(spec)?;
+    info!("set log filter to `{}`", filter);
+
+    log::set_max_level(filter.max_level());
+    *FILTER.lock() = filter;
+    Ok(())
+}
+
+/// Read at most `max_len` bytes of the kernel log from `offset` since boot, and return them with
+/// the offset to read next.
+pub fn read_kmsg(offset: usize, max_len: usize) -> (Vec<u8>, usize) {
+    KMSG.lock().read(offset, max_len)
 }
@@ -0,0 +1,85 @@
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+
+use litchi_user_common::log::{LogError, LogResult};
+use log::LevelFilter;
+
+/// The log levels of modules, like `info,task=debug,memory::tlb=trace`. A module directive applies
+/// to its submodules as well, and the longest matching one wins. The paths of the kernel modules
+/// are relative to the crate, while the ones of other crates are written in full.
+#[derive(Debug)]
+pub struct Filter {
+    default: LevelFilter,
+
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    pub const fn new(default: LevelFilter) -> Self {
+        Self {
+            default,
+            modules: Vec::new(),
+        }
+    }
+
+    /// Parse the comma-separated directives, each of which is either `level` for the default or
+    /// `module=level`. The default level is `info` if not given.
+    pub fn parse(spec: &str) -> LogResult<Self> {
+        let mut filter = Self::new(LevelFilter::Info);
+
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    let module = module.trim().to_owned();
+                    filter.modules.push((module, parse_level(level)?));
+                }
+                None => filter.default = parse_level(directive)?,
+            }
+        }
+        Ok(filter)
+    }
+
+    /// The level of the module path given by the log target.
+    pub fn level(&self, target: &str) -> LevelFilter {
+        let target = target
+            .strip_prefix(concat!(env!("CARGO_CRATE_NAME"), "::"))
+            .unwrap_or(target);
+
+        self.modules
+            .iter()
+            .filter(|(module, _)| is_within(target, module))
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// The most verbose level of all, to skip the records early.
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.default)?;
+        for (module, level) in &self.modules {
+            write!(f, ",{}={}", module, level)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_level(level: &str) -> LogResult<LevelFilter> {
+    LevelFilter::from_str(level.trim()).map_err(|_| LogError::InvalidLevel)
+}
+
+/// Whether the module path is the module or one of its submodules.
+fn is_within(path: &str, module: &str) -> bool {
+    path.strip_prefix(module)
+        .map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
+}
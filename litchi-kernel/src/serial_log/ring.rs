@@ -0,0 +1,57 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Only the latest messages are kept within this size.
+const LOG_RING_CAPACITY: usize = 64 * 1024;
+
+/// A ring buffer of the formatted log messages. It never allocates, so that it works before the
+/// heap is initialized and for the logs of the allocator itself.
+pub struct LogRing {
+    data: [u8; LOG_RING_CAPACITY],
+
+    /// The number of bytes written since boot. The ones before the last `LOG_RING_CAPACITY` bytes
+    /// have been overwritten.
+    end: usize,
+}
+
+impl LogRing {
+    pub const fn new() -> Self {
+        Self {
+            data: [0; LOG_RING_CAPACITY],
+            end: 0,
+        }
+    }
+
+    fn start(&self) -> usize {
+        self.end.saturating_sub(LOG_RING_CAPACITY)
+    }
+
+    /// Read at most `max_len` bytes from `offset` since boot, and return them with the offset to
+    /// read next. If the bytes at `offset` have been overwritten, it starts from the first whole
+    /// line kept instead.
+    pub fn read(&self, offset: usize, max_len: usize) -> (Vec<u8>, usize) {
+        let start = if offset < self.start() {
+            (self.start()..self.end)
+                .find(|&i| self.data[i % LOG_RING_CAPACITY] == b'\n')
+                .map_or(self.end, |i| i + 1)
+        } else {
+            offset.min(self.end)
+        };
+        let end = self.end.min(start + max_len);
+
+        let read = (start..end)
+            .map(|i| self.data[i % LOG_RING_CAPACITY])
+            .collect();
+        (read, end)
+    }
+}
+
+impl fmt::Write for LogRing {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.data[self.end % LOG_RING_CAPACITY] = byte;
+            self.end += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use lazy_static::lazy_static;
+use litchi_user_common::memory::{MemoryError, MemoryResult};
+use log::info;
+use x86_64::structures::paging::{PageSize, PhysFrame, Size4KiB};
+
+use crate::frame_allocator::{self, RaiiFrameAllocator};
+use crate::sync::IrqSafeMutex;
+
+/// A named region of physical memory, which can be mapped by multiple tasks.
+pub struct SharedMemory {
+    name: String,
+
+    frames: Vec<PhysFrame>,
+
+    /// Owns the frames. Since both the registry and the mappings hold a reference to the region,
+    /// the frames will be deallocated only after it's unlinked and unmapped by all of the tasks.
+    _allocator: RaiiFrameAllocator,
+}
+
+impl core::fmt::Debug for SharedMemory {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SharedMemory")
+            .field("name", &self.name)
+            .field("pages", &self.frames.len())
+            .finish()
+    }
+}
+
+impl SharedMemory {
+    fn new(name: String, pages: u64) -> MemoryResult<Self> {
+        let mut allocator = RaiiFrameAllocator::new_traced();
+
+        let frames = (0..pages)
+            .map(|_| allocator.allocate_zeroed_frame())
+            .collect::<Option<Vec<_>>>()
+            .ok_or(MemoryError::NoMemory)?;
+
+        Ok(Self {
+            name,
+            frames,
+            _allocator: allocator,
+        })
+    }
+
+    pub fn frames(&self) -> &[PhysFrame] {
+        &self.frames
+    }
+
+    pub fn size(&self) -> u64 {
+        self.frames.len() as u64 * Size4KiB::SIZE
+    }
+}
+
+lazy_static! {
+    static ref REGISTRY: IrqSafeMutex<BTreeMap<String, Arc<SharedMemory>>> =
+        IrqSafeMutex::new("shared memory registry", BTreeMap::new());
+}
+
+fn with_registry<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut BTreeMap<String, Arc<SharedMemory>>) -> R,
+{
+    f(&mut *REGISTRY.lock())
+}
+
+/// Create a zeroed shared memory region of at least `len` bytes named `name`.
+pub fn create(name: String, len: usize) -> MemoryResult<()> {
+    if len == 0 {
+        return Err(MemoryError::InvalidArgument);
+    }
+    let pages = (len as u64).div_ceil(Size4KiB::SIZE);
+    if pages > frame_allocator::stats().free_frames {
+        return Err(MemoryError::NoMemory);
+    }
+    if with_registry(|registry| registry.contains_key(&name)) {
+        return Err(MemoryError::AlreadyExists);
+    }
+
+    // Allocate the frames without the lock held, and check again on inserting.
+    let shm = Arc::new(SharedMemory::new(name.clone(), pages)?);
+    with_registry(|registry| {
+        if registry.contains_key(&name) {
+            return Err(MemoryError::AlreadyExists);
+        }
+        info!("created {:?}", shm);
+        registry.insert(name, shm);
+        Ok(())
+    })
+}
+
+pub fn get(name: &str) -> MemoryResult<Arc<SharedMemory>> {
+    with_registry(|registry| registry.get(name).cloned()).ok_or(MemoryError::NotExists)
+}
+
+/// Remove the name of the region. The existing mappings are still valid.
+pub fn unlink(name: &str) -> MemoryResult<()> {
+    let shm = with_registry(|registry| registry.remove(name)).ok_or(MemoryError::NotExists)?;
+    info!(
+        "unlinked {:?}, still mapped by {} tasks",
+        shm,
+        Arc::strong_count(&shm) - 1
+    );
+    Ok(())
+}
@@ -0,0 +1,245 @@
+// https://wiki.osdev.org/SMP
+// https://wiki.osdev.org/Symmetric_Multiprocessing
+
+use alloc::boxed::Box;
+use alloc::format;
+use core::arch::global_asm;
+use core::ptr::addr_of;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+
+use acpi::platform::ProcessorState;
+use log::{info, warn};
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::PhysAddr;
+
+use crate::acpi::ACPI;
+use crate::frame_allocator::LOW_MEMORY_END;
+use crate::interrupt::with_local_apic;
+use crate::memory::{phys_to_virt, KERNEL_PAGE_TABLE};
+use crate::percpu::MAX_CPUS;
+use crate::task::with_task_manager;
+use crate::{fpu, gdt, interrupt, percpu, stack, task};
+
+/// The physical address where the trampoline is copied to. The SIPI vector is its page number.
+const TRAMPOLINE_ADDR: u64 = 0x8000;
+
+/// The physical address of the level-4 table loaded by the trampoline, since only 32 bits of the
+/// `cr3` can be set before entering the long mode.
+const TRAMPOLINE_PML4_ADDR: u64 = 0x9000;
+
+const _: () = assert!(TRAMPOLINE_PML4_ADDR + 4096 <= LOW_MEMORY_END);
+
+const AP_STACK_PAGES: u64 = 20;
+
+/// The time to wait for an application processor to be online, in microseconds.
+const AP_TIMEOUT_US: u64 = 1_000_000;
+
+// The application processors start in real mode at `TRAMPOLINE_ADDR`. The trampoline switches to
+// the long mode directly with the kernel page table, where the low memory is identity-mapped, then
+// calls `ap_main` on the given stack. All of the addresses are relative to `TRAMPOLINE_ADDR`
+// since it's copied there from the kernel image.
+global_asm!(
+    r#"
+    .pushsection .text.ap_trampoline, "ax"
+    .global ap_trampoline_start
+    .global ap_trampoline_args
+    .global ap_trampoline_end
+
+    .code16
+ap_trampoline_start:
+    cli
+    cld
+    xorw    %ax, %ax
+    movw    %ax, %ds
+
+    lgdtl   ap_gdt_pointer - ap_trampoline_start + {base}
+
+    movl    %cr4, %eax
+    orl     $0x20, %eax                 # PAE
+    movl    %eax, %cr4
+
+    movl    ap_trampoline_args - ap_trampoline_start + {base}, %eax
+    movl    %eax, %cr3
+
+    movl    $0xc0000080, %ecx           # EFER
+    rdmsr
+    orl     $0x900, %eax                # LME | NXE
+    wrmsr
+
+    movl    %cr0, %eax
+    orl     $0x80010001, %eax           # PG | WP | PE
+    movl    %eax, %cr0
+
+    ljmpl   $0x8, $ap_long_mode - ap_trampoline_start + {base}
+
+    .code64
+ap_long_mode:
+    movw    $0x10, %ax
+    movw    %ax, %ds
+    movw    %ax, %es
+    movw    %ax, %ss
+    xorw    %ax, %ax
+    movw    %ax, %fs
+    movw    %ax, %gs
+
+    movq    ap_trampoline_args + 8 - ap_trampoline_start + {base}, %rsp
+    movq    ap_trampoline_args + 24 - ap_trampoline_start + {base}, %rdi
+    movq    ap_trampoline_args + 32 - ap_trampoline_start + {base}, %rsi
+    movq    ap_trampoline_args + 16 - ap_trampoline_start + {base}, %rax
+    callq   *%rax
+    ud2
+
+    .align 8
+ap_gdt:
+    .quad   0
+    .quad   0x00af9a000000ffff          # 64-bit code
+    .quad   0x00cf92000000ffff          # data
+ap_gdt_pointer:
+    .word   ap_gdt_pointer - ap_gdt - 1
+    .long   ap_gdt - ap_trampoline_start + {base}
+
+    .align 8
+ap_trampoline_args:
+    .fill   5, 8, 0                     # [`TrampolineArgs`]
+ap_trampoline_end:
+    .popsection
+    "#,
+    base = const TRAMPOLINE_ADDR,
+    options(att_syntax)
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_args: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// The arguments for the trampoline, the layout must be consistent with the assembly.
+#[repr(C)]
+struct TrampolineArgs {
+    pml4: u64,
+    stack_top: u64,
+    entry: u64,
+    cpu_id: u64,
+    apic_id: u64,
+}
+
+/// The number of processors online, including the bootstrap one.
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::Acquire)
+}
+
+/// Copy the trampoline and the kernel level-4 table to the low memory.
+fn install_trampoline() {
+    unsafe {
+        let start = addr_of!(ap_trampoline_start);
+        let len = addr_of!(ap_trampoline_end) as usize - start as usize;
+        assert!(TRAMPOLINE_ADDR + len as u64 <= TRAMPOLINE_PML4_ADDR);
+
+        let dest = phys_to_virt(PhysAddr::new(TRAMPOLINE_ADDR)).as_mut_ptr::<u8>();
+        core::ptr::copy_nonoverlapping(start, dest, len);
+
+        KERNEL_PAGE_TABLE.copy_l4_to(PhysFrame::containing_address(PhysAddr::new(
+            TRAMPOLINE_PML4_ADDR,
+        )));
+    }
+}
+
+fn write_trampoline_args(args: TrampolineArgs) {
+    unsafe {
+        let offset = addr_of!(ap_trampoline_args) as u64 - addr_of!(ap_trampoline_start) as u64;
+        let ptr = phys_to_virt(PhysAddr::new(TRAMPOLINE_ADDR + offset)).as_mut_ptr();
+        core::ptr::write_volatile(ptr, args);
+    }
+    fence(Ordering::SeqCst);
+}
+
+/// Wait for about `us` microseconds, by writing to the unused POST port.
+fn delay_us(us: u64) {
+    let mut port = Port::<u8>::new(0x80);
+    for _ in 0..us {
+        unsafe { port.write(0) };
+    }
+}
+
+/// Start the application processor with INIT-SIPI-SIPI, and wait for it to be online.
+fn start_ap(cpu_id: usize, apic_id: u32) -> bool {
+    let name = Box::leak(format!("boot on cpu {}", cpu_id).into_boxed_str());
+    let stack_top = stack::allocate(name, AP_STACK_PAGES);
+
+    write_trampoline_args(TrampolineArgs {
+        pml4: TRAMPOLINE_PML4_ADDR,
+        stack_top: stack_top.as_u64(),
+        entry: ap_main as usize as u64,
+        cpu_id: cpu_id as u64,
+        apic_id: apic_id as u64,
+    });
+
+    let vector = (TRAMPOLINE_ADDR >> 12) as u8;
+    with_local_apic(|lapic| unsafe { lapic.send_init_ipi(apic_id) });
+    delay_us(10_000);
+    for _ in 0..2 {
+        if online_cpus() > cpu_id {
+            break;
+        }
+        with_local_apic(|lapic| unsafe { lapic.send_sipi(vector, apic_id) });
+        delay_us(200);
+    }
+
+    for _ in 0..AP_TIMEOUT_US {
+        if online_cpus() > cpu_id {
+            return true;
+        }
+        delay_us(1);
+    }
+    false
+}
+
+extern "C" fn ap_main(cpu_id: u64, apic_id: u64) -> ! {
+    let cpu_id = cpu_id as usize;
+
+    percpu::init(cpu_id, apic_id as u32);
+    KERNEL_PAGE_TABLE.load();
+    gdt::init_ap(cpu_id);
+    fpu::init();
+    interrupt::init_ap();
+
+    with_task_manager(|tm| tm.add_idle(cpu_id));
+
+    info!("cpu {} with apic id {} is online", cpu_id, apic_id);
+    ONLINE_CPUS.fetch_add(1, Ordering::Release);
+
+    task::run();
+}
+
+/// Start all of the application processors one by one. They'll run tasks right after started.
+pub fn init() {
+    install_trampoline();
+
+    let processors = ACPI
+        .processor_info
+        .applications
+        .iter()
+        .filter(|processor| matches!(processor.state, ProcessorState::WaitingForSipi));
+
+    for (i, processor) in processors.enumerate() {
+        let cpu_id = i + 1;
+        if cpu_id >= MAX_CPUS {
+            warn!("too many processors, ignore the rest");
+            break;
+        }
+
+        if !start_ap(cpu_id, processor.local_apic_id) {
+            warn!(
+                "cpu {} with apic id {} failed to start",
+                cpu_id, processor.local_apic_id
+            );
+            break;
+        }
+    }
+
+    info!("{} cpus online", online_cpus());
+}
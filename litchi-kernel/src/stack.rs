@@ -0,0 +1,99 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use log::info;
+use x86_64::structures::paging::{Page, PageSize, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::memory::KERNEL_PAGE_TABLE;
+use crate::sync::IrqSafeMutex;
+use crate::BOOT_INFO;
+
+/// The area where the kernel stacks are allocated. Each stack takes a slot, and the page at the
+/// bottom of the slot is left unmapped as the guard.
+const STACKS_BASE: VirtAddr = VirtAddr::new_truncate(0xffff_fe00_0000_0000);
+const SLOT_SIZE: u64 = 1024 * 1024; // 1 MiB
+
+const MAX_STACKS: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+struct GuardedStack {
+    name: &'static str,
+
+    /// The unmapped page right below the stack.
+    guard: Page,
+}
+
+static STACKS: IrqSafeMutex<[Option<GuardedStack>; MAX_STACKS]> =
+    IrqSafeMutex::new("kernel stacks", [None; MAX_STACKS]);
+
+static NEXT_SLOT: AtomicU64 = AtomicU64::new(0);
+
+fn with_stacks<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut [Option<GuardedStack>; MAX_STACKS]) -> R,
+{
+    f(&mut *STACKS.lock())
+}
+
+fn register(stack: GuardedStack) {
+    with_stacks(|stacks| {
+        let entry = stacks
+            .iter_mut()
+            .find(|entry| entry.is_none())
+            .expect("too many kernel stacks");
+        *entry = Some(stack);
+    })
+}
+
+/// Allocate a kernel stack of `pages` with an unmapped guard page below it. Returns the stack top.
+pub fn allocate(name: &'static str, pages: u64) -> VirtAddr {
+    assert!((pages + 1) * Size4KiB::SIZE <= SLOT_SIZE, "stack too large");
+
+    let slot = NEXT_SLOT.fetch_add(1, Ordering::SeqCst);
+    let guard = Page::containing_address(STACKS_BASE + slot * SLOT_SIZE);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    for page in Page::range(guard + 1, guard + 1 + pages) {
+        unsafe {
+            KERNEL_PAGE_TABLE
+                .allocate_and_map_to(page, flags)
+                .expect("failed to allocate kernel stack");
+        }
+    }
+
+    let top = (guard + 1 + pages).start_address();
+    register(GuardedStack { name, guard });
+    info!(
+        "allocated stack for {} at {:?}, top {:?}",
+        name,
+        guard + 1,
+        top
+    );
+
+    top
+}
+
+/// Find the stack whose guard page contains `addr`, and return its name.
+pub fn guard_owner(addr: VirtAddr) -> Option<&'static str> {
+    let page = Page::containing_address(addr);
+
+    with_stacks(|stacks| {
+        stacks
+            .iter()
+            .flatten()
+            .find(|stack| stack.guard == page)
+            .map(|stack| stack.name)
+    })
+}
+
+/// Register the kernel stack set up by the bootloader, which is used until the first task runs.
+pub fn init() {
+    let boot_info = BOOT_INFO.get().unwrap();
+    let guard =
+        Page::containing_address(boot_info.kernel_stack_top) - (boot_info.kernel_stack_pages + 1);
+
+    register(GuardedStack {
+        name: "kernel",
+        guard,
+    });
+}
@@ -0,0 +1,106 @@
+#[cfg(feature = "lock-debug")]
+mod lock_debug;
+
+use core::fmt::Debug;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+#[cfg(feature = "lock-debug")]
+pub use self::lock_debug::HeldLocks;
+
+/// A spin lock that disables the interrupts while it's held, so that it can be shared with the
+/// interrupt handlers without deadlocking the processor. The name identifies the lock in the
+/// reports of the lock debugging mode, enabled by the `lock-debug` feature.
+pub struct IrqSafeMutex<T: ?Sized> {
+    name: &'static str,
+
+    #[cfg(feature = "lock-debug")]
+    state: lock_debug::LockState,
+
+    inner: Mutex<T>,
+}
+
+impl<T> IrqSafeMutex<T> {
+    pub const fn new(name: &'static str, value: T) -> Self {
+        Self {
+            name,
+            #[cfg(feature = "lock-debug")]
+            state: lock_debug::LockState::new(),
+            inner: Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqSafeMutex<T> {
+    /// Disable the interrupts and spin until the lock is acquired. The interrupts are restored when
+    /// the guard is dropped, so the guards should be dropped in the reverse order.
+    pub fn lock(&self) -> IrqSafeMutexGuard<T> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        #[cfg(feature = "lock-debug")]
+        let guard = lock_debug::lock(self.name, &self.state, &self.inner);
+        #[cfg(not(feature = "lock-debug"))]
+        let guard = self.inner.lock();
+
+        IrqSafeMutexGuard {
+            #[cfg(feature = "lock-debug")]
+            mutex: self,
+            guard: ManuallyDrop::new(guard),
+            interrupts_enabled,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl<T: ?Sized> Debug for IrqSafeMutex<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("IrqSafeMutex")
+            .field("name", &self.name)
+            .field("locked", &self.inner.is_locked())
+            .finish()
+    }
+}
+
+pub struct IrqSafeMutexGuard<'a, T: ?Sized> {
+    #[cfg(feature = "lock-debug")]
+    mutex: &'a IrqSafeMutex<T>,
+
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+
+    /// Whether the interrupts were enabled before locking.
+    interrupts_enabled: bool,
+}
+
+impl<T: ?Sized> Deref for IrqSafeMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSafeMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSafeMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "lock-debug")]
+        lock_debug::unlock(&self.mutex.state);
+
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
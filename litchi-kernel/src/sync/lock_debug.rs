@@ -0,0 +1,199 @@
+use core::cell::Cell;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use log::warn;
+use spin::{Mutex, MutexGuard};
+
+use crate::percpu::{self, PerCpu};
+
+/// The maximum number of distinct lock names.
+const MAX_CLASSES: usize = 64;
+
+/// The maximum number of locks held by a processor at the same time.
+const MAX_HELD_LOCKS: usize = 16;
+
+const NO_OWNER: usize = usize::MAX;
+const NO_CLASS: usize = usize::MAX;
+
+/// Report a possible deadlock after failing to acquire a lock this many times.
+const SPINS_BEFORE_REPORT: u64 = 1 << 28;
+
+/// The owner and the class of a lock. The locks with the same name belong to the same class, whose
+/// acquisition order is tracked.
+#[derive(Debug)]
+pub struct LockState {
+    /// The id of the processor holding the lock.
+    owner: AtomicUsize,
+
+    class: AtomicUsize,
+}
+
+impl LockState {
+    pub const fn new() -> Self {
+        Self {
+            owner: AtomicUsize::new(NO_OWNER),
+            class: AtomicUsize::new(NO_CLASS),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct HeldLock {
+    state: *const LockState,
+    class: usize,
+}
+
+/// The locks held by a processor, in the order of acquisition.
+#[derive(Debug, Default)]
+pub struct HeldLocks {
+    locks: [Cell<Option<HeldLock>>; MAX_HELD_LOCKS],
+    len: Cell<usize>,
+}
+
+impl HeldLocks {
+    fn iter(&self) -> impl Iterator<Item = HeldLock> + '_ {
+        self.locks[..self.len.get()].iter().filter_map(Cell::get)
+    }
+
+    fn push(&self, lock: HeldLock) {
+        let len = self.len.get();
+        assert!(len < MAX_HELD_LOCKS, "too many locks held");
+        self.locks[len].set(Some(lock));
+        self.len.set(len + 1);
+    }
+
+    fn remove(&self, state: *const LockState) {
+        let len = self.len.get();
+        let position = self.locks[..len]
+            .iter()
+            .rposition(|lock| matches!(lock.get(), Some(lock) if lock.state == state));
+
+        // The lock may be acquired before the per-cpu block is initialized.
+        if let Some(index) = position {
+            for i in index..len - 1 {
+                self.locks[i].set(self.locks[i + 1].get());
+            }
+            self.locks[len - 1].set(None);
+            self.len.set(len - 1);
+        }
+    }
+}
+
+/// The names of the lock classes, and the number of them.
+static CLASSES: Mutex<([&str; MAX_CLASSES], usize)> = Mutex::new(([""; MAX_CLASSES], 0));
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_ORDER: AtomicU64 = AtomicU64::new(0);
+
+/// The bit `b` of `ORDER[a]` is set if the class `b` has been acquired while holding `a`.
+static ORDER: [AtomicU64; MAX_CLASSES] = [NO_ORDER; MAX_CLASSES];
+
+/// The pairs that have been reported, in the same layout as `ORDER`.
+static REPORTED: [AtomicU64; MAX_CLASSES] = [NO_ORDER; MAX_CLASSES];
+
+fn class_of(name: &'static str, state: &LockState) -> usize {
+    let class = state.class.load(Ordering::Relaxed);
+    if class != NO_CLASS {
+        return class;
+    }
+
+    let mut classes = CLASSES.lock();
+    let (names, len) = &mut *classes;
+    let class = match names[..*len].iter().position(|&other| other == name) {
+        Some(class) => class,
+        None => {
+            assert!(*len < MAX_CLASSES, "too many lock classes");
+            names[*len] = name;
+            *len += 1;
+            *len - 1
+        }
+    };
+    state.class.store(class, Ordering::Relaxed);
+    class
+}
+
+fn name_of(class: usize) -> &'static str {
+    CLASSES.lock().0[class]
+}
+
+/// Returns `true` for the first time the pair is seen.
+fn first_report(held: usize, class: usize) -> bool {
+    REPORTED[held].fetch_or(1 << class, Ordering::SeqCst) & (1 << class) == 0
+}
+
+/// Record the order against the locks held by the processor, and report the inversions.
+fn check_order(cpu: &PerCpu, class: usize) {
+    for held in cpu.held_locks.iter() {
+        if held.class == class {
+            if first_report(class, class) {
+                warn!(
+                    "nested locking of \"{}\" on cpu {}, whose order is undefined",
+                    name_of(class),
+                    cpu.id
+                );
+            }
+            continue;
+        }
+
+        if ORDER[class].load(Ordering::SeqCst) & (1 << held.class) != 0
+            && first_report(held.class, class)
+        {
+            warn!(
+                "lock order inversion on cpu {}: \"{}\" acquired inside \"{}\", and reversely before",
+                cpu.id,
+                name_of(class),
+                name_of(held.class)
+            );
+        }
+        ORDER[held.class].fetch_or(1 << class, Ordering::SeqCst);
+    }
+}
+
+pub fn lock<'a, T: ?Sized>(
+    name: &'static str,
+    state: &LockState,
+    inner: &'a Mutex<T>,
+) -> MutexGuard<'a, T> {
+    // Nothing can be recorded before the per-cpu block is initialized.
+    let Some(cpu) = percpu::try_current() else {
+        return inner.lock();
+    };
+
+    if state.owner.load(Ordering::SeqCst) == cpu.id {
+        panic!("recursive locking of \"{}\" on cpu {}", name, cpu.id);
+    }
+
+    let class = class_of(name, state);
+    check_order(cpu, class);
+
+    let mut spins = 0;
+    let guard = loop {
+        if let Some(guard) = inner.try_lock() {
+            break guard;
+        }
+
+        spins += 1;
+        if spins == SPINS_BEFORE_REPORT {
+            warn!(
+                "cpu {} is still waiting for \"{}\" held by cpu {}, maybe deadlocked",
+                cpu.id,
+                name,
+                state.owner.load(Ordering::SeqCst)
+            );
+        }
+        spin_loop();
+    };
+
+    state.owner.store(cpu.id, Ordering::SeqCst);
+    cpu.held_locks.push(HeldLock { state, class });
+    guard
+}
+
+pub fn unlock(state: &LockState) {
+    state.owner.store(NO_OWNER, Ordering::SeqCst);
+
+    if let Some(cpu) = percpu::try_current() {
+        cpu.held_locks.remove(state);
+    }
+}
@@ -3,38 +3,48 @@ use alloc::vec;
 
 use litchi_user_common::resource::ResourceError;
 use litchi_user_common::syscall::{Syscall, SyscallResponse};
-use log::warn;
 
 use crate::task::{with_task_manager, TaskInfo, TaskManager};
-use crate::{kernel_task, print, resource};
+use crate::{kernel_task, percpu, print, resource, serial_log, shm};
 
 /// User may provide some invalid or privileged memory to us within the syscall request. We should
-/// check them before safely handling the request.
+/// check them before safely handling the request. The lazily backed pages of the memory will be
+/// populated, so the kernel can access them later without page faults.
 pub fn check_syscall_legal(syscall: &Syscall) -> bool {
-    fn str_addr(s: &str) -> (*const (), usize) {
-        (s.as_ptr() as *const (), s.as_bytes().len())
+    fn str_addr(s: &str) -> (*const (), usize, bool) {
+        (s.as_ptr() as *const (), s.as_bytes().len(), false)
     }
 
+    // The kernel will write to the buffers with the last field being true.
     let addrs = match syscall {
         Syscall::Print { str } => vec![str_addr(str)],
+        Syscall::SetLogFilter { filter } => vec![str_addr(filter)],
         Syscall::Open { path } => vec![str_addr(path)],
+        Syscall::ShmCreate { name, .. }
+        | Syscall::ShmMap { name, .. }
+        | Syscall::ShmUnlink { name } => vec![str_addr(name)],
+        Syscall::Read { buf, .. } => vec![(buf.as_ptr() as *const (), buf.len(), true)],
         _ => vec![],
     };
 
     with_task_manager(|tm| {
-        let page_table = tm.current_page_table().unwrap();
+        for (base, len, _) in addrs.iter() {
+            tm.populate_current(*base, *len);
+            if !tm.has_running() {
+                return false; // Killed by the OOM killer.
+            }
+        }
+
         let illegal = addrs
             .into_iter()
-            .find(|(base, len)| !page_table.check_user_accessible(*base, *len));
+            .find(|(base, len, write)| !tm.check_current_accessible(*base, *len, *write));
 
         // Kill it on illegal memory requests.
         if let Some(illegal) = illegal {
-            let current_task = tm.current_info().unwrap().clone();
-            warn!(
-                "illegal access to {:?}, killed it: {:?}",
-                illegal, current_task,
+            tm.crash_current(
+                format_args!("illegal access to {:?} in system call", illegal),
+                None,
             );
-            tm.drop_current();
             false
         } else {
             true
@@ -108,6 +118,59 @@ pub fn handle_syscall(syscall: Syscall<'static>, task_info: TaskInfo) -> Syscall
             }
         }
 
+        Syscall::Mmap { addr, len, prot } => {
+            let addr = with_task_manager(|tm| tm.mmap_current(addr, len, prot));
+            SyscallResponse::Mmap { addr }
+        }
+
+        Syscall::Munmap { addr, len } => {
+            let result = with_task_manager(|tm| tm.munmap_current(addr, len));
+            SyscallResponse::Munmap { result }
+        }
+
+        Syscall::Mprotect { addr, len, prot } => {
+            let result = with_task_manager(|tm| tm.mprotect_current(addr, len, prot));
+            SyscallResponse::Mprotect { result }
+        }
+
+        Syscall::ShmCreate { name, len } => {
+            let result = shm::create(name.to_owned(), len);
+            SyscallResponse::ShmCreate { result }
+        }
+
+        Syscall::ShmMap { name, addr, prot } => {
+            let mapping = shm::get(name)
+                .and_then(|shm| with_task_manager(|tm| tm.shm_map_current(shm, addr, prot)));
+            SyscallResponse::ShmMap { mapping }
+        }
+
+        Syscall::ShmUnlink { name } => SyscallResponse::ShmUnlink {
+            result: shm::unlink(name),
+        },
+
+        Syscall::GetCpuId => SyscallResponse::GetCpuId {
+            cpu_id: percpu::current().id,
+        },
+
+        Syscall::GetAffinity => SyscallResponse::GetAffinity {
+            affinity: with_task_manager(TaskManager::current_affinity),
+        },
+
+        Syscall::SetAffinity { affinity } => {
+            let result = with_task_manager(|tm| tm.set_current_affinity(affinity));
+            if result.is_ok() && !affinity.contains(percpu::current().id) {
+                // Move the task to one of the given processors right away, by pending and resuming
+                // it, where the response will be placed.
+                let task = with_task_manager(TaskManager::pend_current);
+                task.resume_syscall_response(|| SyscallResponse::SetAffinity { result: Ok(()) });
+            }
+            SyscallResponse::SetAffinity { result }
+        }
+
+        Syscall::SetLogFilter { filter } => SyscallResponse::SetLogFilter {
+            result: serial_log::set_filter(filter),
+        },
+
         Syscall::Halt => {
             crate::qemu::exit(crate::qemu::ExitCode::Success);
         }
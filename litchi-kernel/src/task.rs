@@ -1,25 +1,34 @@
 use align_data::{include_aligned, Align4K};
 
+mod crash;
 mod frame;
+mod layout;
 mod manager;
+mod oom;
+mod run_queue;
+mod vma;
 
 pub use frame::{Registers, TaskFrame};
+use log::warn;
 use paste::paste;
 
 pub use self::manager::{schedule_and_run, with_task_manager, TaskInfo, TaskManager};
 
 macro_rules! include_binary {
-    ($name: ident) => {
+    ($name:ident) => {
+        include_binary!($name, concat!(stringify!($name), ".lit"));
+    };
+    ($name:ident, $file:expr) => {
         paste! {
             #[cfg(debug_assertions)]
             static [<$name:upper _BIN>]: &[u8] = include_aligned!(
                 Align4K,
-                concat!("../../target/x86_64-unknown-litchi-user/debug/", stringify!($name), ".lit")
+                concat!("../../target/x86_64-unknown-litchi-user/debug/", $file)
             );
             #[cfg(not(debug_assertions))]
             static [<$name:upper _BIN>]: &[u8] = include_aligned!(
                 Align4K,
-                concat!("../../target/x86_64-unknown-litchi-user/release/", stringify!($name), ".lit")
+                concat!("../../target/x86_64-unknown-litchi-user/release/", $file)
             );
         }
     };
@@ -31,25 +40,49 @@ macro_rules! include_binary {
 // include_binary!(evil_memory_access_4);
 // include_binary!(evil_heap);
 // include_binary!(loop);
+// include_binary!(mmap);
+// include_binary!(shm);
 // include_binary!(sleep);
 // include_binary!(sleep_loop);
+include_binary!(sleep_shared);
 include_binary!(shell);
+include_binary!(liblitchi_user, "liblitchi_user.so");
+
+/// The shared libraries that user programs can depend on through `DT_NEEDED`, by their names.
+static SHARED_LIBRARIES: &[(&str, &[u8])] = &[("liblitchi_user.so", LIBLITCHI_USER_BIN)];
+
+fn find_library(name: &str) -> Option<&'static [u8]> {
+    SHARED_LIBRARIES
+        .iter()
+        .find(|(library_name, _)| *library_name == name)
+        .map(|(_, bytes)| *bytes)
+}
+
+fn load_user(task_manager: &TaskManager, name: &str, elf_bytes: &'static [u8]) {
+    if let Err(err) = task_manager.load_user(name, elf_bytes) {
+        warn!("failed to load user binary `{}`: {}", name, err);
+    }
+}
 
 pub fn load() {
     with_task_manager(|task_manager| {
-        // task_manager.load_user("evil_heap", EVIL_HEAP_BIN);
-        // task_manager.load_user("evil_memory_access_1", EVIL_MEMORY_ACCESS_1_BIN);
-        // task_manager.load_user("evil_memory_access_2", EVIL_MEMORY_ACCESS_2_BIN);
-        // task_manager.load_user("evil_memory_access_3", EVIL_MEMORY_ACCESS_3_BIN);
-        // task_manager.load_user("evil_memory_access_4", EVIL_MEMORY_ACCESS_4_BIN);
-        // task_manager.load_user("loop1", LOOP_BIN);
-        // task_manager.load_user("loop2", LOOP_BIN);
-        // task_manager.load_user("loop3", LOOP_BIN);
-        // task_manager.load_user("sleep1", SLEEP_BIN);
-        // task_manager.load_user("sleep2", SLEEP_BIN);
-        // task_manager.load_user("sleep_loop_1", SLEEP_LOOP_BIN);
-        // task_manager.load_user("sleep_loop_2", SLEEP_LOOP_BIN);
-        task_manager.load_user("shell", SHELL_BIN);
+        // load_user(task_manager, "evil_heap", EVIL_HEAP_BIN);
+        // load_user(task_manager, "evil_memory_access_1", EVIL_MEMORY_ACCESS_1_BIN);
+        // load_user(task_manager, "evil_memory_access_2", EVIL_MEMORY_ACCESS_2_BIN);
+        // load_user(task_manager, "evil_memory_access_3", EVIL_MEMORY_ACCESS_3_BIN);
+        // load_user(task_manager, "evil_memory_access_4", EVIL_MEMORY_ACCESS_4_BIN);
+        // load_user(task_manager, "loop1", LOOP_BIN);
+        // load_user(task_manager, "loop2", LOOP_BIN);
+        // load_user(task_manager, "loop3", LOOP_BIN);
+        // load_user(task_manager, "mmap", MMAP_BIN);
+        // load_user(task_manager, "shm1", SHM_BIN);
+        // load_user(task_manager, "shm2", SHM_BIN);
+        // load_user(task_manager, "sleep1", SLEEP_BIN);
+        // load_user(task_manager, "sleep2", SLEEP_BIN);
+        // load_user(task_manager, "sleep_loop_1", SLEEP_LOOP_BIN);
+        // load_user(task_manager, "sleep_loop_2", SLEEP_LOOP_BIN);
+        load_user(task_manager, "sleep_shared", SLEEP_SHARED_BIN);
+        load_user(task_manager, "shell", SHELL_BIN);
     });
 }
 
@@ -0,0 +1,255 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use litchi_common::elf_loader::ElfLoader;
+use litchi_common::symbols::{self, Symbol};
+use x86_64::structures::paging::{FrameAllocator, Size4KiB};
+use x86_64::VirtAddr;
+
+use super::{vma, TaskFrame, TaskInfo};
+use crate::backtrace::Demangle;
+use crate::memory::PageTableWrapper;
+
+/// Dump this many words of the stack from the stack pointer.
+const STACK_DUMP_WORDS: u64 = 32;
+
+/// Stop walking after this many frames, in case the chain of frame pointers is corrupted.
+const MAX_FRAMES: usize = 32;
+
+/// An ELF loaded into the address space of a user task.
+pub struct UserImage {
+    name: String,
+
+    elf_bytes: &'static [u8],
+
+    bias: u64,
+
+    /// The range covering all of the loadable segments.
+    start: VirtAddr,
+
+    end: VirtAddr,
+}
+
+impl UserImage {
+    pub fn new<A>(name: String, elf_bytes: &'static [u8], loader: &ElfLoader<A>) -> Self
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let start = loader.segment_ranges().map(|(start, ..)| start).min();
+        let end = loader.segment_ranges().map(|(_, end, _)| end).max();
+
+        Self {
+            name,
+            elf_bytes,
+            bias: loader.bias(),
+            start: start.unwrap_or_else(VirtAddr::zero),
+            end: end.unwrap_or_else(VirtAddr::zero),
+        }
+    }
+
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.start.as_u64() && addr < self.end.as_u64()
+    }
+}
+
+impl fmt::Debug for UserImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserImage")
+            .field("name", &self.name)
+            .field("start", &self.start)
+            .field("end", &self.end)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The images of a task with their symbols, to symbolize the addresses in the backtrace.
+struct Symbolizer<'a> {
+    images: Vec<(&'a UserImage, Vec<Symbol>)>,
+}
+
+impl<'a> Symbolizer<'a> {
+    fn new(images: &'a [UserImage]) -> Self {
+        Self {
+            images: images
+                .iter()
+                .map(|image| (image, symbols::function_symbols(image.elf_bytes)))
+                .collect(),
+        }
+    }
+
+    /// Write the address as `function+offset (image)`. The address of a call instruction should be
+    /// given for return addresses, which is adjusted by `-1`.
+    fn write(&self, report: &mut String, addr: u64, adjust: u64) -> fmt::Result {
+        let lookup = addr - adjust;
+        let Some((image, symbols)) = self.images.iter().find(|(image, _)| image.contains(lookup))
+        else {
+            return write!(report, "??");
+        };
+
+        match symbols::find(symbols, lookup - image.bias) {
+            Some((symbol, offset)) => write!(
+                report,
+                "{}+{:#x} ({})",
+                Demangle(symbol.name),
+                offset + adjust,
+                image.name
+            ),
+            None => write!(report, "?? ({})", image.name),
+        }
+    }
+}
+
+/// Read a word of the user memory, if it's accessible. The address comes from the registers of the
+/// task, so it may be anywhere, even non-canonical.
+fn read_user(page_table: &PageTableWrapper, addr: u64) -> Option<u64> {
+    let ptr = addr as *const u64;
+    let in_user_space = addr
+        .checked_add(8)
+        .map_or(false, |end| end <= vma::USER_SPACE_END.as_u64());
+    (addr % 8 == 0 && in_user_space && page_table.check_user_accessible(ptr as *const (), 8, false))
+        .then(|| unsafe { ptr.read_volatile() })
+}
+
+fn write_registers(report: &mut String, frame: &TaskFrame) -> fmt::Result {
+    let regs = &frame.regs;
+    let stack_frame = &frame.frame;
+
+    writeln!(report, "registers:")?;
+    let rows = [
+        [
+            ("rip", stack_frame.instruction_pointer.as_u64()),
+            ("rsp", stack_frame.stack_pointer.as_u64()),
+            ("rflags", stack_frame.cpu_flags),
+        ],
+        [("rax", regs.rax), ("rbx", regs.rbx), ("rcx", regs.rcx)],
+        [("rdx", regs.rdx), ("rsi", regs.rsi), ("rdi", regs.rdi)],
+        [("rbp", regs.rbp), ("r8", regs.r8), ("r9", regs.r9)],
+        [("r10", regs.r10), ("r11", regs.r11), ("r12", regs.r12)],
+        [("r13", regs.r13), ("r14", regs.r14), ("r15", regs.r15)],
+    ];
+    for row in rows {
+        for (name, value) in row {
+            write!(report, " {:>6} {:#018x}", name, value)?;
+        }
+        writeln!(report)?;
+    }
+    writeln!(
+        report,
+        "     cs {:#x} ss {:#x} ds {:#x} es {:#x}",
+        stack_frame.code_segment, stack_frame.stack_segment, frame.ds, frame.es
+    )
+}
+
+/// Dump the words from the stack pointer, until an inaccessible one.
+fn write_stack(
+    report: &mut String,
+    frame: &TaskFrame,
+    page_table: &PageTableWrapper,
+) -> fmt::Result {
+    let stack_pointer = frame.frame.stack_pointer.as_u64();
+    writeln!(report, "stack:")?;
+
+    for row in (0..STACK_DUMP_WORDS).step_by(4) {
+        // The stack pointer is set by the user, so stop on overflow.
+        let Some(row_addr) = stack_pointer.checked_add(row * 8) else {
+            break;
+        };
+        let words = (0..4)
+            .map_while(|i| {
+                let addr = row_addr.checked_add(i * 8)?;
+                read_user(page_table, addr)
+            })
+            .collect::<Vec<_>>();
+        if words.is_empty() {
+            break;
+        }
+
+        write!(report, "  {:#x}:", row_addr)?;
+        for word in &words {
+            write!(report, " {:#018x}", word)?;
+        }
+        writeln!(report)?;
+        if words.len() < 4 {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Walk the frame pointers of the user stack, starting from the instruction pointer.
+fn write_backtrace(
+    report: &mut String,
+    frame: &TaskFrame,
+    images: &[UserImage],
+    page_table: &PageTableWrapper,
+) -> fmt::Result {
+    let symbolizer = Symbolizer::new(images);
+    writeln!(report, "backtrace:")?;
+
+    let instruction_pointer = frame.frame.instruction_pointer.as_u64();
+    write!(report, "  {:>4}: {:#x} ", 0, instruction_pointer)?;
+    symbolizer.write(report, instruction_pointer, 0)?;
+    writeln!(report)?;
+
+    let mut frame_pointer = frame.regs.rbp;
+    for depth in 1..MAX_FRAMES {
+        // The saved frame pointer of the caller, followed by the return address.
+        let Some(next) = read_user(page_table, frame_pointer) else {
+            break;
+        };
+        let Some(return_addr) = frame_pointer
+            .checked_add(8)
+            .and_then(|addr| read_user(page_table, addr))
+        else {
+            break;
+        };
+        if return_addr == 0 {
+            break;
+        }
+
+        write!(report, "  {:>4}: {:#x} ", depth, return_addr)?;
+        symbolizer.write(report, return_addr, 1)?;
+        writeln!(report)?;
+
+        // The stack grows down, so the callers' frames must be above.
+        if next <= frame_pointer {
+            break;
+        }
+        frame_pointer = next;
+    }
+    Ok(())
+}
+
+/// Build the crash report of the task, with the registers, the stack and the backtrace. The page
+/// table of the task must be loaded.
+pub fn report(
+    info: &TaskInfo,
+    reason: fmt::Arguments,
+    frame: &TaskFrame,
+    images: &[UserImage],
+    page_table: &PageTableWrapper,
+) -> String {
+    let mut report = String::new();
+    // Writing to a string never fails.
+    let _ = write_report(&mut report, info, reason, frame, images, page_table);
+    report
+}
+
+fn write_report(
+    report: &mut String,
+    info: &TaskInfo,
+    reason: fmt::Arguments,
+    frame: &TaskFrame,
+    images: &[UserImage],
+    page_table: &PageTableWrapper,
+) -> fmt::Result {
+    writeln!(
+        report,
+        "task {} `{}` crashed: {}",
+        info.id, info.name, reason
+    )?;
+    write_registers(report, frame)?;
+    write_stack(report, frame, page_table)?;
+    write_backtrace(report, frame, images, page_table)
+}
@@ -1,7 +1,7 @@
 use core::arch::asm;
 
 use log::debug;
-use x86_64::registers::segmentation::{Segment, SegmentSelector};
+use x86_64::registers::segmentation::{Segment, SegmentSelector, GS};
 use x86_64::structures::idt::InterruptStackFrameValue;
 use x86_64::{registers, PrivilegeLevel};
 
@@ -45,6 +45,11 @@ impl TaskFrame {
 
         debug!("loaded ds = {}, es = {}", self.ds, self.es);
 
+        // Leave the per-cpu block of the kernel to the next entry from the user.
+        if self.is_user() {
+            GS::swap();
+        }
+
         asm!(
             "mov    rsp, {}",
             "add    rsp, 16", // skip es & ds
@@ -0,0 +1,64 @@
+use litchi_user_common::syscall::buffer::SYSCALL_BUFFER_PAGES;
+use x86_64::structures::paging::{PageSize, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::random;
+
+/// The user stack grows on demand up to this size. The page below it is never mapped as a guard.
+pub const USER_STACK_MAX_PAGES: u64 = 256; // 1 MiB
+pub const USER_HEAP_MAX_SIZE: u64 = 1024 * 1024 * 1024; // 1 GiB
+/// Each shared library is loaded in a slot of this size after the library base.
+pub const LIBRARY_MAX_SIZE: u64 = 1024 * 1024 * 1024; // 1 GiB
+
+/// Each part of the layout is placed randomly inside its own window of 1 TiB, so they never
+/// overlap with each other.
+const WINDOW_SIZE: u64 = 0x0100_0000_0000;
+const IMAGE_WINDOW: VirtAddr = VirtAddr::new_truncate(0x1100_0000_0000);
+const HEAP_WINDOW: VirtAddr = VirtAddr::new_truncate(0x1200_0000_0000);
+const SYSCALL_IN_WINDOW: VirtAddr = VirtAddr::new_truncate(0x1300_0000_0000);
+const SYSCALL_OUT_WINDOW: VirtAddr = VirtAddr::new_truncate(0x1400_0000_0000);
+const LIBRARY_WINDOW: VirtAddr = VirtAddr::new_truncate(0x1600_0000_0000);
+const STACK_WINDOW: VirtAddr = VirtAddr::new_truncate(0x1800_0000_0000);
+
+/// The room reserved for the ELF image and the shared libraries in their windows.
+const IMAGE_MAX_SIZE: u64 = 4 * 1024 * 1024 * 1024; // 4 GiB
+const LIBRARIES_MAX_SIZE: u64 = 64 * LIBRARY_MAX_SIZE;
+
+/// The addresses of a user address space, randomized each time a task is loaded.
+#[derive(Debug, Clone, Copy)]
+pub struct UserLayout {
+    /// Where position-independent executables are loaded.
+    pub image_base: VirtAddr,
+
+    /// Where the first shared library is loaded.
+    pub library_base: VirtAddr,
+
+    pub stack_top: VirtAddr,
+
+    pub heap_base: VirtAddr,
+
+    pub syscall_in: VirtAddr,
+
+    pub syscall_out: VirtAddr,
+}
+
+impl UserLayout {
+    pub fn random() -> Self {
+        let stack_size = (USER_STACK_MAX_PAGES + 1) * Size4KiB::SIZE;
+        let buffer_size = SYSCALL_BUFFER_PAGES * Size4KiB::SIZE;
+
+        Self {
+            image_base: random_page_in(IMAGE_WINDOW, WINDOW_SIZE - IMAGE_MAX_SIZE),
+            library_base: random_page_in(LIBRARY_WINDOW, WINDOW_SIZE - LIBRARIES_MAX_SIZE),
+            stack_top: random_page_in(STACK_WINDOW + stack_size, WINDOW_SIZE - stack_size),
+            heap_base: random_page_in(HEAP_WINDOW, WINDOW_SIZE - USER_HEAP_MAX_SIZE),
+            syscall_in: random_page_in(SYSCALL_IN_WINDOW, WINDOW_SIZE - buffer_size),
+            syscall_out: random_page_in(SYSCALL_OUT_WINDOW, WINDOW_SIZE - buffer_size),
+        }
+    }
+}
+
+/// Get a random page-aligned address in `[base, base + len)`.
+fn random_page_in(base: VirtAddr, len: u64) -> VirtAddr {
+    base + random::below(len / Size4KiB::SIZE) * Size4KiB::SIZE
+}
@@ -1,31 +1,41 @@
 use alloc::borrow::ToOwned;
 use alloc::boxed::Box;
-use alloc::collections::{BTreeMap, VecDeque};
+use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use alloc::{format, vec};
+use core::fmt;
 use core::ops::Deref;
 use core::sync::atomic::{AtomicU64, Ordering};
 
 use lazy_static::lazy_static;
-use litchi_common::elf_loader::{ElfLoader, LoaderConfig};
-use litchi_user_common::heap::USER_HEAP_BASE_ADDR;
+use litchi_common::elf_loader::{ElfLoader, LoadError, LoadResult, LoaderConfig, SymbolScope};
+use litchi_user_common::memory::{MemoryError, MemoryResult, Protection};
 use litchi_user_common::resource::ResourceHandle;
-use litchi_user_common::syscall::buffer::{
-    SYSCALL_BUFFER_PAGES, SYSCALL_IN_ADDR, SYSCALL_OUT_ADDR,
-};
+use litchi_user_common::syscall::buffer::SYSCALL_BUFFER_PAGES;
 use litchi_user_common::syscall::SyscallResponse;
-use log::{debug, info, trace, warn};
-use spin::Mutex;
-use x86_64::structures::idt::InterruptStackFrameValue;
-use x86_64::structures::paging::{Page, PageSize, PageTableFlags, Size4KiB};
+use litchi_user_common::task::{CpuMask, TaskError, TaskResult};
+use log::{debug, error, info, trace, warn};
+use x86_64::structures::idt::{InterruptStackFrameValue, PageFaultErrorCode};
+use x86_64::structures::paging::{FrameAllocator, Page, PageSize, PageTableFlags, Size4KiB};
 use x86_64::{instructions, VirtAddr};
 
+use super::crash::{self, UserImage};
+use super::layout::{UserLayout, LIBRARY_MAX_SIZE, USER_HEAP_MAX_SIZE, USER_STACK_MAX_PAGES};
+use super::oom::{self, Candidate};
+use super::run_queue::RunQueue;
+use super::vma::{self, AddressSpace, Vma, VmaKind};
 use super::TaskFrame;
+use crate::fpu::FpuState;
 use crate::gdt::GDT;
 use crate::memory::{PageTableWrapper, KERNEL_PAGE_TABLE};
-use crate::resource::BoxedResource;
+use crate::percpu::{self, MAX_CPUS};
+use crate::resource::{crash_log, BoxedResource};
+use crate::shm::SharedMemory;
+use crate::sync::IrqSafeMutex;
 use crate::task::frame::Registers;
-use crate::{kernel_task, BOOT_INFO};
+use crate::{frame_allocator, kernel_task, smp, stack, BOOT_INFO};
 
 #[derive(Debug)]
 enum TaskPageTable {
@@ -52,7 +62,7 @@ pub struct TaskInfo {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-struct Priority(u8);
+pub(super) struct Priority(u8);
 
 impl Priority {
     const fn user() -> Self {
@@ -73,17 +83,29 @@ impl core::fmt::Debug for PreScheduling {
 }
 
 #[derive(Debug)]
-struct Task {
-    info: TaskInfo,
+pub(super) struct Task {
+    pub info: TaskInfo,
 
-    priority: Priority,
+    pub priority: Priority,
 
-    heap_top: VirtAddr,
+    /// The processors that this task can run on.
+    pub affinity: CpuMask,
+
+    address_space: AddressSpace,
+
+    /// The randomized layout of user tasks. `None` for the idle task.
+    layout: Option<UserLayout>,
+
+    /// The executable and the shared libraries loaded, to symbolize the crash reports.
+    images: Vec<UserImage>,
 
     page_table: TaskPageTable,
 
     frame: Option<TaskFrame>,
 
+    /// The x87 and SSE registers of user tasks, saved while the task is not running.
+    fpu: Box<FpuState>,
+
     resources: BTreeMap<ResourceHandle, Arc<BoxedResource>>,
 
     pre_schduling: Option<PreScheduling>,
@@ -91,15 +113,21 @@ struct Task {
 
 impl Task {
     const IDLE_ID: u64 = 0;
+    const IDLE_STACK_PAGES: u64 = 4;
     const USER_START_ID: u64 = 1024;
 
-    fn idle() -> Self {
+    /// Create an idle task with its own kernel stack. There's one idle task for each processor, so
+    /// that there's always a task to schedule.
+    fn idle(cpu_id: usize) -> Self {
         fn idle() -> ! {
             loop {
                 instructions::hlt();
             }
         }
 
+        let name = Box::leak(format!("idle on cpu {}", cpu_id).into_boxed_str());
+        let stack_top = stack::allocate(name, Self::IDLE_STACK_PAGES);
+
         let segment = GDT.kernel_code_selector.0 as u64;
 
         let frame = TaskFrame {
@@ -110,7 +138,7 @@ impl Task {
                 instruction_pointer: VirtAddr::from_ptr(idle as *const fn() -> !),
                 code_segment: segment,
                 cpu_flags: 0x0000_0200, // enable interrupts
-                stack_pointer: BOOT_INFO.get().unwrap().kernel_stack_top,
+                stack_pointer: stack_top,
                 stack_segment: 0,
             },
         };
@@ -121,13 +149,26 @@ impl Task {
                 name: "idle".to_owned(),
             },
             priority: Priority::idle(),
-            heap_top: VirtAddr::zero(), // unused
+            affinity: CpuMask::single(cpu_id),
+            address_space: AddressSpace::default(), // unused
+            layout: None,
+            images: Vec::new(),
             page_table: TaskPageTable::Kernel(&KERNEL_PAGE_TABLE),
             frame: Some(frame),
+            fpu: Default::default(),
             resources: Default::default(),
             pre_schduling: None,
         }
     }
+
+    pub fn is_idle(&self) -> bool {
+        self.info.id == Self::IDLE_ID
+    }
+
+    /// Whether this task can be moved to the processor `cpu_id` by other processors.
+    pub fn can_migrate_to(&self, cpu_id: usize) -> bool {
+        !self.is_idle() && self.affinity.contains(cpu_id)
+    }
 }
 
 struct PendingTaskToken;
@@ -145,93 +186,258 @@ impl PendingTaskHandle {
         response: impl FnOnce() -> SyscallResponse + Send + 'static,
     ) {
         with_task_manager(|tm| {
+            let out_addr = tm
+                .pending
+                .lock()
+                .get(&self.id)
+                .map(|(task, _)| task.layout.unwrap().syscall_out);
+            let Some(out_addr) = out_addr else {
+                warn!("task {} has been killed before resuming", self.id);
+                return;
+            };
             tm.resume_task(self, move || {
                 let response = response();
-                unsafe { litchi_user_common::syscall::response(response) };
+                unsafe { litchi_user_common::syscall::response(out_addr, response) };
             })
         })
     }
 }
 
 lazy_static! {
-    static ref TASK_MANAGER: Mutex<TaskManager> = Mutex::new(TaskManager::new());
+    static ref TASK_MANAGER: TaskManager = TaskManager::new();
 }
 
+/// Balance the load between processors every this many timer ticks.
+const BALANCE_INTERVAL: u64 = 10;
+
 pub struct TaskManager {
     next_task_id: AtomicU64,
 
-    running: Option<Task>,
-
-    ready: BTreeMap<Priority, VecDeque<Task>>,
+    /// The run queue of each processor, indexed by the processor id.
+    run_queues: [IrqSafeMutex<RunQueue>; MAX_CPUS],
 
-    pending: BTreeMap<u64, (Task, Weak<PendingTaskToken>)>,
+    pending: IrqSafeMutex<BTreeMap<u64, (Task, Weak<PendingTaskToken>)>>,
 }
 
 impl TaskManager {
     fn new() -> Self {
-        let mut tm = Self {
+        let tm = Self {
             next_task_id: Task::USER_START_ID.into(),
-            running: None,
-            ready: Default::default(),
-            pending: Default::default(),
+            run_queues: [(); MAX_CPUS].map(|_| IrqSafeMutex::new("run queue", RunQueue::default())),
+            pending: IrqSafeMutex::new("pending tasks", BTreeMap::new()),
         };
-        tm.add_to_ready(Task::idle());
+        tm.add_idle(0);
         tm
     }
 
+    /// Add an idle task for a new processor.
+    pub fn add_idle(&self, cpu_id: usize) {
+        self.run_queues[cpu_id].lock().push(Task::idle(cpu_id));
+    }
+
     fn allocate_id(&self) -> u64 {
         self.next_task_id.fetch_add(1, Ordering::SeqCst)
     }
+
+    /// Run the closure with the run queue of the current processor. The queues of other processors
+    /// must not be locked inside.
+    fn with_local<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut RunQueue) -> R,
+    {
+        f(&mut *self.run_queues[percpu::current().id].lock())
+    }
+
+    /// Run the closure with the running task of the current processor. The task is taken out of the
+    /// run queue meanwhile, so that the closure may wait for other processors without holding the
+    /// lock, e.g. for TLB shootdowns.
+    fn with_current<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Task) -> R,
+    {
+        let mut task = self.with_local(|rq| rq.running.take().expect("no task running"));
+        let result = f(&mut task);
+        self.with_local(|rq| rq.running = Some(task));
+        result
+    }
+
+    fn online_run_queues(&self) -> &[IrqSafeMutex<RunQueue>] {
+        &self.run_queues[..smp::online_cpus()]
+    }
 }
 
 impl TaskManager {
-    fn add_to_ready(&mut self, task: Task) {
-        self.ready.entry(task.priority).or_default().push_back(task);
+    /// Put the task to the least loaded processor that it can run on.
+    fn enqueue(&self, task: Task) {
+        let cpu_id = (0..smp::online_cpus())
+            .filter(|&cpu_id| task.affinity.contains(cpu_id))
+            .min_by_key(|&cpu_id| self.run_queues[cpu_id].lock().load())
+            .expect("no processor for the task");
+
+        self.run_queues[cpu_id].lock().push(task);
+    }
+
+    /// Find the processor with the most ready tasks other than `cpu_id`, and the number of them.
+    fn busiest(&self, cpu_id: usize) -> Option<(usize, usize)> {
+        (0..smp::online_cpus())
+            .filter(|&other| other != cpu_id)
+            .map(|other| (other, self.run_queues[other].lock().load()))
+            .max_by_key(|&(_, load)| load)
     }
 
-    fn take_one_ready(&mut self) -> Task {
-        self.ready
-            .values_mut()
-            .find(|q| !q.is_empty())
-            .expect("there should be always an idle task")
-            .pop_front()
-            .unwrap()
+    /// Take at most `count` tasks that can run on `cpu_id` from the busiest processor.
+    fn steal(&self, cpu_id: usize, count: usize) -> Vec<Task> {
+        match self.busiest(cpu_id) {
+            Some((busiest, load)) if load > 0 => {
+                self.run_queues[busiest].lock().steal(cpu_id, count)
+            }
+            _ => Vec::new(),
+        }
     }
 
-    pub fn load_user(&mut self, name: impl Into<String>, elf_bytes: &'static [u8]) {
-        const USER_STACK_TOP: VirtAddr = VirtAddr::new_truncate(0x1889_0000_0000);
-        const USER_STACK_PAGES: u64 = 10;
+    /// Pull tasks from the busiest processor, if it has more ready tasks than the current one.
+    fn balance(&self) {
+        let cpu_id = percpu::current().id;
+        let local_load = self.with_local(|rq| rq.load());
+
+        let Some((busiest, load)) = self.busiest(cpu_id) else {
+            return;
+        };
+        if load <= local_load + 1 {
+            return;
+        }
+
+        let tasks = self.run_queues[busiest]
+            .lock()
+            .steal(cpu_id, (load - local_load) / 2);
+        if !tasks.is_empty() {
+            debug!(
+                "moved {} tasks from cpu {} to cpu {}",
+                tasks.len(),
+                busiest,
+                cpu_id
+            );
+        }
+        self.with_local(|rq| tasks.into_iter().for_each(|task| rq.push(task)));
+    }
+
+    /// Count a timer tick on the current processor, and balance the load periodically.
+    pub fn tick(&self) {
+        let ticks = self.with_local(|rq| {
+            rq.ticks += 1;
+            rq.ticks
+        });
 
+        if ticks % BALANCE_INTERVAL == 0 {
+            self.balance();
+            if percpu::current().is_bsp() {
+                self.cleanup_zombies();
+            }
+        }
+    }
+
+    /// Load a user task from the ELF. If there's no enough memory, the OOM killer will be invoked
+    /// and the loading will be retried.
+    pub fn load_user(&self, name: impl Into<String>, elf_bytes: &'static [u8]) -> LoadResult<()> {
         let name = name.into();
 
-        let page_table = PageTableWrapper::new_user();
+        loop {
+            match self.try_load_user(name.clone(), elf_bytes) {
+                Err(LoadError::OutOfMemory) if self.kill_for_memory() => {}
+                result => return result,
+            }
+        }
+    }
+
+    fn try_load_user(&self, name: String, elf_bytes: &'static [u8]) -> LoadResult<()> {
+        let page_table = PageTableWrapper::new_user().ok_or(LoadError::OutOfMemory)?;
+        let layout = UserLayout::random();
         let loader_config = LoaderConfig {
-            stack_top: USER_STACK_TOP,
-            stack_pages: USER_STACK_PAGES,
+            stack_top: layout.stack_top,
+            stack_pages: 0, // The user stack is backed on demand.
             userspace: true,
+            load_base: layout.image_base,
+            phys_offset: BOOT_INFO.get().unwrap().phys_offset,
         };
 
-        let entry_point = page_table.with_allocator(|frame_allocator, page_table| {
-            ElfLoader::new(&loader_config, elf_bytes, frame_allocator, page_table).load()
+        let syscall_buffers = [layout.syscall_in, layout.syscall_out].map(|base_addr| Vma {
+            start: base_addr,
+            end: base_addr + SYSCALL_BUFFER_PAGES * Size4KiB::SIZE,
+            prot: Protection::READ | Protection::WRITE,
+            kind: VmaKind::SyscallBuffer,
         });
+        let stack = Vma {
+            start: layout.stack_top - USER_STACK_MAX_PAGES * Size4KiB::SIZE,
+            end: layout.stack_top,
+            prot: Protection::READ | Protection::WRITE,
+            kind: VmaKind::Stack,
+        };
+
+        let mut address_space = AddressSpace::default();
+        for vma in syscall_buffers.iter().cloned().chain([stack]) {
+            address_space.insert(vma).unwrap();
+        }
+
+        let mut images = Vec::new();
+        let entry_point = page_table.with_allocator(|frame_allocator, page_table| {
+            // Find the shared libraries required by the executable and the libraries themselves
+            // breadth-first, and place each library in its own slot from the library base. The
+            // symbols are exported in the same order, so the executable takes precedence.
+            let mut objects = vec![(name.clone(), elf_bytes, layout.image_base)];
+            let mut library_base = layout.library_base;
+            let mut scope = SymbolScope::new();
+
+            let mut i = 0;
+            while let Some((_, bytes, load_base)) = objects.get(i).cloned() {
+                let config = LoaderConfig {
+                    load_base,
+                    ..loader_config.clone()
+                };
+                let loader = ElfLoader::new(&config, bytes, frame_allocator, page_table)?;
+                insert_image_vmas(&mut address_space, &loader)?;
+                loader.export_symbols(&mut scope);
+
+                for needed in loader.needed() {
+                    if objects
+                        .iter()
+                        .any(|(object_name, ..)| object_name == needed)
+                    {
+                        continue;
+                    }
+                    let bytes = super::find_library(needed)
+                        .ok_or_else(|| LoadError::MissingLibrary(needed.clone()))?;
+                    objects.push((needed.clone(), bytes, library_base));
+                    library_base += LIBRARY_MAX_SIZE;
+                }
+                i += 1;
+            }
+
+            let mut entry_point = None;
+            for (object_name, bytes, load_base) in objects {
+                let config = LoaderConfig {
+                    load_base,
+                    ..loader_config.clone()
+                };
+                let loader = ElfLoader::new(&config, bytes, frame_allocator, page_table)?;
+                images.push(UserImage::new(object_name.clone(), bytes, &loader));
+                let object_entry = loader.load(&scope)?;
+                info!("loaded `{}` at {:?}", object_name, load_base);
+                entry_point.get_or_insert(object_entry);
+            }
+            Ok::<_, LoadError>(entry_point.unwrap())
+        })?;
         info!(
-            "loaded user binary `{}`, entry point {:p}",
-            name, entry_point
+            "loaded user binary `{}`, entry point {:p}, layout {:x?}",
+            name, entry_point, layout
         );
 
         // Map syscall buffer.
-        unsafe {
-            let flags = PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::USER_ACCESSIBLE
-                | PageTableFlags::NO_EXECUTE;
-
-            for base_addr in [SYSCALL_IN_ADDR, SYSCALL_OUT_ADDR] {
-                let base_page = Page::from_start_address(base_addr).unwrap();
-                for page in (0..SYSCALL_BUFFER_PAGES).map(|i| base_page + i) {
+        for vma in syscall_buffers {
+            for page in vma.pages() {
+                unsafe {
                     page_table
-                        .allocate_and_map_to(page, flags)
-                        .expect("no enough memory");
+                        .allocate_and_map_to(page, vma.flags())
+                        .ok_or(LoadError::OutOfMemory)?;
                 }
             }
         }
@@ -242,12 +448,20 @@ impl TaskManager {
         let frame = TaskFrame {
             es: data_segment,
             ds: data_segment,
-            regs: Registers::default(),
+            // Pass the layout to `_user_main` as arguments.
+            regs: Registers {
+                rdi: layout.syscall_in.as_u64(),
+                rsi: layout.syscall_out.as_u64(),
+                rdx: layout.heap_base.as_u64(),
+                ..Default::default()
+            },
             frame: InterruptStackFrameValue {
                 instruction_pointer: VirtAddr::from_ptr(entry_point),
                 code_segment,
                 cpu_flags: 0x0000_0200, // enable interrupts
-                stack_pointer: USER_STACK_TOP,
+                // As if `_user_main` is called and the return address is pushed, so that the stack
+                // is aligned to 16 bytes as required by the ABI.
+                stack_pointer: layout.stack_top - 8u64,
                 stack_segment: data_segment,
             },
         };
@@ -258,19 +472,25 @@ impl TaskManager {
                 name,
             },
             priority: Priority::user(),
-            heap_top: USER_HEAP_BASE_ADDR,
+            affinity: CpuMask::all(),
+            address_space,
+            layout: Some(layout),
+            images,
             page_table: TaskPageTable::User(page_table),
             frame: Some(frame),
+            fpu: Default::default(),
             resources: Default::default(),
             pre_schduling: None,
         };
 
         info!("new task: {:?}", task);
-        self.add_to_ready(task);
+        self.enqueue(task);
+
+        Ok(())
     }
 
-    fn cleanup_zombies(&mut self) {
-        self.pending.retain(|_, (task, token)| {
+    fn cleanup_zombies(&self) {
+        self.pending.lock().retain(|_, (task, token)| {
             let zombie = token.strong_count() == 0;
             if zombie {
                 warn!("zombie task: {:?}", task.info);
@@ -279,30 +499,43 @@ impl TaskManager {
         });
     }
 
-    fn schedule(&mut self) -> TaskFrame {
-        self.cleanup_zombies();
+    fn schedule(&self) -> TaskFrame {
+        let cpu_id = percpu::current().id;
 
-        if self.running.is_none() {
-            let task = self.take_one_ready();
+        // Steal a task from others if there's nothing to run here except the idle task.
+        if self.with_local(|rq| rq.running.is_none() && rq.load() == 0) {
+            let stolen = self.steal(cpu_id, 1);
+            self.with_local(|rq| stolen.into_iter().for_each(|task| rq.push(task)));
+        }
 
-            task.page_table.load();
-            debug!("loaded page table: {:?}", task.page_table);
+        self.with_local(|rq| {
+            if rq.running.is_none() {
+                let task = rq.pop().expect("there should be always an idle task");
 
-            self.running = Some(task);
-        }
+                task.page_table.load();
+                debug!("loaded page table: {:?}", task.page_table);
 
-        let task = self.running.as_mut().unwrap();
-        assert!(task.page_table.is_current());
+                rq.running = Some(task);
+            }
 
-        debug!("scheduled: {:?}", task.info);
-        trace!("scheduled: {:?}", task);
+            let task = rq.running.as_mut().unwrap();
+            assert!(task.page_table.is_current());
 
-        // Run pre scheduling callback. For example, syscall response after pending.
-        if let Some(f) = task.pre_schduling.take() {
-            (f.0)();
-        }
+            debug!("scheduled on cpu {}: {:?}", cpu_id, task.info);
+            trace!("scheduled on cpu {}: {:?}", cpu_id, task);
 
-        task.frame.take().expect("no frame for task")
+            // Run pre scheduling callback. For example, syscall response after pending.
+            if let Some(f) = task.pre_schduling.take() {
+                (f.0)();
+            }
+
+            let frame = task.frame.take().expect("no frame for task");
+            // The kernel never touches these registers, so only restore them for user tasks.
+            if frame.is_user() {
+                task.fpu.restore();
+            }
+            frame
+        })
     }
 
     /// Put back the task frame for the current running task. Used everytime coming from the task by
@@ -312,166 +545,504 @@ impl TaskManager {
     /// the running task to the back of the ready queue. For others like serial interrupt or system
     /// calls, we may want to preserve the time slice of this task, so `yield_task` will be false
     /// and we'll keep this task running on next scheduling.
-    pub fn put_back(&mut self, frame: TaskFrame, yield_task: bool) {
-        let task = self.running.as_mut().expect("no task running");
-
-        if !frame.is_user() {
-            assert_eq!(task.info.id, Task::IDLE_ID);
-        }
+    pub fn put_back(&self, frame: TaskFrame, yield_task: bool) {
+        self.with_local(|rq| {
+            let task = rq.running.as_mut().expect("no task running");
+
+            if frame.is_user() {
+                task.fpu.save();
+            } else {
+                assert!(task.is_idle());
+            }
 
-        let old_frame = task.frame.replace(frame);
-        assert!(old_frame.is_none(), "task frame exists");
+            let old_frame = task.frame.replace(frame);
+            assert!(old_frame.is_none(), "task frame exists");
 
-        debug!(
-            "returned from task: {:?}, yield = {}",
-            task.info, yield_task
-        );
-        trace!("returned from task: {:?}, yield = {}", task, yield_task);
+            debug!(
+                "returned from task: {:?}, yield = {}",
+                task.info, yield_task
+            );
+            trace!("returned from task: {:?}, yield = {}", task, yield_task);
 
-        if yield_task {
-            self.yield_current();
-        }
+            if yield_task {
+                rq.yield_running();
+            }
+        })
     }
 
     /// Put the current running task to the back of the ready queue.
-    pub fn yield_current(&mut self) {
-        if self.ready.is_empty() {
-            debug!("empty ready queue, no need to yield");
-        } else {
-            let task = self.running.take().unwrap();
-            self.add_to_ready(task);
-        }
+    pub fn yield_current(&self) {
+        self.with_local(RunQueue::yield_running);
     }
 
     /// Drop the current running task. Based on the RAII, all of the other resources will be
     /// released as well.
-    pub fn drop_current(&mut self) {
+    pub fn drop_current(&self) {
         KERNEL_PAGE_TABLE.load();
 
-        let task = self.running.take().expect("no task running");
+        let task = self
+            .with_local(|rq| rq.running.take())
+            .expect("no task running");
         info!("dropped current task: {:?}", task.info);
     }
 
+    /// Kill the current running task for the reason, with a crash report written to the log and
+    /// the crash log. The registers are taken from `frame`, or the saved frame of the task if not
+    /// given.
+    pub fn crash_current(&self, reason: fmt::Arguments, frame: Option<&TaskFrame>) {
+        let report = self.with_current(|task| {
+            let frame = frame.or(task.frame.as_ref())?;
+            Some(crash::report(
+                &task.info,
+                reason,
+                frame,
+                &task.images,
+                &task.page_table,
+            ))
+        });
+
+        match report {
+            Some(report) => {
+                warn!("{}", report);
+                crash_log::append(&report);
+            }
+            None => warn!("task crashed without a frame: {}", reason),
+        }
+        self.drop_current();
+    }
+
     /// Pend the current running task by putting it to the pending queue.
     ///
     /// Returns a [`PendingTaskHandle`] which can be used to resume the task. If the caller dropped
     /// the handle instead of resuming the task, The task manager will find it on next scheduling
     /// and clean-up the resources by killing the zombie task.
-    pub fn pend_current(&mut self) -> PendingTaskHandle {
+    pub fn pend_current(&self) -> PendingTaskHandle {
         KERNEL_PAGE_TABLE.load();
 
-        let task = self.running.take().expect("no task running");
+        let task = self
+            .with_local(|rq| rq.running.take())
+            .expect("no task running");
         let id = task.info.id;
         assert!(task.frame.is_some(), "empty frame while pending task");
 
         let token = Arc::new(PendingTaskToken);
         let weak_token = Arc::downgrade(&token);
 
-        self.pending.insert(id, (task, weak_token));
+        self.pending.lock().insert(id, (task, weak_token));
         PendingTaskHandle { id, _token: token }
     }
 
-    /// Resume the given task by transfering it from the pending task queue to the ready queue.
+    /// Resume the given task by transfering it from the pending task queue to the ready queue of
+    /// the least loaded processor.
     ///
     /// The given `pre_scheduling` closure will be saved to the task frame and be called RIGHT
     /// BEFORE this task will be scheduled and AFTER the page table is loaded, since it may rely on
     /// the memory space of this task. For example, we can copy the kernel buffer to the user's and
     /// place the syscall response.
     pub fn resume_task(
-        &mut self,
+        &self,
         task_handle: PendingTaskHandle,
         pre_scheduling: impl FnOnce() + Send + 'static,
     ) {
         let id = task_handle.id;
-        let mut task = self
-            .pending
-            .remove(&id)
-            .unwrap_or_else(|| panic!("no pending task {id}"))
-            .0;
+        let Some((mut task, _)) = self.pending.lock().remove(&id) else {
+            warn!("task {} has been killed before resuming", id);
+            return;
+        };
         task.pre_schduling = Some(PreScheduling(Box::new(pre_scheduling)));
 
-        self.add_to_ready(task);
+        self.enqueue(task);
     }
 
-    pub fn extend_current_heap(&mut self, top: VirtAddr) {
+    /// Extend the heap area of the current task to `top`. The pages will be backed on demand.
+    pub fn extend_current_heap(&self, top: VirtAddr) {
         let top = top.align_up(Size4KiB::SIZE);
-        let task = self.running.as_mut().expect("no task running");
 
-        let mut success = true;
+        let result = self.with_current(|task| {
+            let heap_base = task.layout.expect("no heap for kernel tasks").heap_base;
 
-        if top >= task.heap_top {
-            let base_page = Page::from_start_address(task.heap_top).unwrap();
-            let top_page = Page::from_start_address(top).unwrap();
+            if top > heap_base + USER_HEAP_MAX_SIZE {
+                return Err(format!("heap of {:?} exceeds the limit", top));
+            }
 
-            let flags = PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::USER_ACCESSIBLE
-                | PageTableFlags::NO_EXECUTE;
+            task.address_space
+                .extend_heap(heap_base, top)
+                .map_err(|err| format!("failed to extend heap to {:?}: {}", top, err))?;
+            info!("extend heap to {:?} for task {}", top, task.info.id);
+            Ok(())
+        });
 
-            unsafe {
-                for page in Page::range(base_page, top_page) {
-                    if task.page_table.allocate_and_map_to(page, flags).is_none() {
-                        success = false;
-                        break;
-                    }
+        if let Err(reason) = result {
+            self.crash_current(format_args!("{}", reason), None);
+        }
+    }
+
+    /// Back the page containing `addr` with a zeroed frame, if it's inside a lazily backed area of
+    /// the current task and the access is permitted. Returns `false` if the access is invalid or
+    /// the memory cannot be freed, then the task should be killed if it's still running.
+    pub fn handle_current_page_fault(
+        &self,
+        addr: VirtAddr,
+        error_code: PageFaultErrorCode,
+    ) -> bool {
+        let flags = self.with_current(|task| {
+            let vma = task.address_space.find(addr)?;
+            (vma.is_lazy() && vma.allows(error_code)).then(|| vma.flags())
+        });
+
+        match flags {
+            Some(flags) => self.back_current_page(Page::containing_address(addr), flags),
+            None => false,
+        }
+    }
+
+    /// Back the page of the current task with a zeroed frame, and invoke the OOM killer if there's
+    /// no enough memory for the frame or the page tables. Returns `false` if the memory cannot be
+    /// freed, or the current task itself is killed.
+    fn back_current_page(&self, page: Page, flags: PageTableFlags) -> bool {
+        loop {
+            let mapped = self.with_local(|rq| {
+                let task = rq.running.as_ref()?;
+                Some(unsafe { task.page_table.allocate_zeroed_and_map_to(page, flags) })
+            });
+            let Some(mapped) = mapped else {
+                return false;
+            };
+
+            if let Some(frame) = mapped {
+                debug!("lazily mapped {:?} to {:?}", page, frame);
+                return true;
+            }
+            if !self.kill_for_memory() {
+                return false;
+            }
+        }
+    }
+
+    /// The OOM killer. Kill the user task owning the most frames to free its memory, which may be
+    /// the current one. Tasks running on other processors are skipped, since their memory is still
+    /// in use. Returns `false` if there's no task to kill.
+    fn kill_for_memory(&self) -> bool {
+        let candidate = |task: &Task| Candidate {
+            info: task.info.clone(),
+            frames: task.page_table.allocated_frames(),
+        };
+
+        let mut candidates =
+            self.with_local(|rq| rq.running.iter().map(candidate).collect::<Vec<_>>());
+        for run_queue in self.online_run_queues() {
+            candidates.extend(run_queue.lock().ready_tasks().map(candidate));
+        }
+        candidates.extend(
+            self.pending
+                .lock()
+                .values()
+                .map(|(task, _)| candidate(task)),
+        );
+
+        loop {
+            let Some(victim) = oom::choose_victim(&candidates) else {
+                error!(
+                    "out of memory but no task to kill: {:?}",
+                    frame_allocator::stats()
+                );
+                return false;
+            };
+
+            let id = victim.info.id;
+            if self.kill_not_running(id) {
+                oom::report(&candidates, victim);
+                return true;
+            }
+            // The victim has been scheduled on other processors since the candidates were
+            // collected, so try the next one.
+            candidates.retain(|candidate| candidate.info.id != id);
+        }
+    }
+
+    /// Kill the task if it's the current one or not running on any processor. Returns `false` if
+    /// it's running on others.
+    fn kill_not_running(&self, id: u64) -> bool {
+        if self.with_local(|rq| rq.running.as_ref().map(|task| task.info.id)) == Some(id) {
+            self.drop_current();
+            return true;
+        }
+
+        let victim = self
+            .pending
+            .lock()
+            .remove(&id)
+            .map(|(task, _)| task)
+            .or_else(|| {
+                self.online_run_queues()
+                    .iter()
+                    .find_map(|run_queue| run_queue.lock().remove(id))
+            });
+        victim.is_some()
+    }
+
+    /// Back all of the unmapped pages in `[base, base + len)` that are inside lazily backed areas
+    /// of the current task, so that the kernel can access them without page faults. The current
+    /// task may be killed by the OOM killer here.
+    pub fn populate_current(&self, base: *const (), len: usize) {
+        if len == 0 {
+            return;
+        }
+
+        let base = VirtAddr::from_ptr(base);
+        let base_page = Page::<Size4KiB>::containing_address(base);
+        let end_page = Page::containing_address(base + (len - 1));
+
+        for page in Page::range_inclusive(base_page, end_page) {
+            let flags = self.with_current(|task| {
+                let vma = task.address_space.find(page.start_address())?;
+                (vma.is_lazy() && !task.page_table.is_mapped(page)).then(|| vma.flags())
+            });
+
+            if let Some(flags) = flags {
+                if !self.back_current_page(page, flags) {
+                    return;
                 }
             }
         }
+    }
 
-        if !success {
-            warn!(
-                "no enough memory to extend heap to {:?} for task {}, kill it",
-                top, task.info.id
+    /// Map anonymous memory for the current task. The pages will be backed on demand.
+    pub fn mmap_current(
+        &self,
+        addr: Option<VirtAddr>,
+        len: usize,
+        prot: Protection,
+    ) -> MemoryResult<VirtAddr> {
+        self.with_current(|task| {
+            let len = vma::page_aligned_len(len)?;
+
+            let start = match addr {
+                Some(addr) => addr,
+                None => task.address_space.find_free(len)?,
+            };
+            let end = vma::check_user_range(start, len)?;
+
+            task.address_space.insert(Vma {
+                start,
+                end,
+                prot,
+                kind: VmaKind::Anonymous,
+            })?;
+            info!(
+                "mapped {:?}..{:?} with {:?} for task {}",
+                start, end, prot, task.info.id
             );
-            self.drop_current();
-        } else {
-            task.heap_top = top;
+
+            Ok(start)
+        })
+    }
+
+    /// Map the whole shared memory region for the current task, at `addr` if given.
+    pub fn shm_map_current(
+        &self,
+        shm: Arc<SharedMemory>,
+        addr: Option<VirtAddr>,
+        prot: Protection,
+    ) -> MemoryResult<(VirtAddr, usize)> {
+        self.with_current(|task| {
+            let len = shm.size();
+
+            let start = match addr {
+                Some(addr) => addr,
+                None => task.address_space.find_free(len)?,
+            };
+            let end = vma::check_user_range(start, len)?;
+
+            let vma = Vma {
+                start,
+                end,
+                prot,
+                kind: VmaKind::Shared(shm.clone()),
+            };
+            task.address_space.insert(vma.clone())?;
+            let mapped = vma
+                .pages()
+                .zip(shm.frames())
+                .try_for_each(|(page, &frame)| unsafe {
+                    task.page_table.try_map_to(page, frame, vma.flags())
+                });
+
+            // The page tables may run out of memory, so roll back the mapping.
+            if mapped.is_err() {
+                let mut batch = task.page_table.batch();
+                for page in vma.pages() {
+                    unsafe { batch.unmap(page) };
+                }
+                drop(batch);
+                task.address_space.remove(start, end);
+
+                warn!("no memory to map {:?} for task {}", shm, task.info.id);
+                return Err(MemoryError::NoMemory);
+            }
             info!(
-                "extend heap to {:?} for task {}",
-                task.heap_top, task.info.id
+                "mapped {:?} at {:?}..{:?} with {:?} for task {}",
+                shm, start, end, prot, task.info.id
             );
-        }
+
+            Ok((start, len as usize))
+        })
     }
 
-    pub fn add_current_resources(&mut self, resource: Arc<BoxedResource>) -> ResourceHandle {
-        let task = self.running.as_mut().expect("no task running");
-        let map = &mut task.resources;
-        let new_handle = map
-            .keys()
-            .last()
-            .copied()
-            .map(|h| ResourceHandle(h.0 + 1))
-            .unwrap_or_default();
-        map.insert(new_handle, resource);
-        new_handle
+    /// Unmap the memory mapped by `Mmap` or `ShmMap` in `[addr, addr + len)` for the current task,
+    /// and free the backed frames owned by the task.
+    pub fn munmap_current(&self, addr: VirtAddr, len: usize) -> MemoryResult<()> {
+        self.with_current(|task| {
+            let end = vma::check_user_range(addr, vma::page_aligned_len(len)?)?;
+
+            if !task
+                .address_space
+                .overlapping(addr, end)
+                .all(Vma::is_user_managed)
+            {
+                return Err(MemoryError::InvalidArgument);
+            }
+
+            let mut batch = task.page_table.batch();
+            for vma in task.address_space.remove(addr, end) {
+                for page in vma.pages() {
+                    unsafe { batch.unmap(page) };
+                }
+            }
+            drop(batch);
+            info!("unmapped {:?}..{:?} for task {}", addr, end, task.info.id);
+
+            Ok(())
+        })
+    }
+
+    /// Change the protection of the memory mapped by `Mmap` or `ShmMap` in `[addr, addr + len)` for
+    /// the current task. The range must be fully mapped.
+    pub fn mprotect_current(
+        &self,
+        addr: VirtAddr,
+        len: usize,
+        prot: Protection,
+    ) -> MemoryResult<()> {
+        self.with_current(|task| {
+            let end = vma::check_user_range(addr, vma::page_aligned_len(len)?)?;
+
+            if !task
+                .address_space
+                .overlapping(addr, end)
+                .all(Vma::is_user_managed)
+            {
+                return Err(MemoryError::InvalidArgument);
+            }
+
+            let mut batch = task.page_table.batch();
+            for vma in task.address_space.protect(addr, end, prot)? {
+                for page in vma.pages() {
+                    // Pages not backed yet will get the new flags on the first access.
+                    unsafe { batch.update_flags(page, vma.flags()) };
+                }
+            }
+            drop(batch);
+            info!(
+                "protected {:?}..{:?} with {:?} for task {}",
+                addr, end, prot, task.info.id
+            );
+
+            Ok(())
+        })
+    }
+
+    pub fn add_current_resources(&self, resource: Arc<BoxedResource>) -> ResourceHandle {
+        self.with_current(|task| {
+            let map = &mut task.resources;
+            let new_handle = map
+                .keys()
+                .last()
+                .copied()
+                .map(|h| ResourceHandle(h.0 + 1))
+                .unwrap_or_default();
+            map.insert(new_handle, resource);
+            new_handle
+        })
     }
 
     pub fn get_current_resource(&self, handle: ResourceHandle) -> Option<Arc<BoxedResource>> {
-        let task = self.running.as_ref().expect("no task running");
-        task.resources.get(&handle).cloned()
+        self.with_current(|task| task.resources.get(&handle).cloned())
+    }
+
+    pub fn current_affinity(&self) -> CpuMask {
+        self.with_current(|task| task.affinity)
+    }
+
+    /// Set the processors that the current task can run on. It keeps running on the current
+    /// processor until being scheduled again, even if not included.
+    pub fn set_current_affinity(&self, affinity: CpuMask) -> TaskResult<()> {
+        if !(0..smp::online_cpus()).any(|cpu_id| affinity.contains(cpu_id)) {
+            return Err(TaskError::InvalidAffinity);
+        }
+
+        self.with_current(|task| {
+            task.affinity = affinity;
+            info!("set affinity {:#x} for task {}", affinity.0, task.info.id);
+        });
+        Ok(())
     }
 
     pub fn has_running(&self) -> bool {
-        self.running.is_some()
+        self.with_local(|rq| rq.running.is_some())
+    }
+
+    pub fn current_info(&self) -> Option<TaskInfo> {
+        self.with_local(|rq| rq.running.as_ref().map(|task| task.info.clone()))
     }
 
-    pub fn current_info(&self) -> Option<&TaskInfo> {
-        self.running.as_ref().map(|task| &task.info)
+    /// The addresses of the input and output syscall buffers of the current task.
+    pub fn current_syscall_buffers(&self) -> Option<(VirtAddr, VirtAddr)> {
+        self.with_local(|rq| {
+            let layout = rq.running.as_ref()?.layout?;
+            Some((layout.syscall_in, layout.syscall_out))
+        })
+    }
+
+    /// Check whether the memory is accessible by the current task, see
+    /// [`PageTableWrapper::check_user_accessible`].
+    pub fn check_current_accessible(&self, base: *const (), len: usize, write: bool) -> bool {
+        self.with_current(|task| task.page_table.check_user_accessible(base, len, write))
     }
+}
+
+/// Add the image areas for the loadable segments of the ELF.
+fn insert_image_vmas<A>(address_space: &mut AddressSpace, loader: &ElfLoader<A>) -> LoadResult<()>
+where
+    A: FrameAllocator<Size4KiB>,
+{
+    for (start, end, flags) in loader.segment_ranges() {
+        let start = start.align_down(Size4KiB::SIZE);
+        let end = end.align_up(Size4KiB::SIZE);
+        if start < vma::USER_SPACE_START || end > vma::USER_SPACE_END {
+            return Err(LoadError::InvalidAddress);
+        }
 
-    pub fn current_page_table(&self) -> Option<&PageTableWrapper> {
-        self.running.as_ref().map(|task| task.page_table.deref())
+        // The segments of an object never share pages, which is checked by the loader, so any
+        // overlap is with other objects or the fixed areas.
+        address_space
+            .insert(Vma {
+                start,
+                end,
+                prot: vma::flags_protection(flags),
+                kind: VmaKind::Image,
+            })
+            .map_err(|_| LoadError::AlreadyMapped)?;
     }
+
+    Ok(())
 }
 
 pub fn with_task_manager<F, R>(f: F) -> R
 where
-    F: FnOnce(&mut TaskManager) -> R,
+    F: FnOnce(&TaskManager) -> R,
 {
-    instructions::interrupts::without_interrupts(|| {
-        let mut task_manager = TASK_MANAGER.lock();
-        f(&mut *task_manager)
-    })
+    instructions::interrupts::without_interrupts(|| f(&TASK_MANAGER))
 }
 
 pub fn schedule_and_run() -> ! {
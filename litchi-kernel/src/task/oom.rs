@@ -0,0 +1,54 @@
+use alloc::vec::Vec;
+
+use log::error;
+
+use super::TaskInfo;
+use crate::{frame_allocator, heap};
+
+/// A task that may be killed to free memory.
+#[derive(Debug)]
+pub(super) struct Candidate {
+    pub info: TaskInfo,
+
+    /// The number of frames owned by this task.
+    pub frames: u64,
+}
+
+impl Candidate {
+    /// The badness of the task. The one with the highest score will be killed first.
+    fn score(&self) -> u64 {
+        self.frames
+    }
+}
+
+/// Choose the task to kill, which is the one using the most memory. Later tasks are preferred on a
+/// tie, since they're less likely to be the essential ones.
+pub(super) fn choose_victim(candidates: &[Candidate]) -> Option<&Candidate> {
+    candidates
+        .iter()
+        .filter(|candidate| candidate.frames > 0)
+        .max_by_key(|candidate| (candidate.score(), candidate.info.id))
+}
+
+/// Log the memory usage and the scores of all of the candidates.
+pub(super) fn report(candidates: &[Candidate], victim: &Candidate) {
+    error!("out of memory: {:?}", frame_allocator::stats());
+    error!("kernel heap: {:?}", heap::stats());
+
+    let mut candidates = candidates.iter().collect::<Vec<_>>();
+    candidates.sort_by_key(|candidate| candidate.info.id);
+    for candidate in candidates {
+        error!(
+            "  task {:>5} `{}`: {} frames, score {}",
+            candidate.info.id,
+            candidate.info.name,
+            candidate.frames,
+            candidate.score()
+        );
+    }
+
+    error!(
+        "killed task {} `{}` to free {} frames",
+        victim.info.id, victim.info.name, victim.frames
+    );
+}
@@ -0,0 +1,68 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+
+use super::manager::{Priority, Task};
+
+/// The tasks of a processor. Each processor schedules from its own queue, and only touches the
+/// others' on work stealing and load balancing.
+#[derive(Debug, Default)]
+pub(super) struct RunQueue {
+    pub running: Option<Task>,
+
+    ready: BTreeMap<Priority, VecDeque<Task>>,
+
+    /// The number of timer ticks on this processor, for periodic load balancing.
+    pub ticks: u64,
+}
+
+impl RunQueue {
+    pub fn push(&mut self, task: Task) {
+        self.ready.entry(task.priority).or_default().push_back(task);
+    }
+
+    /// Take the ready task with the highest priority.
+    pub fn pop(&mut self) -> Option<Task> {
+        self.ready.values_mut().find_map(VecDeque::pop_front)
+    }
+
+    /// Put the running task to the back of the ready queue.
+    pub fn yield_running(&mut self) {
+        let task = self.running.take().expect("no task running");
+        self.push(task);
+    }
+
+    /// The number of ready tasks except the idle one, which never migrates.
+    pub fn load(&self) -> usize {
+        self.ready_tasks().filter(|task| !task.is_idle()).count()
+    }
+
+    pub fn ready_tasks(&self) -> impl Iterator<Item = &Task> {
+        self.ready.values().flatten()
+    }
+
+    /// Remove the ready task with the given id.
+    pub fn remove(&mut self, id: u64) -> Option<Task> {
+        self.ready.values_mut().find_map(|queue| {
+            let index = queue.iter().position(|task| task.info.id == id)?;
+            queue.remove(index)
+        })
+    }
+
+    /// Take at most `count` ready tasks that are allowed to run on the processor `cpu_id`. They're
+    /// taken from the back, since they'll wait for the longest here.
+    pub fn steal(&mut self, cpu_id: usize, count: usize) -> Vec<Task> {
+        let mut stolen = Vec::new();
+
+        for queue in self.ready.values_mut() {
+            while stolen.len() < count {
+                let position = queue.iter().rposition(|task| task.can_migrate_to(cpu_id));
+                let Some(index) = position else {
+                    break;
+                };
+                stolen.push(queue.remove(index).unwrap());
+            }
+        }
+
+        stolen
+    }
+}
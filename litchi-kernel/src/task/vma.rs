@@ -0,0 +1,285 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use litchi_user_common::memory::{MemoryError, MemoryResult, Protection};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::page::PageRange;
+use x86_64::structures::paging::{Page, PageSize, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::shm::SharedMemory;
+
+/// The window of virtual memory that the user can map, which is the whole lower half except for the
+/// first pages to catch null pointers, and the last page so that the end is still canonical. The
+/// kernel lives in the higher half.
+pub const USER_SPACE_START: VirtAddr = VirtAddr::new_truncate(0x1_0000);
+pub const USER_SPACE_END: VirtAddr = VirtAddr::new_truncate(0x7fff_ffff_f000);
+
+/// Mappings without a given address are placed from here.
+const MMAP_BASE: VirtAddr = VirtAddr::new_truncate(0x1555_0000_0000);
+
+#[derive(Debug, Clone)]
+pub enum VmaKind {
+    /// Segments of the ELF image, mapped eagerly by the loader.
+    Image,
+    /// The syscall buffers, mapped eagerly.
+    SyscallBuffer,
+    /// The heap, extended by `ExtendHeap` and backed on demand.
+    Heap,
+    /// The stack, backed on demand.
+    Stack,
+    /// Anonymous memory created by `Mmap`, backed on demand.
+    Anonymous,
+    /// A shared memory region, mapped eagerly. The region is kept alive until all of the areas
+    /// referring to it are unmapped.
+    Shared(Arc<SharedMemory>),
+}
+
+/// A virtual memory area, which is a page-aligned range of the user address space with the same
+/// permissions.
+#[derive(Debug, Clone)]
+pub struct Vma {
+    pub start: VirtAddr,
+
+    pub end: VirtAddr,
+
+    pub prot: Protection,
+
+    pub kind: VmaKind,
+}
+
+impl Vma {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    /// Whether the pages of this area are backed on the first access.
+    pub fn is_lazy(&self) -> bool {
+        matches!(
+            self.kind,
+            VmaKind::Heap | VmaKind::Stack | VmaKind::Anonymous
+        )
+    }
+
+    /// Whether the user can change this area by `Munmap` or `Mprotect`.
+    pub fn is_user_managed(&self) -> bool {
+        matches!(self.kind, VmaKind::Anonymous | VmaKind::Shared(_))
+    }
+
+    /// Whether the access described by the page fault `error_code` is permitted.
+    pub fn allows(&self, error_code: PageFaultErrorCode) -> bool {
+        let mut required = Protection::READ;
+        if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            required |= Protection::WRITE;
+        }
+        if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            required |= Protection::EXECUTE;
+        }
+        self.prot.contains(required)
+    }
+
+    pub fn flags(&self) -> PageTableFlags {
+        protection_flags(self.prot)
+    }
+
+    pub fn pages(&self) -> PageRange {
+        Page::range(
+            Page::containing_address(self.start),
+            Page::containing_address(self.end),
+        )
+    }
+}
+
+/// Get the page table flags of user pages with the given protection. Since x86 cannot express
+/// unreadable present pages, pages without any permission are made inaccessible for the user.
+pub fn protection_flags(prot: Protection) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT;
+    if !prot.is_empty() {
+        flags |= PageTableFlags::USER_ACCESSIBLE;
+    }
+    if prot.contains(Protection::WRITE) {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !prot.contains(Protection::EXECUTE) {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+/// Get the protection of user pages mapped with the given flags, the reverse of
+/// [`protection_flags`].
+pub fn flags_protection(flags: PageTableFlags) -> Protection {
+    let mut prot = Protection::empty();
+    if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        prot |= Protection::READ;
+    }
+    if flags.contains(PageTableFlags::WRITABLE) {
+        prot |= Protection::WRITE;
+    }
+    if !flags.contains(PageTableFlags::NO_EXECUTE) {
+        prot |= Protection::EXECUTE;
+    }
+    prot
+}
+
+/// Round the length of a user request up to pages.
+pub fn page_aligned_len(len: usize) -> MemoryResult<u64> {
+    let len = len as u64;
+    if len == 0 || len > USER_SPACE_END - USER_SPACE_START {
+        return Err(MemoryError::InvalidArgument);
+    }
+    Ok(len.next_multiple_of(Size4KiB::SIZE))
+}
+
+/// Check that `[addr, addr + len)` is a page-aligned range inside the user space, and returns its
+/// end.
+pub fn check_user_range(addr: VirtAddr, len: u64) -> MemoryResult<VirtAddr> {
+    if !addr.is_aligned(Size4KiB::SIZE) || addr < USER_SPACE_START || addr >= USER_SPACE_END {
+        return Err(MemoryError::InvalidArgument);
+    }
+    if len > USER_SPACE_END - addr {
+        return Err(MemoryError::InvalidArgument);
+    }
+    Ok(addr + len)
+}
+
+/// The address space of a user task, as a set of non-overlapping areas ordered by their start.
+#[derive(Debug, Default)]
+pub struct AddressSpace {
+    vmas: BTreeMap<VirtAddr, Vma>,
+}
+
+impl AddressSpace {
+    pub fn find(&self, addr: VirtAddr) -> Option<&Vma> {
+        self.vmas
+            .range(..=addr)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    /// Iterate over the areas overlapping with `[start, end)`.
+    pub fn overlapping(&self, start: VirtAddr, end: VirtAddr) -> impl Iterator<Item = &Vma> {
+        let first = self.find(start).map_or(start, |vma| vma.start);
+        self.vmas.range(first..end).map(|(_, vma)| vma)
+    }
+
+    fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        self.overlapping(start, end).next().is_some()
+    }
+
+    pub fn insert(&mut self, vma: Vma) -> MemoryResult<()> {
+        assert!(vma.start.is_aligned(Size4KiB::SIZE) && vma.end.is_aligned(Size4KiB::SIZE));
+
+        if vma.start >= vma.end {
+            return Err(MemoryError::InvalidArgument);
+        }
+        if self.overlaps(vma.start, vma.end) {
+            return Err(MemoryError::AlreadyMapped);
+        }
+        self.vmas.insert(vma.start, vma);
+        Ok(())
+    }
+
+    /// Find a free range of `len` bytes for a new mapping.
+    pub fn find_free(&self, len: u64) -> MemoryResult<VirtAddr> {
+        let mut candidate = MMAP_BASE;
+        for vma in self.vmas.values() {
+            if vma.end <= candidate {
+                continue;
+            }
+            if vma.start > candidate && len <= vma.start - candidate {
+                break;
+            }
+            candidate = vma.end;
+        }
+
+        if len <= USER_SPACE_END - candidate {
+            Ok(candidate)
+        } else {
+            Err(MemoryError::NoSpace)
+        }
+    }
+
+    /// Extend the heap area starting at `base` to `top`, creating it if not exists.
+    pub fn extend_heap(&mut self, base: VirtAddr, top: VirtAddr) -> MemoryResult<()> {
+        let end = match self.vmas.get(&base) {
+            Some(heap) => {
+                assert!(matches!(heap.kind, VmaKind::Heap));
+                heap.end
+            }
+            None if top > base => {
+                return self.insert(Vma {
+                    start: base,
+                    end: top,
+                    prot: Protection::READ | Protection::WRITE,
+                    kind: VmaKind::Heap,
+                })
+            }
+            None => return Ok(()),
+        };
+
+        if top > end {
+            if self.overlaps(end, top) {
+                return Err(MemoryError::AlreadyMapped);
+            }
+            self.vmas.get_mut(&base).unwrap().end = top;
+        }
+        Ok(())
+    }
+
+    /// Split the area containing `addr` into two at `addr`.
+    fn split_at(&mut self, addr: VirtAddr) {
+        let Some(vma) = self.find(addr).filter(|vma| vma.start < addr).cloned() else {
+            return;
+        };
+
+        self.vmas.get_mut(&vma.start).unwrap().end = addr;
+        self.vmas.insert(addr, Vma { start: addr, ..vma });
+    }
+
+    /// Remove `[start, end)` from the address space, splitting the areas on the boundaries. Returns
+    /// the removed parts.
+    pub fn remove(&mut self, start: VirtAddr, end: VirtAddr) -> Vec<Vma> {
+        self.split_at(start);
+        self.split_at(end);
+
+        let mut removed = self.vmas.split_off(&start);
+        self.vmas.append(&mut removed.split_off(&end));
+        removed.into_values().collect()
+    }
+
+    /// Change the protection of `[start, end)`, splitting the areas on the boundaries. Returns the
+    /// changed parts.
+    pub fn protect(
+        &mut self,
+        start: VirtAddr,
+        end: VirtAddr,
+        prot: Protection,
+    ) -> MemoryResult<Vec<Vma>> {
+        // The whole range must be covered without holes.
+        let mut covered = start;
+        for vma in self.overlapping(start, end) {
+            if vma.start > covered {
+                break;
+            }
+            covered = vma.end;
+        }
+        if covered < end {
+            return Err(MemoryError::NotMapped);
+        }
+
+        self.split_at(start);
+        self.split_at(end);
+
+        Ok(self
+            .vmas
+            .range_mut(start..end)
+            .map(|(_, vma)| {
+                vma.prot = prot;
+                vma.clone()
+            })
+            .collect())
+    }
+}
@@ -1,3 +0,0 @@
-use x86_64::VirtAddr;
-
-pub const USER_HEAP_BASE_ADDR: VirtAddr = VirtAddr::new_truncate(0x1222_0000_0000);
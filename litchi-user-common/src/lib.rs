@@ -1,6 +1,8 @@
 #![no_std]
 #![feature(never_type)]
 
-pub mod heap;
+pub mod log;
+pub mod memory;
 pub mod resource;
 pub mod syscall;
+pub mod task;
@@ -0,0 +1,13 @@
+#[derive(Debug)]
+pub enum LogError {
+    /// The level is not one of `off`, `error`, `warn`, `info`, `debug` and `trace`.
+    InvalidLevel,
+}
+
+impl core::fmt::Display for LogError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self, f)
+    }
+}
+
+pub type LogResult<T> = Result<T, LogError>;
@@ -0,0 +1,37 @@
+use bitflags::bitflags;
+
+bitflags! {
+    /// The access permissions of a memory mapping.
+    pub struct Protection: u8 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXECUTE = 1 << 2;
+    }
+}
+
+#[derive(Debug)]
+pub enum MemoryError {
+    /// The address or the length is not page aligned, out of the user space, or refers to memory
+    /// that cannot be changed by the user.
+    InvalidArgument,
+    /// The requested range overlaps with existing mappings.
+    AlreadyMapped,
+    /// The requested range is not fully mapped.
+    NotMapped,
+    /// No free virtual memory for the mapping.
+    NoSpace,
+    /// No enough physical memory.
+    NoMemory,
+    /// The shared memory region with the given name already exists.
+    AlreadyExists,
+    /// The shared memory region with the given name does not exist.
+    NotExists,
+}
+
+impl core::fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self, f)
+    }
+}
+
+pub type MemoryResult<T> = Result<T, MemoryError>;
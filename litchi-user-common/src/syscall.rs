@@ -1,8 +1,11 @@
 use enum_as_inner::EnumAsInner;
 use x86_64::VirtAddr;
 
-use self::buffer::{SYSCALL_IN_BUFFER, SYSCALL_OUT_BUFFER};
+use self::buffer::{In, Out, SyscallBuffer, SYSCALL_BUFFERS};
+use crate::log::LogResult;
+use crate::memory::{MemoryResult, Protection};
 use crate::resource::{ResourceHandle, ResourceResult};
+use crate::task::{CpuMask, TaskResult};
 
 pub mod buffer;
 
@@ -28,6 +31,50 @@ pub enum Syscall<'a> {
         handle: ResourceHandle,
         buf: &'a mut [u8],
     },
+    /// Map anonymous memory of `len` bytes, which is zeroed and backed on demand. If `addr` is
+    /// given, the mapping will be placed exactly there.
+    Mmap {
+        addr: Option<VirtAddr>,
+        len: usize,
+        prot: Protection,
+    },
+    Munmap {
+        addr: VirtAddr,
+        len: usize,
+    },
+    Mprotect {
+        addr: VirtAddr,
+        len: usize,
+        prot: Protection,
+    },
+    /// Create a zeroed shared memory region of `len` bytes named `name`.
+    ShmCreate {
+        name: &'a str,
+        len: usize,
+    },
+    /// Map the whole shared memory region named `name`, at `addr` if given.
+    ShmMap {
+        name: &'a str,
+        addr: Option<VirtAddr>,
+        prot: Protection,
+    },
+    /// Remove the name of the shared memory region. It will be freed after being unmapped by all
+    /// of the tasks.
+    ShmUnlink {
+        name: &'a str,
+    },
+    /// Get the id of the processor running the current task.
+    GetCpuId,
+    GetAffinity,
+    /// Restrict the current task to the processors in `affinity`. The task is moved to one of them
+    /// right away if the current processor is not included.
+    SetAffinity {
+        affinity: CpuMask,
+    },
+    /// Set the log filter of the kernel, like `info,task=debug`.
+    SetLogFilter {
+        filter: &'a str,
+    },
     Halt,
     Exit,
 }
@@ -44,23 +91,68 @@ pub enum SyscallResponse {
     Read {
         len: ResourceResult<usize>,
     },
+    Mmap {
+        addr: MemoryResult<VirtAddr>,
+    },
+    Munmap {
+        result: MemoryResult<()>,
+    },
+    Mprotect {
+        result: MemoryResult<()>,
+    },
+    ShmCreate {
+        result: MemoryResult<()>,
+    },
+    ShmMap {
+        mapping: MemoryResult<(VirtAddr, usize)>,
+    },
+    ShmUnlink {
+        result: MemoryResult<()>,
+    },
+    GetCpuId {
+        cpu_id: usize,
+    },
+    GetAffinity {
+        affinity: CpuMask,
+    },
+    SetAffinity {
+        result: TaskResult<()>,
+    },
+    SetLogFilter {
+        result: LogResult<()>,
+    },
 }
 
 // For user
 //
 
+/// Set the addresses of the syscall buffers given by the kernel, before any syscall is made.
+pub unsafe fn init_buffers(in_addr: VirtAddr, out_addr: VirtAddr) {
+    SYSCALL_BUFFERS.call_once(|| {
+        (
+            SyscallBuffer::new(in_addr).into(),
+            SyscallBuffer::new(out_addr).into(),
+        )
+    });
+}
+
 pub unsafe fn syscall(syscall: Syscall) -> SyscallResponse {
-    SYSCALL_IN_BUFFER.lock().call(syscall);
-    SYSCALL_OUT_BUFFER.lock().get_response()
+    let (in_buffer, out_buffer) = SYSCALL_BUFFERS
+        .get()
+        .expect("syscall buffers not initialized");
+    in_buffer.lock().call(syscall);
+    out_buffer.lock().get_response()
 }
 
 // For kernel
 //
 
-pub unsafe fn get_syscall() -> Syscall<'static> {
-    SYSCALL_IN_BUFFER.lock().get_syscall()
+/// Get the syscall from the input buffer at `in_addr` of the current task.
+pub unsafe fn get_syscall(in_addr: VirtAddr) -> Syscall<'static> {
+    SyscallBuffer::<In>::new(in_addr).get_syscall()
 }
 
-pub unsafe fn response(response: SyscallResponse) {
-    SYSCALL_OUT_BUFFER.lock().response(response);
+/// Put the response to the output buffer at `out_addr` of the current task.
+pub unsafe fn response(out_addr: VirtAddr, response: SyscallResponse) {
+    SyscallBuffer::<Out>::new(out_addr).response(response);
 }
@@ -2,14 +2,12 @@ use core::arch::asm;
 use core::marker::PhantomData;
 use core::mem::{size_of, MaybeUninit};
 
-use spin::Mutex;
+use spin::{Mutex, Once};
 use x86_64::structures::paging::{PageSize, Size4KiB};
 use x86_64::VirtAddr;
 
 use super::{Syscall, SyscallResponse};
 
-pub const SYSCALL_IN_ADDR: VirtAddr = VirtAddr::new_truncate(0x1333_0000_0000);
-pub const SYSCALL_OUT_ADDR: VirtAddr = VirtAddr::new_truncate(0x1334_0000_0000);
 pub const SYSCALL_BUFFER_PAGES: u64 = 1;
 pub const SYSCALL_BUFFER_BYTES: usize = (SYSCALL_BUFFER_PAGES * Size4KiB::SIZE) as usize;
 
@@ -19,10 +17,10 @@ static_assertions::const_assert!(SYSCALL_BUFFER_BYTES >= size_of::<SyscallRespon
 pub struct In;
 pub struct Out;
 
-lazy_static::lazy_static! {
-    pub static ref SYSCALL_IN_BUFFER: Mutex<SyscallBuffer<In>> = Mutex::new(SyscallBuffer::new(SYSCALL_IN_ADDR));
-    pub static ref SYSCALL_OUT_BUFFER: Mutex<SyscallBuffer<Out>> = Mutex::new(SyscallBuffer::new(SYSCALL_OUT_ADDR));
-}
+/// The syscall buffers of the user, whose addresses are randomized and given by the kernel on
+/// start.
+pub(super) static SYSCALL_BUFFERS: Once<(Mutex<SyscallBuffer<In>>, Mutex<SyscallBuffer<Out>>)> =
+    Once::new();
 
 pub struct SyscallBuffer<T> {
     buffer: &'static mut [u8; SYSCALL_BUFFER_BYTES],
@@ -31,11 +29,12 @@ pub struct SyscallBuffer<T> {
 }
 
 impl<T> SyscallBuffer<T> {
-    fn new(base: VirtAddr) -> Self {
-        let buffer =
-            unsafe { core::slice::from_raw_parts_mut(base.as_mut_ptr(), SYSCALL_BUFFER_BYTES) }
-                .try_into()
-                .unwrap();
+    /// # Safety
+    /// The `base` must point to a mapped syscall buffer of `SYSCALL_BUFFER_PAGES` pages.
+    pub(super) unsafe fn new(base: VirtAddr) -> Self {
+        let buffer = core::slice::from_raw_parts_mut(base.as_mut_ptr(), SYSCALL_BUFFER_BYTES)
+            .try_into()
+            .unwrap();
 
         Self {
             buffer,
@@ -0,0 +1,31 @@
+/// A set of processors, where the bit `i` stands for the processor `i`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CpuMask(pub u64);
+
+impl CpuMask {
+    pub const fn all() -> Self {
+        Self(u64::MAX)
+    }
+
+    pub const fn single(cpu_id: usize) -> Self {
+        Self(1 << cpu_id)
+    }
+
+    pub const fn contains(self, cpu_id: usize) -> bool {
+        cpu_id < u64::BITS as usize && self.0 & (1 << cpu_id) != 0
+    }
+}
+
+#[derive(Debug)]
+pub enum TaskError {
+    /// None of the processors in the affinity mask is online.
+    InvalidAffinity,
+}
+
+impl core::fmt::Display for TaskError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self, f)
+    }
+}
+
+pub type TaskResult<T> = Result<T, TaskError>;
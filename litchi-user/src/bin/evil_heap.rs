@@ -1,12 +1,12 @@
 #![no_std]
 #![no_main]
 
+use litchi_user::heap;
 use litchi_user::syscall::sys_extend_heap;
-use litchi_user_common::heap::USER_HEAP_BASE_ADDR;
 
 #[no_mangle]
 extern "C" fn main() {
-    sys_extend_heap(USER_HEAP_BASE_ADDR + 0x0100_0000_0000u64);
+    sys_extend_heap(heap::base() + 0x0100_0000_0000u64);
 
     unreachable!("we should be killed");
 }
@@ -0,0 +1,38 @@
+#![no_std]
+#![no_main]
+
+// Map, protect and unmap anonymous memory. The last write to the read-only memory should kill us.
+
+use litchi_user::println;
+use litchi_user::syscall::{sys_mmap, sys_mprotect, sys_munmap};
+use litchi_user_common::memory::Protection;
+
+#[no_mangle]
+extern "C" fn main() {
+    let len = 4 * 4096;
+    let addr = sys_mmap(None, len, Protection::READ | Protection::WRITE).unwrap();
+    println!("mapped {} bytes at {:?}", len, addr);
+
+    let memory = unsafe { core::slice::from_raw_parts_mut(addr.as_mut_ptr::<u8>(), len) };
+    assert!(memory.iter().all(|&b| b == 0));
+    memory.fill(0x42);
+
+    let fixed = sys_mmap(Some(addr + len), 4096, Protection::READ).unwrap();
+    println!("mapped fixed at {:?}", fixed);
+    println!(
+        "map overlapped: {:?}",
+        sys_mmap(Some(addr), 4096, Protection::READ)
+    );
+
+    sys_munmap(addr, 4096).unwrap();
+    sys_mprotect(addr + 4096u64, 4096, Protection::READ).unwrap();
+    println!(
+        "protect unmapped: {:?}",
+        sys_mprotect(addr, 4096, Protection::READ)
+    );
+
+    println!("read after protect: {:x}", memory[4096]);
+    memory[4096] = 0;
+
+    unreachable!("we should be killed");
+}
@@ -10,10 +10,14 @@ use alloc::vec;
 use alloc::vec::Vec;
 
 use anyhow::{anyhow, Error, Result};
-use litchi_user::syscall::{sys_halt, sys_open, sys_read, sys_sleep};
+use litchi_user::syscall::{
+    sys_get_affinity, sys_get_cpu_id, sys_halt, sys_open, sys_read, sys_set_affinity,
+    sys_set_log_filter, sys_sleep,
+};
 use litchi_user::tsc::read_tsc;
 use litchi_user::{print, println};
 use litchi_user_common::resource::ResourceHandle;
+use litchi_user_common::task::CpuMask;
 
 struct Term {
     handle: ResourceHandle,
@@ -97,6 +101,44 @@ fn handle<'a>(command: String, mut args: impl Iterator<Item = &'a str>) -> Resul
         "halt" => {
             sys_halt();
         }
+        "cpu" => {
+            println!(
+                "cpu: {}, affinity: {:#x}",
+                sys_get_cpu_id(),
+                sys_get_affinity().0
+            );
+        }
+        "pin" => {
+            let cpu_id: usize = next_arg()?.parse().map_err(Error::msg)?;
+            sys_set_affinity(CpuMask::single(cpu_id)).map_err(Error::msg)?;
+            println!("pinned to cpu {}", sys_get_cpu_id());
+        }
+        "crashes" => {
+            let handle = sys_open("/device/crash-log").map_err(Error::msg)?;
+            let mut buf = vec![0u8; 1024];
+            loop {
+                let len = sys_read(handle, &mut buf).map_err(Error::msg)?;
+                if len == 0 {
+                    break;
+                }
+                print!("{}", String::from_utf8_lossy(&buf[..len]));
+            }
+        }
+        "dmesg" => {
+            let handle = sys_open("/device/kmsg").map_err(Error::msg)?;
+            let mut buf = vec![0u8; 1024];
+            loop {
+                let len = sys_read(handle, &mut buf).map_err(Error::msg)?;
+                if len == 0 {
+                    break;
+                }
+                print!("{}", String::from_utf8_lossy(&buf[..len]));
+            }
+        }
+        "loglevel" => {
+            let filter = args.collect::<Vec<_>>().join(",");
+            sys_set_log_filter(&filter).map_err(Error::msg)?;
+        }
         "tsc" => {
             println!("tsc: {}", read_tsc());
         }
@@ -0,0 +1,44 @@
+#![no_std]
+#![no_main]
+
+// Exchange a message through a shared memory region. Load two instances of this program: the first
+// one creates the region and writes the message, and the second one reads it.
+
+use litchi_user::println;
+use litchi_user::syscall::{
+    sys_get_task_id, sys_shm_create, sys_shm_map, sys_shm_unlink, sys_sleep,
+};
+use litchi_user_common::memory::{MemoryError, Protection};
+
+const NAME: &str = "litchi";
+const MESSAGE: &[u8] = b"hello from shared memory";
+
+#[no_mangle]
+extern "C" fn main() {
+    let id = sys_get_task_id();
+
+    match sys_shm_create(NAME, 4096) {
+        Ok(()) => {
+            let (addr, len) =
+                sys_shm_map(NAME, None, Protection::READ | Protection::WRITE).unwrap();
+            let memory = unsafe { core::slice::from_raw_parts_mut(addr.as_mut_ptr::<u8>(), len) };
+            memory[..MESSAGE.len()].copy_from_slice(MESSAGE);
+            println!("Task {}: wrote message at {:?}", id, addr);
+
+            sys_sleep(100);
+            sys_shm_unlink(NAME).unwrap();
+        }
+        Err(MemoryError::AlreadyExists) => {
+            let (addr, len) = sys_shm_map(NAME, None, Protection::READ).unwrap();
+            let memory = unsafe { core::slice::from_raw_parts(addr.as_ptr::<u8>(), len) };
+            sys_sleep(50);
+            println!(
+                "Task {}: read message at {:?}: {}",
+                id,
+                addr,
+                core::str::from_utf8(&memory[..MESSAGE.len()]).unwrap()
+            );
+        }
+        Err(err) => panic!("failed to create shared memory: {}", err),
+    }
+}
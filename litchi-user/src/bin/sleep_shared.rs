@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+
+// Sleep like `sleep`, but through the shared runtime `liblitchi_user.so` instead of the statically
+// linked user library. The kernel loads the runtime along with the program and links them.
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+#[link(name = "litchi_user")]
+extern "C" {
+    fn litchi_user_start(
+        syscall_in: u64,
+        syscall_out: u64,
+        heap_base: u64,
+        main: unsafe extern "C" fn(),
+    ) -> !;
+    fn litchi_print(str: *const u8, len: usize);
+    fn litchi_get_task_id() -> u64;
+    fn litchi_sleep(slice: usize);
+    fn litchi_exit() -> !;
+}
+
+struct Stdout;
+
+impl Write for Stdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        unsafe { litchi_print(s.as_ptr(), s.len()) };
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let _ = writeln!(Stdout, "{}", info);
+    unsafe { litchi_exit() };
+}
+
+#[no_mangle]
+extern "C" fn _user_main(syscall_in: u64, syscall_out: u64, heap_base: u64) -> ! {
+    unsafe { litchi_user_start(syscall_in, syscall_out, heap_base, main) };
+}
+
+#[no_mangle]
+extern "C" fn main() {
+    let id = unsafe { litchi_get_task_id() };
+    let sleep_slices = 50;
+
+    let _ = writeln!(Stdout, "Task {}: hello from the shared runtime", id);
+    unsafe { litchi_sleep(sleep_slices) };
+    let _ = writeln!(
+        Stdout,
+        "Task {}: goodbye after sleeping {} slices",
+        id, sleep_slices
+    );
+}
@@ -1,7 +1,6 @@
 use core::sync::atomic::{AtomicU64, Ordering};
 
 use linked_list_allocator::LockedHeap;
-use litchi_user_common::heap::USER_HEAP_BASE_ADDR;
 use x86_64::structures::paging::{PageSize, Size4KiB};
 use x86_64::VirtAddr;
 
@@ -10,23 +9,29 @@ use crate::syscall::sys_extend_heap;
 #[global_allocator]
 static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
-static HEAP_TOP: AtomicU64 = AtomicU64::new(USER_HEAP_BASE_ADDR.as_u64());
+static HEAP_BASE: AtomicU64 = AtomicU64::new(0);
+static HEAP_TOP: AtomicU64 = AtomicU64::new(0);
+
+/// The base address of the heap, which is randomized by the kernel for each task.
+pub fn base() -> VirtAddr {
+    VirtAddr::new(HEAP_BASE.load(Ordering::SeqCst))
+}
 
 fn extend_additional(size: usize) {
     let old_top = HEAP_TOP.fetch_add(size as u64, Ordering::SeqCst);
     sys_extend_heap(VirtAddr::new(old_top) + size);
 }
 
-pub(crate) fn init() {
+pub(crate) fn init(base: VirtAddr) {
     const HEAP_PAGES: usize = 2048; // 8 MiB
     const HEAP_SIZE: usize = HEAP_PAGES * (Size4KiB::SIZE as usize);
 
+    HEAP_BASE.store(base.as_u64(), Ordering::SeqCst);
+    HEAP_TOP.store(base.as_u64(), Ordering::SeqCst);
     extend_additional(HEAP_SIZE);
 
     unsafe {
-        ALLOCATOR
-            .lock()
-            .init(USER_HEAP_BASE_ADDR.as_u64() as usize, HEAP_SIZE);
+        ALLOCATOR.lock().init(base.as_u64() as usize, HEAP_SIZE);
     }
 }
 
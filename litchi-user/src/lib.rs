@@ -4,13 +4,16 @@
 
 extern crate alloc;
 
-mod heap;
+pub mod heap;
+pub mod shared;
 pub mod syscall;
 pub mod term;
 pub mod tsc;
 
 use core::panic::PanicInfo;
 
+use x86_64::VirtAddr;
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
@@ -21,9 +24,29 @@ extern "C" {
     fn main();
 }
 
+/// Initialize the syscall buffers and the heap, then run `main` and exit. The shared runtime
+/// exports it for the programs linked against it, which pass their own `main`.
 #[no_mangle]
-pub extern "C" fn _user_main() {
-    heap::init();
+pub extern "C" fn litchi_user_start(
+    syscall_in: u64,
+    syscall_out: u64,
+    heap_base: u64,
+    main: unsafe extern "C" fn(),
+) -> ! {
+    unsafe {
+        litchi_user_common::syscall::init_buffers(
+            VirtAddr::new(syscall_in),
+            VirtAddr::new(syscall_out),
+        )
+    };
+    heap::init(VirtAddr::new(heap_base));
     unsafe { main() };
     syscall::sys_exit();
 }
+
+/// The entry point of user tasks. The kernel passes the randomized addresses of the syscall buffers
+/// and the heap in the arguments.
+#[no_mangle]
+pub extern "C" fn _user_main(syscall_in: u64, syscall_out: u64, heap_base: u64) -> ! {
+    litchi_user_start(syscall_in, syscall_out, heap_base, main)
+}
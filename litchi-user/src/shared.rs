@@ -0,0 +1,31 @@
+//! The C interface of the shared runtime `liblitchi_user.so`, for the programs linked against it
+//! instead of the Rust library.
+
+use core::slice::from_raw_parts;
+use core::str::from_utf8_unchecked;
+
+use crate::syscall::{sys_exit, sys_get_task_id, sys_print, sys_sleep};
+
+/// Print the UTF-8 string of `len` bytes at `str`.
+///
+/// # Safety
+/// `str` must point to `len` bytes of valid UTF-8.
+#[no_mangle]
+pub unsafe extern "C" fn litchi_print(str: *const u8, len: usize) {
+    sys_print(from_utf8_unchecked(from_raw_parts(str, len)));
+}
+
+#[no_mangle]
+pub extern "C" fn litchi_get_task_id() -> u64 {
+    sys_get_task_id()
+}
+
+#[no_mangle]
+pub extern "C" fn litchi_sleep(slice: usize) {
+    sys_sleep(slice);
+}
+
+#[no_mangle]
+pub extern "C" fn litchi_exit() -> ! {
+    sys_exit();
+}
@@ -1,5 +1,8 @@
+use litchi_user_common::log::LogResult;
+use litchi_user_common::memory::{MemoryResult, Protection};
 use litchi_user_common::resource::{ResourceHandle, ResourceResult};
 use litchi_user_common::syscall::{syscall, Syscall};
+use litchi_user_common::task::{CpuMask, TaskResult};
 use x86_64::VirtAddr;
 
 pub fn sys_print(str: &str) {
@@ -36,6 +39,70 @@ pub fn sys_read(handle: ResourceHandle, buf: &mut [u8]) -> ResourceResult<usize>
         .unwrap()
 }
 
+pub fn sys_mmap(addr: Option<VirtAddr>, len: usize, prot: Protection) -> MemoryResult<VirtAddr> {
+    unsafe { syscall(Syscall::Mmap { addr, len, prot }) }
+        .into_mmap()
+        .unwrap()
+}
+
+pub fn sys_munmap(addr: VirtAddr, len: usize) -> MemoryResult<()> {
+    unsafe { syscall(Syscall::Munmap { addr, len }) }
+        .into_munmap()
+        .unwrap()
+}
+
+pub fn sys_mprotect(addr: VirtAddr, len: usize, prot: Protection) -> MemoryResult<()> {
+    unsafe { syscall(Syscall::Mprotect { addr, len, prot }) }
+        .into_mprotect()
+        .unwrap()
+}
+
+pub fn sys_shm_create(name: &str, len: usize) -> MemoryResult<()> {
+    unsafe { syscall(Syscall::ShmCreate { name, len }) }
+        .into_shm_create()
+        .unwrap()
+}
+
+pub fn sys_shm_map(
+    name: &str,
+    addr: Option<VirtAddr>,
+    prot: Protection,
+) -> MemoryResult<(VirtAddr, usize)> {
+    unsafe { syscall(Syscall::ShmMap { name, addr, prot }) }
+        .into_shm_map()
+        .unwrap()
+}
+
+pub fn sys_shm_unlink(name: &str) -> MemoryResult<()> {
+    unsafe { syscall(Syscall::ShmUnlink { name }) }
+        .into_shm_unlink()
+        .unwrap()
+}
+
+pub fn sys_get_cpu_id() -> usize {
+    unsafe { syscall(Syscall::GetCpuId) }
+        .into_get_cpu_id()
+        .unwrap()
+}
+
+pub fn sys_get_affinity() -> CpuMask {
+    unsafe { syscall(Syscall::GetAffinity) }
+        .into_get_affinity()
+        .unwrap()
+}
+
+pub fn sys_set_affinity(affinity: CpuMask) -> TaskResult<()> {
+    unsafe { syscall(Syscall::SetAffinity { affinity }) }
+        .into_set_affinity()
+        .unwrap()
+}
+
+pub fn sys_set_log_filter(filter: &str) -> LogResult<()> {
+    unsafe { syscall(Syscall::SetLogFilter { filter }) }
+        .into_set_log_filter()
+        .unwrap()
+}
+
 pub fn sys_exit() -> ! {
     unsafe {
         syscall(Syscall::Exit);